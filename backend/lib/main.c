@@ -1,12 +1,38 @@
 #include<stdio.h>
+#include<stdlib.h>
+#include<string.h>
 
 void start();
 
+// The first field of an object is its tag,
+// the second is the value or a pointer to it.
+enum tag {
+  INT_TAG,
+  STRING_TAG,
+  FLOAT_TAG,
+  SYMBOL_TAG,
+  LIST_TAG,
+};
+
 typedef struct {
   long x;
   long y;
 } object;
 
+// Symbols are strings too, with a different tag.
+typedef struct {
+  long length;
+  const char *data;
+} string;
+
+// Lists grow as they're pushed to: `capacity`
+// is how many items there's room for.
+typedef struct {
+  long length;
+  long capacity;
+  object *items;
+} list;
+
 object _to_object(long x, long y) {
   object a;
   a.x = x;
@@ -14,6 +40,111 @@ object _to_object(long x, long y) {
   return a;
 }
 
+// Wraps a string constant emitted by the compiler.
+object _string_object(const char *data, long length) {
+  string *s = malloc(sizeof(string));
+  s->length = length;
+  s->data = data;
+  return _to_object(STRING_TAG, (long) s);
+}
+
+// Wraps the name of a quoted symbol.
+object _symbol_object(const char *data, long length) {
+  object a = _string_object(data, length);
+  a.x = SYMBOL_TAG;
+  return a;
+}
+
+// Copies `length` objects into a new list.
+object _list_object(object *items, long length) {
+  list *l = malloc(sizeof(list));
+  l->length = length;
+  l->capacity = length;
+  l->items = malloc(length * sizeof(object));
+  if (length > 0) {
+    memcpy(l->items, items, length * sizeof(object));
+  }
+  return _to_object(LIST_TAG, (long) l);
+}
+
+void _fail(const char *message) {
+  fprintf(stderr, "%s\n", message);
+  exit(1);
+}
+
+list *_list_value(object a) {
+  if (a.x != LIST_TAG) {
+    _fail("expected a list");
+  }
+  return (list *) a.y;
+}
+
+// Checks an index is an int within the list.
+long _index_value(list *l, object index) {
+  if (index.x != INT_TAG) {
+    _fail("a list index has to be an int");
+  }
+  if (index.y < 0 || index.y >= l->length) {
+    _fail("list index out of range");
+  }
+  return index.y;
+}
+
+// The length of a list, or of a string or symbol.
+object length(object a) {
+  if (a.x == STRING_TAG || a.x == SYMBOL_TAG) {
+    return _to_object(INT_TAG, ((string *) a.y)->length);
+  }
+  return _to_object(INT_TAG, _list_value(a)->length);
+}
+
+object get(object a, object index) {
+  list *l = _list_value(a);
+  return l->items[_index_value(l, index)];
+}
+
+// Returns the value that was set.
+object set(object a, object index, object value) {
+  list *l = _list_value(a);
+  l->items[_index_value(l, index)] = value;
+  return value;
+}
+
+// Returns the list, so pushes can be chained.
+object push(object a, object value) {
+  list *l = _list_value(a);
+  if (l->length == l->capacity) {
+    l->capacity = l->capacity == 0 ? 4 : l->capacity * 2;
+    l->items = realloc(l->items, l->capacity * sizeof(object));
+  }
+  l->items[l->length++] = value;
+  return a;
+}
+
+// Removes and returns the last item.
+object pop(object a) {
+  list *l = _list_value(a);
+  if (l->length == 0) {
+    _fail("can't pop from an empty list");
+  }
+  return l->items[--l->length];
+}
+
+// A new list of the items of `a` then the items of `b`.
+object _list_concat(object a, object b) {
+  if (a.x != LIST_TAG || b.x != LIST_TAG) {
+    _fail("only a list can be spliced into a list");
+  }
+  list *x = (list *) a.y;
+  list *y = (list *) b.y;
+  object *items = malloc((x->length + y->length) * sizeof(object));
+  memcpy(items, x->items, x->length * sizeof(object));
+  memcpy(items + x->length, y->items, y->length * sizeof(object));
+  object result = _list_object(items, x->length + y->length);
+  free(items);
+  return result;
+}
+
 int main() {
   object b = _to_object(0,0);
   start(b);
@@ -24,3 +155,314 @@ void print_number(object a) {
   printf("calling c from acorn from c\n");
   printf("object %ld %ld\n", a.x, a.y);
 }
+
+// Floats are stored as the bits of a double.
+double _float_value(object a) {
+  double d;
+  memcpy(&d, &a.y, sizeof(double));
+  return d;
+}
+
+// Writes an object without a newline - lists
+// are written like the code they were quoted from.
+void _write(FILE *out, object a) {
+  string *s;
+  list *l;
+  switch (a.x) {
+  case STRING_TAG:
+  case SYMBOL_TAG:
+    s = (string *) a.y;
+    fprintf(out, "%.*s", (int) s->length, s->data);
+    break;
+  case FLOAT_TAG:
+    fprintf(out, "%g", _float_value(a));
+    break;
+  case LIST_TAG:
+    l = (list *) a.y;
+    fprintf(out, "(");
+    for (long i = 0; i < l->length; i++) {
+      if (i != 0) {
+        fprintf(out, " ");
+      }
+      _write(out, l->items[i]);
+    }
+    fprintf(out, ")");
+    break;
+  default:
+    fprintf(out, "%ld", a.y);
+  }
+}
+
+object print(object a) {
+  _write(stdout, a);
+  printf("\n");
+  return a;
+}
+
+// The string `print` would write - a string is itself.
+object to_string(object a) {
+  if (a.x == STRING_TAG) {
+    return a;
+  }
+  char *data;
+  size_t length;
+  FILE *out = open_memstream(&data, &length);
+  _write(out, a);
+  fclose(out);
+  return _string_object(data, length);
+}
+
+// A new string of `a` then `b`.
+object concat(object a, object b) {
+  if (a.x != STRING_TAG || b.x != STRING_TAG) {
+    _fail("only strings can be concatenated");
+  }
+  string *x = (string *) a.y;
+  string *y = (string *) b.y;
+  char *data = malloc(x->length + y->length);
+  memcpy(data, x->data, x->length);
+  memcpy(data + x->length, y->data, y->length);
+  return _string_object(data, x->length + y->length);
+}
+
+// Whether the symbol `name` is `expected`.
+int _is_named(object name, const char *expected) {
+  string *s = (string *) name.y;
+  return (long) strlen(expected) == s->length && memcmp(s->data, expected, s->length) == 0;
+}
+
+// Calls the method `name` of `receiver`, passing the receiver
+// and then the items of the list `arguments`.
+object send(object receiver, object name, object arguments) {
+  list *l = _list_value(arguments);
+  object *a = l->items;
+  long count = l->length;
+  if (receiver.x == LIST_TAG) {
+    if (_is_named(name, "get") && count == 1) {
+      return get(receiver, a[0]);
+    }
+    if (_is_named(name, "set") && count == 2) {
+      return set(receiver, a[0], a[1]);
+    }
+    if (_is_named(name, "push") && count == 1) {
+      return push(receiver, a[0]);
+    }
+    if (_is_named(name, "pop") && count == 0) {
+      return pop(receiver);
+    }
+  }
+  if (receiver.x == LIST_TAG || receiver.x == STRING_TAG || receiver.x == SYMBOL_TAG) {
+    if (_is_named(name, "length") && count == 0) {
+      return length(receiver);
+    }
+  }
+  if (_is_named(name, "print") && count == 0) {
+    return print(receiver);
+  }
+  if (_is_named(name, "to_string") && count == 0) {
+    return to_string(receiver);
+  }
+  string *s = (string *) name.y;
+  fprintf(stderr, "no method '%.*s' that takes %ld arguments\n", (int) s->length, s->data, count);
+  exit(1);
+}
+
+// Nothing has fields yet - so reading one calls the method
+// of that name that takes no arguments, like `xs.length`.
+object get_field(object receiver, object name) {
+  return send(receiver, name, _list_object(NULL, 0));
+}
+
+// The operators - `a + b` is compiled to `_add(a, b)`.
+
+object _float_object(double d) {
+  object a;
+  a.x = FLOAT_TAG;
+  memcpy(&a.y, &d, sizeof(double));
+  return a;
+}
+
+double _number_value(object a) {
+  if (a.x == INT_TAG) {
+    return (double) a.y;
+  }
+  if (a.x != FLOAT_TAG) {
+    _fail("expected a number");
+  }
+  return _float_value(a);
+}
+
+// There's no boolean type: the int 0 is false,
+// and anything else is true.
+int _is_true(object a) {
+  return !(a.x == INT_TAG && a.y == 0);
+}
+
+object _truth(int truth) {
+  return _to_object(INT_TAG, truth ? 1 : 0);
+}
+
+// Ints stay ints, and anything with a float in it is a float.
+object _add(object a, object b) {
+  if (a.x == INT_TAG && b.x == INT_TAG) {
+    return _to_object(INT_TAG, a.y + b.y);
+  }
+  return _float_object(_number_value(a) + _number_value(b));
+}
+
+object _subtract(object a, object b) {
+  if (a.x == INT_TAG && b.x == INT_TAG) {
+    return _to_object(INT_TAG, a.y - b.y);
+  }
+  return _float_object(_number_value(a) - _number_value(b));
+}
+
+object _multiply(object a, object b) {
+  if (a.x == INT_TAG && b.x == INT_TAG) {
+    return _to_object(INT_TAG, a.y * b.y);
+  }
+  return _float_object(_number_value(a) * _number_value(b));
+}
+
+// Dividing two ints rounds towards zero.
+object _divide(object a, object b) {
+  if (a.x == INT_TAG && b.x == INT_TAG) {
+    if (b.y == 0) {
+      _fail("division by zero");
+    }
+    return _to_object(INT_TAG, a.y / b.y);
+  }
+  return _float_object(_number_value(a) / _number_value(b));
+}
+
+object _remainder(object a, object b) {
+  if (a.x != INT_TAG || b.x != INT_TAG) {
+    _fail("'%' only takes ints");
+  }
+  if (b.y == 0) {
+    _fail("division by zero");
+  }
+  return _to_object(INT_TAG, a.y % b.y);
+}
+
+// The exponent has to be an int - an int to a
+// negative power is a float.
+object _power(object a, object b) {
+  if (b.x != INT_TAG) {
+    _fail("an exponent has to be an int");
+  }
+  long n = b.y < 0 ? -b.y : b.y;
+  if (a.x == INT_TAG && b.y >= 0) {
+    long result = 1;
+    for (long i = 0; i < n; i++) {
+      result *= a.y;
+    }
+    return _to_object(INT_TAG, result);
+  }
+  double base = _number_value(a);
+  double result = 1;
+  for (long i = 0; i < n; i++) {
+    result *= base;
+  }
+  return _float_object(b.y < 0 ? 1 / result : result);
+}
+
+object _negate(object a) {
+  if (a.x == INT_TAG) {
+    return _to_object(INT_TAG, -a.y);
+  }
+  return _float_object(-_number_value(a));
+}
+
+// Numbers are equal if their values are, strings and symbols
+// if their text is, and lists if their items are.
+int _equals(object a, object b) {
+  if ((a.x == INT_TAG || a.x == FLOAT_TAG) && (b.x == INT_TAG || b.x == FLOAT_TAG)) {
+    if (a.x == INT_TAG && b.x == INT_TAG) {
+      return a.y == b.y;
+    }
+    return _number_value(a) == _number_value(b);
+  }
+  if (a.x != b.x) {
+    return 0;
+  }
+  if (a.x == STRING_TAG || a.x == SYMBOL_TAG) {
+    string *x = (string *) a.y;
+    string *y = (string *) b.y;
+    return x->length == y->length && memcmp(x->data, y->data, x->length) == 0;
+  }
+  if (a.x == LIST_TAG) {
+    list *x = (list *) a.y;
+    list *y = (list *) b.y;
+    if (x->length != y->length) {
+      return 0;
+    }
+    for (long i = 0; i < x->length; i++) {
+      if (!_equals(x->items[i], y->items[i])) {
+        return 0;
+      }
+    }
+    return 1;
+  }
+  return a.y == b.y;
+}
+
+// Less than zero if `a` comes before `b`, zero if they're the same,
+// and more than zero if it comes after - numbers are ordered by
+// value, and strings by their bytes.
+int _compare(object a, object b) {
+  if (a.x == STRING_TAG && b.x == STRING_TAG) {
+    string *x = (string *) a.y;
+    string *y = (string *) b.y;
+    long shorter = x->length < y->length ? x->length : y->length;
+    int order = memcmp(x->data, y->data, shorter);
+    if (order != 0) {
+      return order;
+    }
+    return (x->length > y->length) - (x->length < y->length);
+  }
+  if (a.x == INT_TAG && b.x == INT_TAG) {
+    return (a.y > b.y) - (a.y < b.y);
+  }
+  double x = _number_value(a);
+  double y = _number_value(b);
+  return (x > y) - (x < y);
+}
+
+object _equal(object a, object b) {
+  return _truth(_equals(a, b));
+}
+
+object _not_equal(object a, object b) {
+  return _truth(!_equals(a, b));
+}
+
+object _less(object a, object b) {
+  return _truth(_compare(a, b) < 0);
+}
+
+object _less_equal(object a, object b) {
+  return _truth(_compare(a, b) <= 0);
+}
+
+object _greater(object a, object b) {
+  return _truth(_compare(a, b) > 0);
+}
+
+object _greater_equal(object a, object b) {
+  return _truth(_compare(a, b) >= 0);
+}
+
+// Both sides of `and` and `or` are always evaluated - the
+// result is the side that decided it, like in lisp.
+object _and(object a, object b) {
+  return _is_true(a) ? b : a;
+}
+
+object _or(object a, object b) {
+  return _is_true(a) ? a : b;
+}
+
+object _not(object a) {
+  return _truth(!_is_true(a));
+}
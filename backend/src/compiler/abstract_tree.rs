@@ -1,9 +1,15 @@
 // compiler/abstract_tree.rs
 
-use utils::{Result, Position, err_position};
+use utils::{Error, Result, Span, err_span};
+use compiler::visit::{Context, Walk};
+use compiler::schema::count;
 use self::AbstractTree::*;
 
-pub static BLOCK_IDENTIFIER: &'static str = "block";
+pub static BLOCK_IDENTIFIER: &str = "block";
+pub static LIST_IDENTIFIER: &str = "list";
+pub static MEMBER_IDENTIFIER: &str = ".";
+pub static KEYWORD_IDENTIFIER: &str = ":";
+pub static INTERPOLATION_IDENTIFIER: &str = "interpolate";
 
 /// TokenType is supposed to relay any information
 /// about the Token that would be known from the first
@@ -12,239 +18,245 @@ pub static BLOCK_IDENTIFIER: &'static str = "block";
 /// string representation.
 ///
 /// A `Symbol` type is the most basic - representing an ident of the language.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TokenType {
-    Flag, // Used internally - should not be encountered by outside people.
     Symbol,
-    Int, /* Str,
-          * Float, */
+    Int, // The string holds the literal's value, in decimal.
+    Str, // The string holds the literal's contents, escapes already resolved.
+    Float,
+    // Stands in for something that couldn't be parsed -
+    // the string is the error's description.
+    Error,
 }
 
 /// The AbstractTree is what is given to the `compile`
 /// function of the compiler module. It consists of
 /// nodes and tokens - nodes simply hold more abstract
 /// trees, whereas tokens have a TokenType and a string
-/// representation. All AbstractTree's have a position
-/// that is used for reporting errors.
-#[derive(Debug, Eq, PartialEq)]
+/// representation. All AbstractTree's have the span
+/// of source they were parsed from, which is used
+/// for reporting errors.
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum AbstractTree {
-    Node(Vec<AbstractTree>, Position),
-    Token(TokenType, String, Position),
+    Node(Vec<AbstractTree>, Span),
+    Token(TokenType, String, Span),
 }
 
 impl AbstractTree {
-    /// This is only called in assert_only_top_level() so it can
-    /// be baseed to match_symbol()
-    fn fail_for_top_leval_call(a: &mut AbstractTree) -> Result<()> {
-        a.err(format!("{} was invoked without being on the top level", a.name()))
+    /// assert_only_top_level() returns an error for each
+    /// call that occurs somewhere that's not the top level.
+    pub fn assert_only_top_level(&self, s: &str) -> Vec<Error> {
+        let mut errors = vec![];
+        self.walk(&mut |at: &AbstractTree, context: &Context| {
+            // the top level's lines are one node deep
+            if context.depth > 1 && at.is_form(s) {
+                errors.push(Error {
+                    description: format!("{} was invoked without being on the top level", s),
+                    span: at.span(),
+                    notes: vec![],
+                });
+            }
+            if at.is_quoted() {
+                Walk::Skip
+            } else {
+                Walk::Continue
+            }
+        });
+        errors
     }
 
-    /// assert_only_top_level() will return a Result::Err if
-    /// a call occurs somewhere that's not the top level.
-    pub fn assert_only_top_level<'a>(&mut self, s: &'a str) -> Result<()> {
-        // Go two nodes deep and assert there are no more after that.
-        match self {
-            &mut Node(ref mut ats, _) => {
-                ats.iter_mut().fold(Ok(()), |acc, at| {
-                    acc.and_then(|_| {
-                        match at {
-                            &mut Node(ref mut ats, _) => {
-                                ats.iter_mut().fold(Ok(()), |acc, a| {
-                                    acc.and_then(|_| {
-                                        a.match_symbol(s, AbstractTree::fail_for_top_leval_call)
-                                    })
-                                })
-                            }
-                            _ => Ok(()),
-                        }
-                    })
-                })
+    /// Like match_symbol(), but carries on past errors -
+    /// returning every error `f` gave. Quoted code is
+    /// data, so it isn't looked inside.
+    pub fn match_symbol_all<F>(&mut self, s: &str, mut f: F) -> Vec<Error>
+        where F: FnMut(&mut AbstractTree) -> Result<()>
+    {
+        let mut errors = vec![];
+        self.walk_mut(&mut |at: &mut AbstractTree, _: &Context| {
+            if at.is_form(s) {
+                errors.extend(f(at).err());
             }
-            _ => Ok(()),
-        }
+            if at.is_quoted() {
+                Walk::Skip
+            } else {
+                Walk::Continue
+            }
+        });
+        errors
     }
 
-    pub fn match_symbol<'a>(&mut self,
-                            s: &'a str,
-                            f: fn(&mut AbstractTree) -> Result<()>)
-                            -> Result<()> {
-        let start = match self {
-                        &mut Node(ref ats, _) => {
-                            match ats.get(0) {
-                                Some(&Token(TokenType::Symbol, ref a, _)) => {
-                                    if s == a {
-                                        // this is so I can destructure immutably
-                                        // and then call f on the mutable self object
-                                        self.err("this is a goto to f(self)".to_string())
-                                    } else {
-                                        Ok(())
-                                    }
-                                }
-                                _ => Ok(()),
-                            }
-                        }
-                        _ => Ok(()),
-                    }
-                    .or_else(|_| f(self));
+    /// Calls `f` on every node named `s`, outside in,
+    /// stopping at the first error.
+    pub fn match_symbol<F>(&mut self, s: &str, mut f: F) -> Result<()>
+        where F: FnMut(&mut AbstractTree) -> Result<()>
+    {
+        let mut result = Ok(());
+        self.walk_mut(&mut |at: &mut AbstractTree, _: &Context| {
+            if at.is_form(s) {
+                if let Err(error) = f(at) {
+                    result = Err(error);
+                    return Walk::Stop;
+                }
+            }
+            Walk::Continue
+        });
+        result
+    }
 
-        match self {
-            &mut Node(ref mut ats, _) => {
-                ats.iter_mut().fold(start,
-                                    |results, x| results.and_then(|_| x.match_symbol(s, f)))
+    /// Whether this is a node named `s`, like `(s ...)`.
+    pub fn is_form(&self, s: &str) -> bool {
+        match *self {
+            Node(ref ats, _) => {
+                matches!(ats.first(), Some(&Token(TokenType::Symbol, ref a, _)) if a == s)
             }
-            _ => start,
+            _ => false,
         }
     }
 
+    /// Whether this is a `(quote x)` or a `(quasiquote x)`.
+    pub fn is_quoted(&self) -> bool {
+        self.is_node() &&
+        matches!(self.name().map(|name| name.as_str()), Ok("quote") | Ok("quasiquote"))
+    }
+
+    /// The errors the parser left in the tree, in the
+    /// order they appear.
+    pub fn syntax_errors(&self) -> Vec<Error> {
+        match *self {
+            Node(ref ats, _) => ats.iter().flat_map(|at| at.syntax_errors()).collect(),
+            Token(TokenType::Error, ref description, ref span) => {
+                vec![Error {
+                    description: description.clone(),
+                    span: span.clone(),
+                    notes: vec![],
+                }]
+            }
+            _ => vec![],
+        }
+    }
 
     // Functions for validating ast
 
     pub fn check_min_length(&self, i: usize) -> Result<()> {
         match self {
-            &Node(ref ats, _) => {
+            Node(ats, _) => {
                 if ats.len() >= i {
                     Ok(())
                 } else {
-                    self.err(format!("{} takes at least {} arguments", self.name(), i - 1))
+                    self.err(format!("'{}' takes at least {}",
+                                     self.name()?,
+                                     count(i - 1, "argument")))
                 }
             }
-            _ => panic!(format!("check_length called on not a node: {:?}", self)),
+            _ => self.err("expected a call here".to_string()),
         }
     }
 
     pub fn check_length(&self, i: usize) -> Result<()> {
         match self {
-            &Node(ref ats, _) => {
+            Node(ats, _) => {
                 if ats.len() == i {
                     Ok(())
                 } else {
-                    self.err(format!("{} takes {} arguments", self.name(), i - 1))
+                    self.err(format!("'{}' takes {}", self.name()?, count(i - 1, "argument")))
                 }
             }
-            _ => panic!(format!("check_length called on not a node: {:?}", self)),
-        }
-    }
-
-    /// check_argument_block will check to make sure the argument_number'th
-    /// argument is formatted like a block.
-    pub fn check_argument_block(&self, argument_number: usize) -> Result<()> {
-        let error = self.err(format!("{} expects a block for its {}th argument",
-                                     self.name(),
-                                     argument_number));
-        let argument = self.argument(argument_number);
-        Ok(())
-            .and_then(|_| argument.assert_node(error.clone()))
-            .and_then(|_| argument.check_min_length(2))
-            .and_then(|_| {
-                // make sure the block starts with 'block'
-                match argument {
-                    &Node(ref ats, _) => {
-                        let block_error = self.err("a block takes a \
-                                                     list of arguments \
-                                                     followed by a list \
-                                                     of expressions"
-                                                       .to_string());
-                        Ok(())
-                            .and_then(|_| {
-                                match ats.get(0).unwrap() {
-                                    &Token(TokenType::Symbol, ref a, _) => {
-                                        if a == BLOCK_IDENTIFIER {
-                                            Ok(())
-                                        } else {
-                                            error.clone()
-                                        }
-                                    }
-                                    _ => error.clone(),
-                                }
-                            })
-                            .and_then(|_| ats.last().unwrap().assert_node(block_error.clone()))
-                    }
-                    _ => panic!("I already asserted this was a node."),
-                }
-            })
-    }
-
-    /// Given an AbstractTree, assert it's a Node not a Token.
-    ///
-    /// # Examples:
-    ///
-    /// ```
-    /// let error = Node(vec![], Position(0,0)).err("this is an error")
-    ///
-    /// Node(vec![], Position(0,0)).assert_node().ok().unwrap()
-    /// Token(Symbol, "", Position(0,0)).assert_node().err().unwrap()
-    /// ```
-    ///
-    fn assert_node(&self, error: Result<()>) -> Result<()> {
-        match self {
-            &Node(_, _) => Ok(()),
-            _ => error,
+            _ => self.err("expected a call here".to_string()),
         }
     }
 
     // Functions for reading the ast
 
     /// Get an immutable reference to the ith argument of a node.
-    pub fn argument(&self, i: usize) -> &AbstractTree {
-        self.arguments().get(i).unwrap()
+    pub fn argument(&self, i: usize) -> Result<&AbstractTree> {
+        match self.arguments()?.get(i) {
+            Some(argument) => Ok(argument),
+            None => self.err(format!("expected an argument at position {}", i)),
+        }
     }
 
     /// Get an immutabe reference to the arguments of a node
     ///
-    /// This will panic if called on a Token.
-    pub fn arguments(&self) -> &Vec<AbstractTree> {
+    /// This is an error if called on a Token.
+    pub fn arguments(&self) -> Result<&Vec<AbstractTree>> {
         match self {
-            &Node(ref ats, _) => return ats,
-            _ => panic!("fn arguments called on a Token"),
+            Node(ats, _) => Ok(ats),
+            _ => self.err("expected a call here".to_string()),
         }
     }
 
-    pub fn arguments_mut(&mut self) -> &mut Vec<AbstractTree> {
+    pub fn arguments_mut(&mut self) -> Result<&mut Vec<AbstractTree>> {
         match self {
-            &mut Node(ref mut ats, _) => return ats,
-            _ => panic!("fn arguments called on a Token"),
+            &mut Node(ref mut ats, _) => Ok(ats),
+            _ => self.err("expected a call here".to_string()),
         }
     }
 
     /// Get the 'name' of a Node - defined to be the
     /// string of the first token if the abstract tree is
-    /// a node and has a first token.
-    pub fn name<'a>(&'a self) -> &'a String {
+    /// a node and has a first token, otherwise an error.
+    pub fn name(&self) -> Result<&String> {
         match self {
-            &Node(ref ats, _) => {
-                match ats.get(0) {
-                    Some(&Token(TokenType::Symbol, ref a, _)) => a,
-                    _ => {
-                        panic!("cannot access the name of just any node");
-                    }
+            Node(ats, _) => {
+                match ats.first() {
+                    Some(&Token(TokenType::Symbol, ref a, _)) => Ok(a),
+                    _ => self.err("expected a name here".to_string()),
                 }
             }
-            &Token(_, ref data, _) => data,
+            Token(_, data, _) => Ok(data),
+        }
+    }
+
+    /// Compares two trees, ignoring the spans they were
+    /// parsed from - e.g. to check that reformatting some
+    /// source hasn't changed what it means.
+    pub fn eq_ignoring_spans(&self, other: &AbstractTree) -> bool {
+        match (self, other) {
+            (Node(ats, _), Node(others, _)) => {
+                ats.len() == others.len() &&
+                ats.iter().zip(others.iter()).all(|(a, b)| a.eq_ignoring_spans(b))
+            }
+            (Token(token_type, data, _), Token(other_type, other_data, _)) => {
+                token_type == other_type && data == other_data
+            }
+            _ => false,
         }
     }
 
-    /// The Position of an abstract tree -
+    /// How many levels deep the tree goes - a Token is 1,
+    /// and a Node is one more than its deepest child. It's
+    /// found without recursing, so any tree can be measured.
+    pub fn height(&self) -> usize {
+        let mut height = 0;
+        let mut trees = vec![(self, 1)];
+        while let Some((tree, depth)) = trees.pop() {
+            height = height.max(depth);
+            if let Node(ref ats, _) = *tree {
+                trees.extend(ats.iter().map(|at| (at, depth + 1)));
+            }
+        }
+        height
+    }
+
+    /// The Span of an abstract tree -
     /// both a Node and a Token have it, but
     /// accessing it requires deconstructing
     /// which is why this method is useful.
-    pub fn position(&self) -> Position {
+    pub fn span(&self) -> Span {
         match self {
-            &Node(_, ref position) => position.clone(),
-            &Token(_, _, ref position) => position.clone(),
+            Node(_, span) => span.clone(),
+            Token(_, _, span) => span.clone(),
         }
     }
 
     /// Generate an utils::Result type from a discription
-    /// passed in and this abstract tree's position.
+    /// passed in and this abstract tree's span.
     pub fn err<T>(&self, description: String) -> Result<T> {
-        err_position(self.position(), description)
+        err_span(self.span(), description)
     }
 
     pub fn is_node(&self) -> bool {
-        match self {
-            &Node(_, _) => true,
-            _ => false,
-        }
+        matches!(*self, Node(_, _))
     }
 }
 
@@ -254,20 +266,35 @@ mod tests {
     use utils::Result;
     use utils::tests::generate_data;
 
-    static mut foo_visitor_count: i64 = 0;
-    fn visitor_match_symbol(_: &mut AbstractTree) -> Result<()> {
-        unsafe {
-            foo_visitor_count += 1;
-        };
-        Ok(())
-    }
-
     #[test]
     fn test_match_symbol() {
         let mut data = generate_data();
-        data.match_symbol("foo", visitor_match_symbol).ok().unwrap();
-        assert_eq!(unsafe { foo_visitor_count }, 2);
-        unsafe { foo_visitor_count = 0 };
+        let mut count = 0;
+        data.match_symbol("foo", |_| {
+                count += 1;
+                Ok(())
+            })
+            .ok()
+            .unwrap();
+        assert_eq!(count, 2);
+
+        // the first error stops it
+        let mut count = 0;
+        let result = data.match_symbol("foo", |at| {
+            count += 1;
+            at.err("stop".to_string())
+        });
+        assert_eq!(result.err().unwrap().description, "stop");
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_assert_only_top_level() {
+        let data = generate_data();
+        assert!(data.assert_only_top_level("define").is_empty());
+        let errors = data.assert_only_top_level("foo");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].description, "foo was invoked without being on the top level");
     }
 
     fn visitor_check_length_2(at: &mut AbstractTree) -> Result<()> {
@@ -282,13 +309,22 @@ mod tests {
         let mut data = generate_data();
         assert!(data.match_symbol("define", visitor_check_length_2).is_ok());
         assert!(data.match_symbol("define", visitor_check_length_1).is_err());
+        assert_eq!(data.match_symbol_all("foo", visitor_check_length_1).len(), 2);
+    }
+
+    #[test]
+    fn test_height() {
+        let data = generate_data();
+        assert_eq!(data.height(), 4);
+        assert_eq!(data.arguments().unwrap()[1].height(), 2);
+        assert_eq!(data.arguments().unwrap()[1].argument(0).unwrap().height(), 1);
     }
 
     #[test]
-    fn test_err_and_position() {
+    fn test_err_and_span() {
         let data = generate_data();
         let error = data.err::<Result<()>>("this is an error".to_string()).err().unwrap();
         assert_eq!(error.description, "this is an error".to_string());
-        assert_eq!(error.position, data.position());
+        assert_eq!(error.span, data.span());
     }
 }
@@ -2,21 +2,117 @@
 
 use std::collections::HashMap;
 
-use utils::{Result, IR, err_position};
-use compiler::abstract_tree::{AbstractTree, TokenType};
-use compiler::abstract_tree::AbstractTree::*;
+use utils::{Result, Span, IR, err_span};
+use compiler::hir::{Argument, Define, Expr, Item, Literal, Parameter};
+use compiler::keywords::compile_arguments;
+use compiler::quote::compile_quote;
+use compiler::schema::count;
+use compiler::variables::{compile_assign, compile_let, declarations};
+
+/// Every %object is a tag followed by a payload - these
+/// have to match the `tag` enum in lib/main.c.
+pub const INT_TAG: i64 = 0;
+pub const FLOAT_TAG: i64 = 2;
+pub const SYMBOL_TAG: i64 = 3;
+pub const LIST_TAG: i64 = 4;
+
+/// The functions of lib/main.c that acorn can call,
+/// and how many arguments they take.
+pub static RUNTIME_FUNCTIONS: &[(&str, usize)] = &[("print_number", 1),
+                                                  ("print", 1),
+                                                  ("length", 1),
+                                                  ("get", 2),
+                                                  ("set", 3),
+                                                  ("push", 2),
+                                                  ("to_string", 1),
+                                                  ("concat", 2),
+                                                  ("pop", 1),
+                                                  ("get_field", 2),
+                                                  ("send", 3)];
+
+/// The functions of lib/main.c that the operators are compiled to,
+/// by operator and how many arguments it's given - `a - b` calls
+/// `_subtract`, and `-a` calls `_negate`.
+pub static OPERATOR_FUNCTIONS: &[(&str, usize, &str)] = &[("+", 2, "_add"),
+                                                          ("-", 2, "_subtract"),
+                                                          ("-", 1, "_negate"),
+                                                          ("*", 2, "_multiply"),
+                                                          ("/", 2, "_divide"),
+                                                          ("%", 2, "_remainder"),
+                                                          ("^", 2, "_power"),
+                                                          ("==", 2, "_equal"),
+                                                          ("!=", 2, "_not_equal"),
+                                                          ("<", 2, "_less"),
+                                                          ("<=", 2, "_less_equal"),
+                                                          (">", 2, "_greater"),
+                                                          (">=", 2, "_greater_equal"),
+                                                          ("and", 2, "_and"),
+                                                          ("or", 2, "_or"),
+                                                          ("not", 1, "_not")];
+
+/// The function an operator given `arity` arguments is compiled to.
+pub fn operator_function(name: &str, arity: usize) -> Option<&'static str> {
+    OPERATOR_FUNCTIONS.iter()
+                      .find(|&&(operator, n, _)| operator == name && n == arity)
+                      .map(|&(_, _, function)| function)
+}
+
+/// How many arguments an operator can be given - none if
+/// `name` isn't an operator.
+pub fn operator_arities(name: &str) -> Vec<usize> {
+    OPERATOR_FUNCTIONS.iter()
+                      .filter(|&&(operator, _, _)| operator == name)
+                      .map(|&(_, n, _)| n)
+                      .collect()
+}
 
 mod utils {
-    pub fn generate_function_arguments(start: usize, i: usize) -> String {
-        let mut output = "(".to_string();
-        for x in start..(start + i - 1) {
-            // comma formatting (facepalm)
-            if x != start {
-                output.push(',');
+    use compiler::schema::is_valid_name;
+
+    /// The argument list of a call, from the
+    /// registers holding each argument.
+    pub fn generate_function_arguments(names: &[String]) -> String {
+        let arguments = names.iter()
+                             .map(|name| format!("%object %{}", name))
+                             .collect::<Vec<_>>();
+        format!("({})", arguments.join(","))
+    }
+
+    /// The llvm name of a function - quoted, unless
+    /// it's a name llvm takes as it is.
+    pub fn global_name(name: &str) -> String {
+        if is_valid_name(name) {
+            format!("@{}", name)
+        } else {
+            format!("@\"{}\"", escape_string_constant(name))
+        }
+    }
+
+    /// Declares a function of lib/main.c, or one
+    /// linked in, that takes `arity` objects.
+    pub fn declaration(name: &str, arity: usize) -> String {
+        format!("declare %object {}({}) #0",
+                global_name(name),
+                vec!["%object"; arity].join(", "))
+    }
+
+    /// An instruction building an %object out of
+    /// a tag and a constant payload.
+    pub fn object_constant(tag: i64, payload: &str) -> String {
+        format!("insertvalue %object {{ i64 {}, i64 0 }}, i64 {}, 1", tag, payload)
+    }
+
+    /// Escapes a string for use in an llvm `c"..."` constant:
+    /// anything that isn't printable ascii is written as `\XX`.
+    pub fn escape_string_constant(string: &str) -> String {
+        let mut output = String::new();
+        for byte in string.bytes() {
+            if byte == b'"' || byte == b'\\' || !(0x20..0x7f).contains(&byte) {
+                output.push_str(&format!("\\{:02X}", byte));
+            } else {
+                output.push(byte as char);
             }
-            output.push_str(&format!("%object %ret.{}", x));
         }
-        output.push_str(")");
         output
     }
 }
@@ -28,45 +124,74 @@ mod utils {
 #[derive(Debug, Clone)]
 pub struct Assignee {
     pub name: String,
-    /// This is incremented each time this var
-    /// is assigned to. It's needed to convert to
-    /// ssa.
-    count: i64,
 }
 
 // probably going to get rid of this.
 impl Assignee {
     fn new(name: String) -> Assignee {
-        Assignee {
-            name: name,
-            count: -1,
-        }
+        Assignee { name }
     }
 }
 
 pub struct LLVMBackend {
-    pub abstract_tree: Option<AbstractTree>,
-    pub transformations: HashMap<String, fn(&mut LLVMBackend, &mut AbstractTree) -> Result<IR>>,
+    /// The items to compile - `compile` takes them.
+    pub program: Option<Vec<Item>>,
+    /// The names `let` declares in the function being compiled,
+    /// at their first `let` - to point at when one's used too soon.
+    pub declarations: HashMap<String, Span>,
+    /// How many arguments each function of lib/main.c takes.
+    arities: HashMap<String, usize>,
+    /// The functions that are called but not defined anywhere - they're
+    /// declared, to be linked in, and all calls have to agree on how
+    /// many arguments they take.
+    externals: HashMap<String, (usize, Span)>,
+    /// The parameters of each function made with `define`.
+    signatures: HashMap<String, Vec<Parameter>>,
     all_counter: HashMap<String, i64>,
     global_ir: Option<IR>,
     locals: Vec<HashMap<String, Assignee>>,
 }
 
 impl LLVMBackend {
-    pub fn new(a: AbstractTree) -> LLVMBackend {
+    pub fn new(program: Vec<Item>) -> LLVMBackend {
+        let mut global_ir = vec![
+            "target datalayout = \"e-m:e-i64:64-f80:128-n8:16:32:64-S128\"".to_string(),
+            "%object = type { i64, i64 }".to_string(),
+            "declare %object @_string_object(i8*, i64) #0".to_string(),
+            "declare %object @_symbol_object(i8*, i64) #0".to_string(),
+            "declare %object @_list_object(%object*, i64) #0".to_string(),
+            "declare %object @_list_concat(%object, %object) #0".to_string(),
+        ];
+        let operators = OPERATOR_FUNCTIONS.iter().map(|&(_, arity, name)| (name, arity));
+        let runtime_functions = RUNTIME_FUNCTIONS.iter()
+                                                 .cloned()
+                                                 .chain(operators)
+                                                 .collect::<Vec<_>>();
+        for &(name, arity) in &runtime_functions {
+            global_ir.push(self::utils::declaration(name, arity));
+        }
         LLVMBackend {
-            abstract_tree: Some(a),
-            transformations: HashMap::new(),
-            global_ir: Some(vec![
-                "target datalayout = \"e-m:e-i64:64-f80:128-n8:16:32:64-S128\"".to_string(),
-                "%object = type { i64, i64 }".to_string(),
-                "declare %object @print_number(%object) #0".to_string(),
-            ]),
+            program: Some(program),
+            declarations: HashMap::new(),
+            arities: runtime_functions.iter()
+                                      .map(|&(name, arity)| (name.to_string(), arity))
+                                      .collect(),
+            externals: HashMap::new(),
+            signatures: HashMap::new(),
+            global_ir: Some(global_ir),
             all_counter: HashMap::new(),
             locals: vec![],
         }
     }
 
+    /// Makes a function callable by name - every function
+    /// has to be added before anything is compiled. Calls to
+    /// it can give its parameters by name, like `width: 1`.
+    pub fn add_function(mut self, name: &str, parameters: Vec<Parameter>) -> LLVMBackend {
+        self.signatures.insert(name.to_string(), parameters);
+        self
+    }
+
     pub fn start_stack(&mut self) {
         self.all_counter.remove("ret"); // okay?
         self.locals.push(HashMap::new());
@@ -77,7 +202,7 @@ impl LLVMBackend {
     }
 
     pub fn get_var_index(&mut self, key: &str) -> i64 {
-        self.all_counter.get(key).map(|x| *x).unwrap_or(-1) + 1
+        self.all_counter.get(key).copied().unwrap_or(-1) + 1
     }
 
     pub fn load_var_ir(&mut self, existing_name: String, local_name: String) -> IR {
@@ -86,8 +211,8 @@ impl LLVMBackend {
         vec![format!("%{} = load %object, %object* %{}", existing_name, local_name)]
     }
 
-    pub fn set_var_ir(&mut self, existing_name: &String, new_value: String) -> IR {
-        if !self.get_assignee(existing_name).is_some() {
+    pub fn set_var_ir(&mut self, existing_name: &str, new_value: String) -> IR {
+        if self.get_assignee(existing_name).is_none() {
             self.add_assignee(existing_name);
         }
         vec![format!("store %object %{}, %object* %{}", new_value, existing_name)]
@@ -95,17 +220,18 @@ impl LLVMBackend {
 
     // this does not take into account whether
     // the variable already exists in the stack.
-    pub fn add_assignee(&mut self, name: &String) {
-        let assignee = Assignee::new(name.clone());
-        self.locals.last_mut().unwrap().insert(name.clone(), assignee);
+    pub fn add_assignee(&mut self, name: &str) {
+        let assignee = Assignee::new(name.to_string());
+        self.locals.last_mut().unwrap().insert(name.to_string(), assignee);
     }
 
-    pub fn get_assignee(&mut self, name: &String) -> Option<Assignee> {
+    pub fn get_assignee(&mut self, name: &str) -> Option<Assignee> {
         // This could be improved with a hashtable if it becomes a problem.
         for stack in &self.locals {
-            let assignee = stack.get(name);
-            if assignee.is_some() && &assignee.unwrap().name == name {
-                return Some(assignee.unwrap().clone()) // TODO: this is gross.
+            if let Some(assignee) = stack.get(name) {
+                if assignee.name == name {
+                    return Some(assignee.clone())
+                }
             }
         }
         None
@@ -117,143 +243,374 @@ impl LLVMBackend {
     }
 
     pub fn inc_counter(&mut self, key: &str) -> String {
-        let i = self.all_counter.get(key).map(|i| *i);
-        let v = if i.is_some() {
-            let value = i.unwrap() +  1;
-            self.all_counter.insert(key.to_string(), value);
-            value
-        } else {
-            self.all_counter.insert(key.to_string(), 0);
-            0
+        let v = match self.all_counter.get(key).copied() {
+            Some(i) => i + 1,
+            None => 0,
         };
+        self.all_counter.insert(key.to_string(), v);
         format!("{}.{}", key, v)
     }
 
 
-    pub fn handle(mut self,
-                  key: String,
-                  f: fn(&mut LLVMBackend, &mut AbstractTree) -> Result<IR>)
-                  -> LLVMBackend {
-        self.transformations.insert(key, f);
-        self
+    /// A string literal becomes a private global constant,
+    /// which the runtime wraps in a string object.
+    pub fn string_ir(&mut self, string: &str) -> IR {
+        let constant = self.string_constant(string);
+        vec![format!("%{} = call %object @_string_object({}, i64 {})",
+                     self.inc_counter("ret"),
+                     constant,
+                     string.len())]
     }
 
-    pub fn compile(&mut self) -> Result<IR> {
-        let mut abstract_tree = self.abstract_tree.take().unwrap();
-        match abstract_tree {
-            Node(ref mut ats, _) => {
-                ats.iter_mut().fold(Ok(vec![]), |acc, node| {
-                    acc.and_then(|mut ir| {
-                        self.compile_inner(node).and_then(|mut most_recently_compiled| {
-                            ir.append(&mut most_recently_compiled);
-                            Ok(ir)
-                        })
+    /// A quoted symbol is wrapped by the runtime
+    /// in the same way as a string.
+    pub fn symbol_ir(&mut self, name: &str) -> IR {
+        let constant = self.string_constant(name);
+        vec![format!("%{} = call %object @_symbol_object({}, i64 {})",
+                     self.inc_counter("ret"),
+                     constant,
+                     name.len())]
+    }
+
+    /// Adds a global constant holding `string`, and returns
+    /// an `i8*` operand pointing at its first byte.
+    fn string_constant(&mut self, string: &str) -> String {
+        let global = self.inc_counter("str");
+        let array_type = format!("[{} x i8]", string.len() + 1);
+        self.global_ir.as_mut().unwrap().push(
+            format!("@{} = private unnamed_addr constant {} c\"{}\\00\"",
+                    global,
+                    array_type,
+                    self::utils::escape_string_constant(string)));
+        format!("i8* getelementptr inbounds ({}, {}* @{}, i64 0, i64 0)",
+                array_type,
+                array_type,
+                global)
+    }
+
+    /// Builds a list out of the objects in the registers `items`: they're
+    /// stored in a stack array, which the runtime copies.
+    pub fn list_ir(&mut self, items: &[String]) -> IR {
+        if items.is_empty() {
+            return vec![format!("%{} = call %object @_list_object(%object* null, i64 0)",
+                                self.inc_counter("ret"))];
+        }
+        let array = self.inc_counter("list");
+        let array_type = format!("[{} x %object]", items.len());
+        let mut ir = vec![format!("%{} = alloca {}", array, array_type)];
+        let mut first = String::new();
+        for (i, item) in items.iter().enumerate() {
+            let pointer = self.inc_counter("item");
+            ir.push(format!("%{} = getelementptr inbounds {}, {}* %{}, i64 0, i64 {}",
+                            pointer,
+                            array_type,
+                            array_type,
+                            array,
+                            i));
+            ir.push(format!("store %object %{}, %object* %{}", item, pointer));
+            if i == 0 {
+                first = pointer;
+            }
+        }
+        ir.push(format!("%{} = call %object @_list_object(%object* %{}, i64 {})",
+                        self.inc_counter("ret"),
+                        first,
+                        items.len()));
+        ir
+    }
+
+    /// A list literal `[a b c]` - each item is compiled,
+    /// then the list is built out of their values.
+    pub fn compile_list(&mut self, items: &[Expr]) -> Result<IR> {
+        let mut ir = vec![];
+        let mut values = vec![];
+        for item in items {
+            ir.append(&mut self.compile_inner(item)?);
+            values.push(self.get_counter("ret"));
+        }
+        ir.append(&mut self.list_ir(&values));
+        Ok(ir)
+    }
+
+    /// A string with code in it, like `"total: #{count}"`, is
+    /// built from left to right: the value of each `#{..}` is
+    /// made a string with `to_string`, and each part is
+    /// `concat`ed onto the ones before it.
+    pub fn compile_interpolation(&mut self, parts: &[Expr]) -> Result<IR> {
+        let mut ir = vec![];
+        let mut string: Option<String> = None;
+        for part in parts {
+            let is_text = matches!(*part, Expr::Literal(Literal::Str(_), _));
+            ir.append(&mut self.compile_inner(part)?);
+            if !is_text {
+                let value = self.get_counter("ret");
+                ir.push(format!("%{} = call %object @to_string(%object %{})",
+                                self.inc_counter("ret"),
+                                value));
+            }
+            let part = self.get_counter("ret");
+            if let Some(before) = string {
+                ir.push(format!("%{} = call %object @concat(%object %{}, %object %{})",
+                                self.inc_counter("ret"),
+                                before,
+                                part));
+            }
+            string = Some(self.get_counter("ret"));
+        }
+        Ok(ir)
+    }
+
+    /// `a.b` reads the field `b` of `a` - the runtime's
+    /// `get_field` looks it up.
+    pub fn compile_member(&mut self, receiver: &Expr, name: &str) -> Result<IR> {
+        let (mut ir, receiver, name) = self.compile_receiver(receiver, name)?;
+        ir.push(format!("%{} = call %object @get_field(%object %{}, %object %{})",
+                        self.inc_counter("ret"),
+                        receiver,
+                        name));
+        Ok(ir)
+    }
+
+    /// `a.b x y` calls the method `b` of `a` - the runtime's `send`
+    /// dispatches on `a`, and passes it as the first argument.
+    pub fn compile_send(&mut self, receiver: &Expr, name: &str, arguments: &[Expr]) -> Result<IR> {
+        let (mut ir, receiver, name) = self.compile_receiver(receiver, name)?;
+        let mut values = vec![];
+        for argument in arguments {
+            ir.append(&mut self.compile_inner(argument)?);
+            values.push(self.get_counter("ret"));
+        }
+        ir.append(&mut self.list_ir(&values));
+        let arguments = self.get_counter("ret");
+        ir.push(format!("%{} = call %object @send(%object %{}, %object %{}, %object %{})",
+                        self.inc_counter("ret"),
+                        receiver,
+                        name,
+                        arguments));
+        Ok(ir)
+    }
+
+    /// Compiles the receiver and the member name of `a.b`, returning
+    /// the IR and the registers holding the two.
+    fn compile_receiver(&mut self, receiver: &Expr, name: &str) -> Result<(IR, String, String)> {
+        let mut ir = self.compile_inner(receiver)?;
+        let receiver = self.get_counter("ret");
+        ir.append(&mut self.symbol_ir(name));
+        Ok((ir, receiver, self.get_counter("ret")))
+    }
+
+    /// Joins the lists in the registers `a` and `b` into a new list.
+    pub fn list_concat_ir(&mut self, a: &str, b: &str) -> IR {
+        vec![format!("%{} = call %object @_list_concat(%object %{}, %object %{})",
+                     self.inc_counter("ret"),
+                     a,
+                     b)]
+    }
+
+    /// Errors if `name` isn't a local yet, but will be - it's
+    /// used at `span`, before the `let` that declares it.
+    pub fn check_declared(&mut self, name: &str, span: &Span) -> Result<()> {
+        if self.get_assignee(name).is_some() {
+            return Ok(());
+        }
+        match self.declarations.get(name) {
+            Some(declaration) => {
+                let declaration = declaration.clone();
+                err_span(span.clone(), format!("'{}' is used before it's declared", name))
+                    .map_err(|error| {
+                        error.note(declaration, format!("'{}' is declared here", name))
                     })
-                })
             }
-            _ => {
-                panic!("there should not be a node at the top level OR only call compile on the \
-                        top level.")
+            None => Ok(()),
+        }
+    }
+
+    pub fn compile(&mut self) -> Result<IR> {
+        let program = self.program.take().unwrap();
+        let mut ir = vec![];
+        for item in &program {
+            match *item {
+                Item::Define(ref define) => ir.append(&mut self.compile_define(define)?),
             }
         }
-        .map(|mut ir| {
-            let mut global_ir = self.global_ir.take().unwrap();
-            global_ir.append(&mut ir);
-            global_ir
-        })
-    }
-
-    // TODO: Move this to it's own module
-    pub fn compile_function_call(&mut self, tree: &mut AbstractTree) -> Result<IR> {
-        match tree {
-            &mut Node(ref mut ats, ref position) => {
-                let length = ats.len();
-                let mut iterator = ats.iter_mut();
-                let mut first_item = iterator.next().unwrap();
-                if length == 1 {
-                    self.compile_inner(first_item)
-                } else if length >= 1 {
-                    match first_item {
-                        &mut Node(_, ref position) => {
-                            err_position(position.clone(),
-                                         "unimplemented: no support for calling closures yet \
-                                          implemented"
-                                             .to_string())
-                        }
-                        &mut Token(TokenType::Symbol, ref mut fuction_name, _) => {
-                            let starting_index = self.get_var_index("ret") as usize;
-
-                            let mut ir = iterator.fold(Ok(vec![]), |acc, argument| {
-                                acc.and_then(|mut vector| {
-                                    self.compile_inner(argument).and_then(|mut argument_ir| {
-                                        vector.append(&mut argument_ir);
-                                        Ok(vector)
-                                    })
-                                })
-                            });
-
-                            let argument_names = self::utils::generate_function_arguments(starting_index, length);
-                            ir.iter_mut()
-                              .map(|inner| {
-                                  inner.push(format!("%{} = call %object @{}{}",
-                                                     self.inc_counter("ret"),
-                                                     fuction_name,
-                                                     argument_names))
-                              })
-                              .collect::<Vec<_>>();
-                            ir
+        let mut global_ir = self.global_ir.take().unwrap();
+        global_ir.append(&mut ir);
+        Ok(global_ir)
+    }
+
+    /// `define f { x y ... }` is a function taking an %object for each
+    /// parameter - they're stored in locals, like the `let`s of its body.
+    fn compile_define(&mut self, define: &Define) -> Result<IR> {
+        self.declarations = declarations(&define.block.parameters, &define.block.body)?;
+        self.start_stack();
+
+        let mut parameters = vec![];
+        let mut argument_ir = vec![];
+        for (i, parameter) in define.block.parameters.iter().enumerate() {
+            parameters.push(format!("%object %in_arg.{}", i));
+            argument_ir.append(&mut self.set_var_ir(&parameter.name, format!("in_arg.{}", i)));
+            self.add_assignee(&parameter.name);
+        }
+        let mut ir = vec![format!("define %object {}({}) {{",
+                                  self::utils::global_name(&define.name),
+                                  parameters.join(","))];
+
+        let body_ir = define.block.body.iter().try_fold(vec![], |mut ir, expression| {
+            self.compile_inner(expression).map(|mut new_ir| {
+                ir.append(&mut new_ir);
+                ir
+            })
+        });
+
+        self.declarations.clear();
+        let stack = self.end_stack();
+        let mut ir_from_stack = stack.values()
+            .map(|assignee| format!("%{} = alloca %object", assignee.name))
+            .collect::<Vec<_>>();
+        // sorted, so the same source always compiles to the same IR
+        ir_from_stack.sort();
+        ir.append(&mut ir_from_stack);
+        ir.append(&mut argument_ir);
+        ir.append(&mut body_ir?);
+        ir.push(format!("ret %object %{}", self.get_counter("ret")));
+        ir.push("}".to_string());
+        Ok(ir)
+    }
+
+    /// `f x y` calls the function `f` - a function made with
+    /// `define` can be given its parameters by name. A function
+    /// that isn't defined is declared, for the linker to find.
+    pub fn compile_function_call(&mut self,
+                                 function: &str,
+                                 span: &Span,
+                                 arguments: &[Argument])
+                                 -> Result<IR> {
+        if self.get_assignee(function).is_some() {
+            return err_span(span.clone(),
+                            "unimplemented: no support for calling closures yet implemented"
+                                .to_string());
+        }
+
+        // an argument can take any number of registers -
+        // its value is in the last one.
+        let (mut ir, argument_names) = match self.signatures.get(function).cloned() {
+            Some(parameters) => compile_arguments(self, function, span, &parameters, arguments)?,
+            None => {
+                let mut ir = vec![];
+                let mut argument_names = vec![];
+                for argument in arguments {
+                    match *argument {
+                        Argument::Positional(ref value) => {
+                            ir.append(&mut self.compile_inner(value)?);
+                            argument_names.push(self.get_counter("ret"));
                         }
-                        &mut Token(ref token_type, ref data, ref position) => {
-                            err_position(position.clone(),
-                                         format!("cannot call token {} of type {:?}",
-                                                 data,
-                                                 token_type))
+                        Argument::Keyword { ref name, ref span, .. } => {
+                            return err_span(span.clone(),
+                                            format!("'{}:' can only be passed to a function \
+                                                     made with define",
+                                                    name));
                         }
                     }
-                } else {
-                    err_position(position.clone(), "node with zero items".to_string())
                 }
+                (ir, argument_names)
             }
-            _ => panic!("compile_function_call not called on a node."),
+        };
+
+        if !self.signatures.contains_key(function) {
+            self.check_arity(function, span, argument_names.len())?;
         }
+        ir.push(format!("%{} = call %object {}{}",
+                        self.inc_counter("ret"),
+                        self::utils::global_name(function),
+                        self::utils::generate_function_arguments(&argument_names)));
+        Ok(ir)
     }
 
-    pub fn compile_token(&mut self, tree: &mut AbstractTree) -> Result<IR> {
-        match tree {
-            &mut Token(TokenType::Symbol, ref name, _) => {
-                match self.get_assignee(name) {
-                    Some(local) => {
-                        let counter = self.inc_counter("ret");
-                        Ok(self.load_var_ir(counter, local.name))
-                    },
-                    None => {
-                        Ok(vec![format!("%{} = call %object @{}()", self.inc_counter("ret"), name)])
-                    }
+    /// Checks a function of lib/main.c is given as many arguments as it
+    /// takes, and declares a function that isn't defined at its first call.
+    fn check_arity(&mut self, function: &str, span: &Span, arity: usize) -> Result<()> {
+        if let Some(&expected) = self.arities.get(function) {
+            if arity != expected {
+                return err_span(span.clone(),
+                                format!("'{}' takes {}", function, count(expected, "argument")));
+            }
+            return Ok(());
+        }
+        match self.externals.get(function).cloned() {
+            Some((expected, first)) => {
+                if arity != expected {
+                    return err_span(span.clone(),
+                                    format!("'{}' is given {} here, but {} before",
+                                            function,
+                                            count(arity, "argument"),
+                                            expected))
+                        .map_err(|error| error.note(first, "it's first called here".to_string()));
                 }
             }
-            &mut Token(TokenType::Int, ref integer_literal, _) => {
-                // what the hell is happening here.
-                // this is wrong.
-                Ok(vec![format!("%{} =l {}", self.inc_counter("ret"), integer_literal)])
+            None => {
+                self.externals.insert(function.to_string(), (arity, span.clone()));
+                self.global_ir.as_mut().unwrap().push(self::utils::declaration(function, arity));
             }
-            _ => tree.err("compile_token not called on a token.".to_string()),
         }
+        Ok(())
     }
 
-    pub fn compile_inner(&mut self, tree: &mut AbstractTree) -> Result<IR> {
-        {
-            let transformation = self.transformations
-                                     .get(&tree.name().clone())
-                                     .map(|trsfmts| *trsfmts);
-            if transformation.is_some() {
-                let function = transformation.unwrap();
-                return function(self, tree);
-            }
+    /// A name on its own is a local, or a call to a function -
+    /// a function with keyword parameters can be called without them.
+    pub fn compile_var(&mut self, name: &str, span: &Span) -> Result<IR> {
+        if let Some(local) = self.get_assignee(name) {
+            let counter = self.inc_counter("ret");
+            return Ok(self.load_var_ir(counter, local.name));
         }
-        if tree.is_node() {
-            self.compile_function_call(tree)
+        self.check_declared(name, span)?;
+        if self.arities.contains_key(name) || self.signatures.contains_key(name) {
+            self.compile_function_call(name, span, &[])
         } else {
-            self.compile_token(tree)
+            err_span(span.clone(), format!("'{}' isn't defined", name))
+        }
+    }
+
+    pub fn literal_ir(&mut self, literal: &Literal) -> IR {
+        match *literal {
+            Literal::Int(integer) => {
+                vec![format!("%{} = {}",
+                             self.inc_counter("ret"),
+                             self::utils::object_constant(INT_TAG, &integer.to_string()))]
+            }
+            Literal::Float(float) => {
+                // the payload is the double's bits.
+                let bits = (float.to_bits() as i64).to_string();
+                vec![format!("%{} = {}",
+                             self.inc_counter("ret"),
+                             self::utils::object_constant(FLOAT_TAG, &bits))]
+            }
+            Literal::Str(ref string) => self.string_ir(string),
+        }
+    }
+
+    pub fn compile_inner(&mut self, expression: &Expr) -> Result<IR> {
+        match *expression {
+            Expr::Literal(ref literal, _) => Ok(self.literal_ir(literal)),
+            Expr::Var(ref name, ref span) => self.compile_var(name, span),
+            Expr::Call { ref function, ref function_span, ref arguments, .. } => {
+                self.compile_function_call(function, function_span, arguments)
+            }
+            Expr::Member { ref receiver, ref name, .. } => self.compile_member(receiver, name),
+            Expr::Send { ref receiver, ref name, ref arguments, .. } => {
+                self.compile_send(receiver, name, arguments)
+            }
+            Expr::List(ref items, _) => self.compile_list(items),
+            Expr::Interpolate(ref parts, _) => self.compile_interpolation(parts),
+            Expr::Quote(ref datum, _) => compile_quote(self, datum),
+            Expr::Let { ref name, ref value, .. } => compile_let(self, name, value),
+            Expr::Assign { ref name, ref name_span, ref value, .. } => {
+                compile_assign(self, name, name_span, value)
+            }
+            Expr::Block(ref block) => {
+                err_span(block.span.clone(),
+                         "unimplemented: a block can only be the body of a define so far"
+                             .to_string())
+            }
         }
     }
 }
@@ -0,0 +1,185 @@
+// compiler/hir.rs
+
+use utils::Span;
+
+/// The high-level IR is what the backend compiles. It's
+/// lowered from the AbstractTree by `lower`, which checks the
+/// shape of every special form on the way - so the backend
+/// can take a `define` apart without looking for its pieces.
+///
+/// The top level of a program is a list of items.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Define(Define),
+}
+
+/// `define name { parameters ... body }`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Define {
+    pub name: String,
+    /// The span of the name.
+    pub span: Span,
+    pub block: Block,
+}
+
+/// `{ parameters ... body }` - only a define's block
+/// can be compiled so far.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub parameters: Vec<Parameter>,
+    pub body: Vec<Expr>,
+    pub span: Span,
+}
+
+/// A parameter of a block. A keyword parameter, declared like
+/// `width: 1`, has a default - it can be left out of a call, and
+/// is only given by name. The others are given in order, or
+/// by name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    pub name: String,
+    pub span: Span,
+    pub default: Option<Expr>,
+}
+
+/// The value of a literal, already parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Literal, Span),
+    /// A name on its own - a local, or a function to call.
+    Var(String, Span),
+    /// `f x y` - a call to the function named `f`.
+    Call {
+        function: String,
+        /// The span of the function's name.
+        function_span: Span,
+        arguments: Vec<Argument>,
+        span: Span,
+    },
+    /// `a.b` - the field `b` of `a`.
+    Member {
+        receiver: Box<Expr>,
+        name: String,
+        name_span: Span,
+        span: Span,
+    },
+    /// `a.b x y` - a call to the method `b` of `a`.
+    Send {
+        receiver: Box<Expr>,
+        name: String,
+        name_span: Span,
+        arguments: Vec<Expr>,
+        span: Span,
+    },
+    /// `[a b c]`.
+    List(Vec<Expr>, Span),
+    /// `"text #{code}"` - the parts, in order. Text
+    /// is a `Literal::Str`.
+    Interpolate(Vec<Expr>, Span),
+    /// A quote or quasiquote, and the data it builds.
+    Quote(Datum, Span),
+    /// `let x value`.
+    Let {
+        name: String,
+        name_span: Span,
+        value: Box<Expr>,
+        span: Span,
+    },
+    /// `x = value`.
+    Assign {
+        name: String,
+        name_span: Span,
+        value: Box<Expr>,
+        span: Span,
+    },
+    Block(Block),
+}
+
+/// An argument of a call to a function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Argument {
+    Positional(Expr),
+    /// `width: 1` - `span` covers the name and its colon.
+    Keyword {
+        name: String,
+        span: Span,
+        value: Expr,
+    },
+}
+
+/// The data a quote builds - quoted symbols and lists
+/// become runtime objects, and the unquotes of a quasiquote
+/// are compiled as they always are.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Datum {
+    Symbol(String),
+    Literal(Literal),
+    List(Vec<Datum>),
+    /// `,x` - the value of `x`.
+    Unquote(Box<Expr>),
+    /// `,@xs` - the items of the list `xs`, spliced into
+    /// the list around it.
+    Splice(Box<Expr>),
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match *self {
+            Expr::Literal(_, ref span) |
+            Expr::Var(_, ref span) |
+            Expr::List(_, ref span) |
+            Expr::Interpolate(_, ref span) |
+            Expr::Quote(_, ref span) |
+            Expr::Call { ref span, .. } |
+            Expr::Member { ref span, .. } |
+            Expr::Send { ref span, .. } |
+            Expr::Let { ref span, .. } |
+            Expr::Assign { ref span, .. } |
+            Expr::Block(Block { ref span, .. }) => span.clone(),
+        }
+    }
+
+    /// The expressions directly inside this one - a block's
+    /// body is inside it, but its defaults aren't.
+    pub fn children(&self) -> Vec<&Expr> {
+        match *self {
+            Expr::Literal(..) | Expr::Var(..) => vec![],
+            Expr::Call { ref arguments, .. } => {
+                arguments.iter()
+                         .map(|argument| match *argument {
+                             Argument::Positional(ref value) |
+                             Argument::Keyword { ref value, .. } => value,
+                         })
+                         .collect()
+            }
+            Expr::Member { ref receiver, .. } => vec![receiver],
+            Expr::Send { ref receiver, ref arguments, .. } => {
+                let mut children = vec![&**receiver];
+                children.extend(arguments);
+                children
+            }
+            Expr::List(ref items, _) | Expr::Interpolate(ref items, _) => items.iter().collect(),
+            Expr::Quote(ref datum, _) => datum.unquoted(),
+            Expr::Let { ref value, .. } | Expr::Assign { ref value, .. } => vec![value],
+            Expr::Block(ref block) => block.body.iter().collect(),
+        }
+    }
+}
+
+impl Datum {
+    /// The expressions unquoted in this datum, in order.
+    pub fn unquoted(&self) -> Vec<&Expr> {
+        match *self {
+            Datum::Symbol(_) | Datum::Literal(_) => vec![],
+            Datum::List(ref items) => items.iter().flat_map(Datum::unquoted).collect(),
+            Datum::Unquote(ref expr) | Datum::Splice(ref expr) => vec![&**expr],
+        }
+    }
+}
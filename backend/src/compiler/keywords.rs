@@ -0,0 +1,264 @@
+// compiler/keywords.rs
+
+use utils::{Result, Span, IR, err_span};
+use compiler::abstract_tree::{AbstractTree, TokenType, KEYWORD_IDENTIFIER};
+use compiler::abstract_tree::AbstractTree::*;
+use compiler::backend::LLVMBackend;
+use compiler::hir::{Argument, Expr, Parameter};
+use compiler::lower::Lowerer;
+use compiler::schema::count;
+
+/// Whether `tree` is a keyword, like `width: 1`.
+pub fn is_keyword(tree: &AbstractTree) -> bool {
+    tree.is_node() && matches!(tree.name(), Ok(name) if name == KEYWORD_IDENTIFIER)
+}
+
+/// The name of a keyword, and the span of the name and its colon.
+fn keyword_name(keyword: &AbstractTree) -> Result<(&String, Span)> {
+    match *keyword.argument(1)? {
+        Token(TokenType::Symbol, ref name, ref span) => {
+            Ok((name, span.to(&keyword.arguments()?[0].span())))
+        }
+        ref other => other.err("expected a name before ':'".to_string()),
+    }
+}
+
+/// Keywords are taken out of the calls they're passed to, so
+/// any left to lower are in the wrong place.
+pub fn lower_keyword(_: &Lowerer, tree: &AbstractTree) -> Result<Expr> {
+    let (name, span) = keyword_name(tree)?;
+    err_span(span,
+             format!("'{}:' can only be passed to a function made with define", name))
+}
+
+/// An argument of a call - given in order, or by name.
+pub fn lower_argument(lowerer: &Lowerer, tree: &AbstractTree) -> Result<Argument> {
+    if !is_keyword(tree) {
+        return lowerer.lower(tree).map(Argument::Positional);
+    }
+    tree.check_length(3)?;
+    let (name, span) = keyword_name(tree)?;
+    Ok(Argument::Keyword {
+        name: name.clone(),
+        span,
+        value: lowerer.lower(tree.argument(2)?)?,
+    })
+}
+
+/// The parameters a block declares, in order. They have to have
+/// different names, and their defaults have to be literals or
+/// quoted - a default is compiled where it's used, so it can't
+/// name anything.
+pub fn lower_parameters(lowerer: &Lowerer, declared: &[AbstractTree]) -> Result<Vec<Parameter>> {
+    let mut parameters: Vec<Parameter> = vec![];
+    for parameter in declared {
+        let parameter = match *parameter {
+            // the parser only gives symbols
+            Token(_, ref name, ref span) => {
+                Parameter {
+                    name: name.clone(),
+                    span: span.clone(),
+                    default: None,
+                }
+            }
+            _ if is_keyword(parameter) => {
+                parameter.check_length(3)?;
+                let (name, _) = keyword_name(parameter)?;
+                let default = parameter.argument(2)?;
+                let literal = match *default {
+                    Token(TokenType::Int, _, _) |
+                    Token(TokenType::Float, _, _) |
+                    Token(TokenType::Str, _, _) => true,
+                    _ => default.is_quoted(),
+                };
+                if !literal {
+                    return default.err("a default has to be a literal, or quoted".to_string());
+                }
+                Parameter {
+                    name: name.clone(),
+                    span: parameter.argument(1)?.span(),
+                    default: Some(lowerer.lower(default)?),
+                }
+            }
+            _ => return parameter.err("a block's parameters have to be plain names".to_string()),
+        };
+        if let Some(first) = parameters.iter().find(|first| first.name == parameter.name) {
+            return err_span(parameter.span.clone(),
+                            format!("'{}' is already a parameter", parameter.name))
+                .map_err(|error| {
+                    error.note(first.span.clone(), "it's first declared here".to_string())
+                });
+        }
+        parameters.push(parameter);
+    }
+    Ok(parameters)
+}
+
+/// Compiles the arguments of a call to a `define`, in the order
+/// they're written, and returns the registers of their values in
+/// the order of its parameters - with the defaults of any keyword
+/// parameters that weren't given.
+pub fn compile_arguments(backend: &mut LLVMBackend,
+                         name: &str,
+                         span: &Span,
+                         parameters: &[Parameter],
+                         arguments: &[Argument])
+                         -> Result<(IR, Vec<String>)> {
+    // which argument gives each parameter
+    let mut given: Vec<Option<usize>> = vec![None; parameters.len()];
+    let mut in_order = parameters.iter()
+                                 .enumerate()
+                                 .filter(|&(_, parameter)| parameter.default.is_none())
+                                 .map(|(i, _)| i);
+    for (index, argument) in arguments.iter().enumerate() {
+        let (slot, span) = match *argument {
+            Argument::Keyword { name: ref keyword, ref span, .. } => {
+                match parameters.iter().position(|parameter| parameter.name == *keyword) {
+                    Some(slot) => (slot, span.clone()),
+                    None => {
+                        return err_span(span.clone(),
+                                        format!("'{}' has no parameter '{}'", name, keyword));
+                    }
+                }
+            }
+            Argument::Positional(ref value) => {
+                match in_order.next() {
+                    Some(slot) => (slot, value.span()),
+                    None => {
+                        return err_span(value.span(), too_many_arguments(name, parameters))
+                    }
+                }
+            }
+        };
+        if let Some(first) = given[slot] {
+            return err_span(span, format!("'{}' is given twice", parameters[slot].name))
+                .map_err(|error| {
+                    error.note(argument_span(&arguments[first]),
+                               "it's first given here".to_string())
+                });
+        }
+        given[slot] = Some(index);
+    }
+    for (slot, parameter) in parameters.iter().enumerate() {
+        if given[slot].is_none() && parameter.default.is_none() {
+            return err_span(span.clone(),
+                            format!("'{}' needs a value for '{}'", name, parameter.name))
+                .map_err(|error| {
+                    error.note(parameter.span.clone(), "it's declared here".to_string())
+                });
+        }
+    }
+
+    // arguments are run in the order they're written
+    let mut ir = vec![];
+    let mut registers = vec![];
+    for argument in arguments {
+        let value = match *argument {
+            Argument::Positional(ref value) | Argument::Keyword { ref value, .. } => value,
+        };
+        ir.append(&mut backend.compile_inner(value)?);
+        registers.push(backend.get_counter("ret"));
+    }
+    let mut names = vec![];
+    for (slot, parameter) in parameters.iter().enumerate() {
+        if let Some(index) = given[slot] {
+            names.push(registers[index].clone());
+        } else if let Some(ref default) = parameter.default {
+            ir.append(&mut backend.compile_inner(default)?);
+            names.push(backend.get_counter("ret"));
+        }
+    }
+    Ok((ir, names))
+}
+
+/// Where an argument is - a keyword's is its name and colon.
+fn argument_span(argument: &Argument) -> Span {
+    match *argument {
+        Argument::Positional(ref value) => value.span(),
+        Argument::Keyword { ref span, .. } => span.clone(),
+    }
+}
+
+fn too_many_arguments(name: &str, parameters: &[Parameter]) -> String {
+    let in_order = parameters.iter().filter(|parameter| parameter.default.is_none()).count();
+    if in_order == parameters.len() {
+        format!("'{}' takes {}", name, count(in_order, "argument"))
+    } else {
+        format!("'{}' takes {} without keywords", name, count(in_order, "argument"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::{Error, IR};
+    use utils::tests::compile_source;
+
+    static DRAW: &str = "define draw { x y width: 1 color: 'black\nprint x\n}\n";
+
+    /// The line calling `draw`, in a program that defines it.
+    fn call_draw(call: &str) -> Result<IR, Error> {
+        compile_source(&format!("{}define start {{ a\n{}\n}}", DRAW, call))
+    }
+
+    #[test]
+    fn test_compile_keywords() {
+        let ir = call_draw("draw color: 'red 10 width: 3 20").unwrap();
+        assert!(ir.contains(&"define %object @draw(%object %in_arg.0,%object %in_arg.1,\
+                               %object %in_arg.2,%object %in_arg.3) {"
+                                  .to_string()));
+        // the arguments are run as they're written, and passed in order
+        let start = ir.iter().position(|line| line.starts_with("define %object @start")).unwrap();
+        assert!(ir[start + 3].starts_with("%ret.0 = call %object @_symbol_object"));
+        assert!(ir[start + 3].ends_with("i64 3)"));
+        assert!(ir.contains(&"%ret.4 = call %object @draw(%object %ret.1,%object %ret.3,\
+                              %object %ret.2,%object %ret.0)"
+                                 .to_string()));
+
+        // keywords that aren't given are their defaults
+        let ir = call_draw("draw 10 20").unwrap();
+        assert!(ir.contains(&"%ret.4 = call %object @draw(%object %ret.0,%object %ret.1,\
+                              %object %ret.2,%object %ret.3)"
+                                 .to_string()));
+        assert!(call_draw("draw y: 2 x: 1").is_ok());
+    }
+
+    #[test]
+    fn test_keyword_errors() {
+        let error = |call: &str| {
+            let error = call_draw(call).err().unwrap();
+            let source = format!("{}define start {{ a\n{}\n}}", DRAW, call);
+            (error.description,
+             source[error.span.start_offset..error.span.end_offset].to_string())
+        };
+        assert_eq!(error("draw 1 2 height: 3"),
+                   ("'draw' has no parameter 'height'".to_string(), "height:".to_string()));
+        assert_eq!(error("draw 1 2 width: 3 width: 4"),
+                   ("'width' is given twice".to_string(), "width:".to_string()));
+        assert_eq!(error("draw 1 2 x: 3"), ("'x' is given twice".to_string(), "x:".to_string()));
+        assert_eq!(error("draw 1"),
+                   ("'draw' needs a value for 'y'".to_string(), "draw".to_string()));
+        assert_eq!(error("draw 1 2 3"),
+                   ("'draw' takes 2 arguments without keywords".to_string(), "3".to_string()));
+        assert_eq!(error("print a end: 1"),
+                   ("'end:' can only be passed to a function made with define".to_string(),
+                    "end:".to_string()));
+
+        let twice = call_draw("draw 1 2 width: 3 width: 4").err().unwrap();
+        assert_eq!(twice.notes[0].description, "it's first given here");
+
+        let error = |define: &str| compile_source(define).err().unwrap().description;
+        assert_eq!(error("define f { a a\na\n}"), "'a' is already a parameter");
+        assert_eq!(error("define f { a b: a\na\n}"), "a default has to be a literal, or quoted");
+        assert_eq!(error("define f { a b: []\na\n}"), "a default has to be a literal, or quoted");
+        assert!(compile_source("define f { a b: '[] c: \"s\" d: 1.5\na\n}").is_ok());
+
+        let call = |define: &str, call: &str| {
+            let source = format!("{}\ndefine start {{ a\n{}\n}}", define, call);
+            compile_source(&source).err().unwrap().description
+        };
+        assert_eq!(call("define g { a\na\n}", "g 1 2"), "'g' takes 1 argument");
+        assert_eq!(call("define g { a b\na\n}", "g 1 2 3"), "'g' takes 2 arguments");
+        assert_eq!(call("define g { a b: 1\na\n}", "g 1 2"),
+                   "'g' takes 1 argument without keywords");
+    }
+}
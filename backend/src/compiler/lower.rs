@@ -0,0 +1,356 @@
+// compiler/lower.rs
+
+use std::collections::HashMap;
+
+use utils::{Error, MultiResult, Result, Span, err_span};
+use compiler::abstract_tree::{AbstractTree, TokenType, MEMBER_IDENTIFIER};
+use compiler::abstract_tree::AbstractTree::*;
+use compiler::hir::{Block, Define, Expr, Item, Literal};
+use compiler::keywords::{lower_argument, lower_parameters};
+use compiler::backend::{operator_arities, operator_function};
+use compiler::schema::{Schema, count};
+use compiler::visit::{Context, Walk};
+
+/// A lowering turns a special form into HIR - it's registered
+/// with `handle` under the form's name, next to its schema.
+pub type Lowering = fn(&Lowerer, &AbstractTree) -> Result<Expr>;
+
+/// Whether a tree is a member access, like `a.b`.
+fn is_member(tree: &AbstractTree) -> bool {
+    tree.is_node() && matches!(tree.name(), Ok(name) if name == MEMBER_IDENTIFIER)
+}
+
+/// The function to call for `name` - an operator is compiled to
+/// the runtime function for it, like `a + b` to `_add a b`.
+fn operator(name: &str, span: &Span, arity: usize) -> Result<String> {
+    let mut arities = operator_arities(name);
+    arities.sort();
+    if arities.is_empty() {
+        return Ok(name.to_string());
+    }
+    match operator_function(name, arity) {
+        Some(function) => Ok(function.to_string()),
+        None => {
+            let (last, rest) = arities.split_last().unwrap();
+            let mut counts = rest.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+            counts.push(count(*last, "argument"));
+            err_span(span.clone(), format!("'{}' takes {}", name, counts.join(" or ")))
+        }
+    }
+}
+
+/// The value of a literal token.
+pub fn literal(tree: &AbstractTree) -> Result<Literal> {
+    match *tree {
+        Token(TokenType::Int, ref text, ref span) => {
+            match text.parse::<i64>() {
+                Ok(integer) => Ok(Literal::Int(integer)),
+                Err(_) => err_span(span.clone(), format!("invalid integer literal '{}'", text)),
+            }
+        }
+        Token(TokenType::Float, ref text, ref span) => {
+            match text.parse::<f64>() {
+                Ok(float) => Ok(Literal::Float(float)),
+                Err(_) => err_span(span.clone(), format!("invalid float literal '{}'", text)),
+            }
+        }
+        Token(TokenType::Str, ref text, _) => Ok(Literal::Str(text.clone())),
+        Token(TokenType::Error, ref description, ref span) => {
+            err_span(span.clone(), description.clone())
+        }
+        _ => tree.err("expected a literal here".to_string()),
+    }
+}
+
+/// Lowerer turns an AbstractTree into HIR. A node headed by the
+/// name of a special form is lowered by that form's lowering,
+/// and any other node with more than one item is a call.
+///
+/// Every special form has a schema, and `check` reports every
+/// form that doesn't fit its schema before anything is lowered.
+#[derive(Default)]
+pub struct Lowerer {
+    forms: HashMap<String, Lowering>,
+    schemas: HashMap<String, Schema>,
+}
+
+impl Lowerer {
+    /// Registers a special form lowered by `f`, with
+    /// arguments like `schema`.
+    pub fn handle(self, key: String, schema: &str, f: Lowering) -> Lowerer {
+        let mut lowerer = self.schema(key.clone(), schema);
+        lowerer.forms.insert(key, f);
+        lowerer
+    }
+
+    /// Registers the schema of a special form that isn't lowered
+    /// as an expression, like `define`.
+    ///
+    /// A schema is written by hand, so one that can't be
+    /// parsed is a bug.
+    pub fn schema(mut self, key: String, schema: &str) -> Lowerer {
+        match Schema::parse(schema) {
+            Ok(schema) => self.schemas.insert(key, schema),
+            Err(error) => panic!("the schema of '{}' is invalid: {}", key, error),
+        };
+        self
+    }
+
+    /// Every error in the special forms of `tree`, in order.
+    /// Quoted code is data, so it isn't looked inside.
+    pub fn check(&self, tree: &AbstractTree) -> Vec<Error> {
+        let mut errors = vec![];
+        tree.walk(&mut |tree: &AbstractTree, _: &Context| {
+            let schema = match tree.name() {
+                Ok(name) if tree.is_node() => self.schemas.get(name).map(|schema| (name, schema)),
+                _ => None,
+            };
+            if let Some((name, schema)) = schema {
+                errors.append(&mut schema.validate(name, tree));
+            }
+            if tree.is_quoted() {
+                Walk::Skip
+            } else {
+                Walk::Continue
+            }
+        });
+        errors
+    }
+
+    /// Lowers every item at the top level - carrying on
+    /// past one that can't be lowered, to report them all.
+    pub fn lower_program(&self, tree: &AbstractTree) -> MultiResult<Vec<Item>> {
+        let mut items = vec![];
+        let mut errors = vec![];
+        for line in tree.arguments().map_err(|error| vec![error])? {
+            match self.lower_item(line) {
+                Ok(item) => items.push(item),
+                Err(error) => errors.push(error),
+            }
+        }
+        if errors.is_empty() {
+            Ok(items)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn lower_item(&self, tree: &AbstractTree) -> Result<Item> {
+        match tree.name() {
+            Ok(name) if tree.is_node() && name == "define" => {
+                self.lower_define(tree).map(Item::Define)
+            }
+            _ => tree.err("only a define can be at the top level".to_string()),
+        }
+    }
+
+    /// `define name { ... }`.
+    fn lower_define(&self, tree: &AbstractTree) -> Result<Define> {
+        tree.check_length(3)?;
+        let name = tree.argument(1)?;
+        Ok(Define {
+            name: name.name()?.clone(),
+            span: name.span(),
+            block: self.lower_block(tree.argument(2)?)?,
+        })
+    }
+
+    /// `{ a b | ... }` is read as `(block a b (...))` - the items
+    /// between `block` and the lines of the body are parameters.
+    pub fn lower_block(&self, tree: &AbstractTree) -> Result<Block> {
+        tree.check_min_length(2)?;
+        let items = tree.arguments()?;
+        let lines = match items[items.len() - 1] {
+            Node(ref lines, _) => lines,
+            ref other => {
+                return other.err("a block takes a list of arguments followed by a list of \
+                                  expressions"
+                                     .to_string())
+            }
+        };
+        Ok(Block {
+            parameters: lower_parameters(self, &items[1..items.len() - 1])?,
+            body: lines.iter().map(|line| self.lower(line)).collect::<Result<_>>()?,
+            span: tree.span(),
+        })
+    }
+
+    pub fn lower(&self, tree: &AbstractTree) -> Result<Expr> {
+        match *tree {
+            Node(ref items, ref span) => {
+                let lowering = tree.name().ok().and_then(|name| self.forms.get(name)).copied();
+                if let Some(lowering) = lowering {
+                    return lowering(self, tree);
+                }
+                match items.len() {
+                    0 => err_span(span.clone(), "expected an expression inside ()".to_string()),
+                    1 => self.lower(&items[0]),
+                    _ => self.lower_call(items, span),
+                }
+            }
+            Token(TokenType::Symbol, ref name, ref span) => {
+                Ok(Expr::Var(name.clone(), span.clone()))
+            }
+            Token(..) => Ok(Expr::Literal(literal(tree)?, tree.span())),
+        }
+    }
+
+    /// `f x y` calls the function `f`, and `a.b x y` calls
+    /// the method `b` of `a`.
+    fn lower_call(&self, items: &[AbstractTree], span: &Span) -> Result<Expr> {
+        let (head, arguments) = (&items[0], &items[1..]);
+        if is_member(head) {
+            let (receiver, name, name_span) = self.member(head)?;
+            return Ok(Expr::Send {
+                receiver,
+                name,
+                name_span,
+                arguments: arguments.iter()
+                                    .map(|argument| self.lower(argument))
+                                    .collect::<Result<_>>()?,
+                span: span.clone(),
+            });
+        }
+        match *head {
+            Token(TokenType::Symbol, ref function, ref function_span) => {
+                Ok(Expr::Call {
+                    function: operator(function, function_span, arguments.len())?,
+                    function_span: function_span.clone(),
+                    arguments: arguments.iter()
+                                        .map(|argument| lower_argument(self, argument))
+                                        .collect::<Result<_>>()?,
+                    span: span.clone(),
+                })
+            }
+            Node(_, ref span) => {
+                err_span(span.clone(),
+                         "unimplemented: no support for calling closures yet implemented"
+                             .to_string())
+            }
+            Token(ref token_type, ref data, ref span) => {
+                err_span(span.clone(),
+                         format!("cannot call token {} of type {:?}", data, token_type))
+            }
+        }
+    }
+
+    /// The receiver of `a.b`, and the name of the member.
+    fn member(&self, tree: &AbstractTree) -> Result<(Box<Expr>, String, Span)> {
+        tree.check_length(3)?;
+        let (name, name_span) = match *tree.argument(2)? {
+            Token(TokenType::Symbol, ref name, ref span) => (name.clone(), span.clone()),
+            ref other => return other.err("expected a name after '.'".to_string()),
+        };
+        Ok((Box::new(self.lower(tree.argument(1)?)?), name, name_span))
+    }
+
+    /// `a.b` reads the field `b` of `a`.
+    pub fn lower_member(&self, tree: &AbstractTree) -> Result<Expr> {
+        let (receiver, name, name_span) = self.member(tree)?;
+        Ok(Expr::Member {
+            receiver,
+            name,
+            name_span,
+            span: tree.span(),
+        })
+    }
+
+    /// A list literal `[a b c]`.
+    pub fn lower_list(&self, tree: &AbstractTree) -> Result<Expr> {
+        Ok(Expr::List(self.lower_items(tree)?, tree.span()))
+    }
+
+    /// A string with code in it, like `"total: #{count}"` -
+    /// its text and code, in order.
+    pub fn lower_interpolation(&self, tree: &AbstractTree) -> Result<Expr> {
+        Ok(Expr::Interpolate(self.lower_items(tree)?, tree.span()))
+    }
+
+    /// A block that isn't a define's.
+    pub fn lower_block_expression(&self, tree: &AbstractTree) -> Result<Expr> {
+        self.lower_block(tree).map(Expr::Block)
+    }
+
+    /// Lowers the items of a special form, after its name.
+    fn lower_items(&self, tree: &AbstractTree) -> Result<Vec<Expr>> {
+        tree.arguments()?.iter().skip(1).map(|item| self.lower(item)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use compiler::lowerer;
+    use compiler::hir::{Argument, Define, Expr, Item, Literal};
+    use parser::parse_recovering;
+    use utils::{Error, MultiResult};
+
+    fn lower(source: &str) -> MultiResult<Vec<Item>> {
+        lowerer().lower_program(&parse_recovering(source).tree)
+    }
+
+    fn error(source: &str) -> Error {
+        lower(source).err().unwrap().remove(0)
+    }
+
+    /// The first define in `source`.
+    fn define(source: &str) -> Define {
+        let Item::Define(define) = lower(source).unwrap().remove(0);
+        define
+    }
+
+    #[test]
+    fn test_lower_define() {
+        let source = "define draw { x width: 1\nprint x 2.5\n}";
+        let define = define(source);
+        assert_eq!(define.name, "draw");
+        assert_eq!(&source[define.span.start_offset..define.span.end_offset], "draw");
+        let parameters = &define.block.parameters;
+        assert_eq!((parameters[0].name.as_str(), parameters[1].name.as_str()), ("x", "width"));
+        assert!(parameters[0].default.is_none());
+        assert!(matches!(parameters[1].default, Some(Expr::Literal(Literal::Int(1), _))));
+
+        match define.block.body[0] {
+            Expr::Call { ref function, ref arguments, .. } => {
+                assert_eq!(function, "print");
+                assert!(matches!(arguments[0],
+                                 Argument::Positional(Expr::Var(ref name, _)) if name == "x"));
+                assert!(matches!(arguments[1],
+                                 Argument::Positional(Expr::Literal(Literal::Float(_), _))));
+            }
+            ref other => panic!("expected a call, not {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lower_forms() {
+        let body = define("define start { xs\nxs.push [1 \"a\"]\nxs.length\n(xs)\n}").block.body;
+        match body[0] {
+            Expr::Send { ref name, ref arguments, .. } => {
+                assert_eq!(name, "push");
+                assert!(matches!(arguments[0], Expr::List(ref items, _) if items.len() == 2));
+            }
+            ref other => panic!("expected a send, not {:?}", other),
+        }
+        assert!(matches!(body[1], Expr::Member { ref name, .. } if name == "length"));
+        // a single expression in parentheses is just that expression
+        assert!(matches!(body[2], Expr::Var(ref name, _) if name == "xs"));
+    }
+
+    #[test]
+    fn test_lowering_errors() {
+        assert_eq!(error("print 1").description, "only a define can be at the top level");
+        assert_eq!(error("define f { x\n3 x\n}").description, "cannot call token 3 of type Int");
+        assert_eq!(error("define f { x\n(f) x\n}").description,
+                   "unimplemented: no support for calling closures yet implemented");
+        assert_eq!(error("define f { x\nprint [a: 1]\n}").description,
+                   "'a:' can only be passed to a function made with define");
+
+        // every item is lowered, past the ones with errors
+        assert_eq!(lower("print 1\ndefine f { x\nx\n}\n2").err().unwrap().len(), 2);
+
+        let source = "define f { x\n(. x (g))\n}";
+        let error = error(source);
+        assert_eq!(error.description, "expected a name after '.'");
+        assert_eq!(&source[error.span.start_offset..error.span.end_offset], "(g)");
+    }
+}
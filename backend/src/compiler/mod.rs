@@ -2,106 +2,74 @@
 
 pub mod abstract_tree;
 pub mod backend;
+pub mod hir;
+pub mod keywords;
+pub mod lower;
+pub mod quote;
+pub mod schema;
+pub mod serialize;
+pub mod variables;
+pub mod visit;
 
-use self::abstract_tree::AbstractTree;
+use self::abstract_tree::{AbstractTree, BLOCK_IDENTIFIER, INTERPOLATION_IDENTIFIER,
+                          KEYWORD_IDENTIFIER, LIST_IDENTIFIER, MEMBER_IDENTIFIER};
 use self::backend::LLVMBackend;
-use utils::{Result, IR};
+use self::hir::Item;
+use self::keywords::lower_keyword;
+use self::lower::Lowerer;
+use self::quote::{lower_quasiquote, lower_quote, lower_unquote};
+use self::variables::{lower_assign, lower_let};
+use utils::{MultiResult, IR};
 
-/// check_define ensures the tree passed to it is valid
-/// for a define call
-///
-fn check_define<'a>(at: &'a mut AbstractTree) -> Result<()> {
-    Ok(())
-        .and_then(|_| at.check_length(3))
-        .and_then(|_| at.check_argument_block(2))
+/// Each special form: the shape of its arguments, and its lowering.
+/// A define is lowered as a top-level item, so it only has a schema.
+fn lowerer() -> Lowerer {
+    Lowerer::default()
+        .schema("define".to_string(), "Name, Block(params: Name | Keyword*)")
+        .handle("quote".to_string(), "Any", lower_quote)
+        .handle("quasiquote".to_string(), "Any", lower_quasiquote)
+        .handle("unquote".to_string(), "Any", lower_unquote)
+        .handle("unquote-splicing".to_string(), "Any", lower_unquote)
+        .handle(BLOCK_IDENTIFIER.to_string(), "Any*, Lines", Lowerer::lower_block_expression)
+        .handle(LIST_IDENTIFIER.to_string(), "Any*", Lowerer::lower_list)
+        .handle(INTERPOLATION_IDENTIFIER.to_string(), "Any*", Lowerer::lower_interpolation)
+        .handle(MEMBER_IDENTIFIER.to_string(), "Any, Name", Lowerer::lower_member)
+        .handle("let".to_string(), "Name, Any", lower_let)
+        .handle("=".to_string(), "Name, Any", lower_assign)
+        .handle(KEYWORD_IDENTIFIER.to_string(), "Name, Any", lower_keyword)
 }
 
-
-// Maybe this should be put in the backend - or it's own module.
-fn compile_define(backend: &mut LLVMBackend, tree: &mut AbstractTree) -> Result<IR> {
-    backend.start_stack();
-
-    let mut arguments_to_define = tree.arguments_mut();
-    let mut block = arguments_to_define.pop().unwrap();
-
-    let mut top_level_iterator = arguments_to_define.iter();
-    top_level_iterator.next(); // get rid of the call to 'define'
-
-    let name = top_level_iterator.next().unwrap().name();
-
-    let mut arguments_to_block = block.arguments_mut();
-
-    let mut block_expressions = arguments_to_block.pop().unwrap();
-    let mut arguments_to_block = arguments_to_block.iter_mut();
-
-    // get rid of 'block', the first argument to block.
-    arguments_to_block.next();
-
-    let mut function_definition = format!("define %object @{}(", name);
-    let mut beginning = true;
-
-    let mut argument_ir = vec![];
-    let mut i = 0;
-    for argument in arguments_to_block {
-        if !beginning {
-            function_definition.push(',')
-        }
-
-        function_definition.push_str(&format!("%object %in_arg.{}", i));
-        argument_ir.append(&mut backend.set_var_ir(argument.name(), format!("in_arg.{}", i)));
-        backend.add_assignee(argument.name());
-
-        // comma formatting (facepalm)
-        beginning = false;
-
-        i += 1;
+/// compile takes an abstract tree and compiles it - eventually
+/// down to IR
+///
+/// Every syntax error left in the tree, every special form that
+/// doesn't fit its schema and every misused `define` is reported
+/// together, before anything is compiled. The tree is then lowered
+/// to HIR, which is what the backend compiles.
+pub fn compile(at: AbstractTree) -> MultiResult<IR> {
+    let lowerer = lowerer();
+    let mut errors = at.syntax_errors();
+    errors.append(&mut lowerer.check(&at));
+    errors.append(&mut at.assert_only_top_level("define"));
+    if !errors.is_empty() {
+        return Err(errors);
     }
 
-    function_definition.push_str(") {");
-    let mut ir = vec![function_definition];
-
-    let ir_result = block_expressions.arguments_mut()
-                                     .iter_mut()
-                                     .fold(Ok(vec![]), |acc, expression| {
-                                         acc.and_then(|mut ir| {
-                                             backend.compile_inner(expression)
-                                                    .and_then(|mut new_ir| {
-                                                        ir.append(&mut new_ir);
-                                                        Ok(ir)
-                                                    })
-                                         })
-                                     })
-                                     .and_then(|mut ir| {
-                                         ir.push(format!("ret %object %{}",
-                                                         backend.get_counter("ret")));
-                                         ir.push("}".to_string());
-                                         Ok(ir)
-                                     });
-
-    let stack = backend.end_stack();
-    let mut ir_from_stack = stack.values()
-        .map(|assignee| format!("%{} = alloca %object", assignee.name))
-        .collect::<Vec<_>>();
-    ir_result.and_then(|mut r| {
-        ir.append(&mut ir_from_stack);
-        ir.append(&mut argument_ir);
-        ir.append(&mut r);
-        Ok(ir)
-    })
-}
+    let program = lowerer.lower_program(&at)?;
 
-/// compile takes an abstract tree and compiles it - eventually
-/// down to IR
-pub fn compile<'a>(mut at: AbstractTree) -> Result<IR> {
-    Ok(())
-        .and_then(|_| at.match_symbol("define", check_define))
-        .and_then(|_| at.assert_only_top_level("define"))
-        .and_then(|_| {
-            // compilation stage
-            LLVMBackend::new(at)
-                .handle("define".to_string(), compile_define)
-                .compile()
-        })
+    // compilation stage - a function can be called before its definition
+    let functions = program.iter()
+                           .map(|item| match *item {
+                               Item::Define(ref define) => {
+                                   (define.name.clone(), define.block.parameters.clone())
+                               }
+                           })
+                           .collect::<Vec<_>>();
+    let mut backend = LLVMBackend::new(program);
+    for (name, parameters) in functions {
+        backend = backend.add_function(&name, parameters);
+    }
+    backend.compile().map_err(|error| vec![error])
 }
 
 #[cfg(test)]
@@ -110,14 +78,15 @@ mod tests {
     use compiler::abstract_tree::AbstractTree;
     use compiler::abstract_tree::AbstractTree::*;
     use compiler::abstract_tree::TokenType::*;
-    use utils::Position;
+    use utils::Span;
+    use parser::parse_recovering;
     use super::compile;
 
-    fn construct_define_item<'a>(items: Vec<AbstractTree>) -> AbstractTree {
+    fn construct_define_item(items: Vec<AbstractTree>) -> AbstractTree {
         abstract_tree_item(vec![
-            Token(Symbol, "define".to_string(), Position(0,0)),
-            Token(Int, "2".to_string(), Position(0,0)),
-            Node(items, Position(0, 0)),
+            Token(Symbol, "define".to_string(), Span::default()),
+            Token(Symbol, "f".to_string(), Span::default()),
+            Node(items, Span::default()),
         ])
     }
 
@@ -125,55 +94,290 @@ mod tests {
     fn test_define_constraints() {
         // Test argument constraint
         let at = abstract_tree_item(vec![
-            Token(Symbol, "define".to_string(), Position(0,0)),
-            Token(Int, "2".to_string(), Position(0,0)),
+            Token(Symbol, "define".to_string(), Span::default()),
+            Token(Int, "2".to_string(), Span::default()),
         ]);
-        assert_returns_error(compile(at), "define takes 2 arguments");
+        assert_returns_error(compile(at), "'define' takes 2 arguments");
 
         // Test need for block constraint
         let at = abstract_tree_item(vec![
-            Token(Symbol, "define".to_string(), Position(0,0)),
-            Token(Int, "2".to_string(), Position(0,0)),
-            Token(Int, "2".to_string(), Position(0,0)),
+            Token(Symbol, "define".to_string(), Span::default()),
+            Token(Symbol, "f".to_string(), Span::default()),
+            Token(Int, "2".to_string(), Span::default()),
         ]);
-        assert_returns_error(compile(at), "define expects a block for its 2th argument");
+        assert_returns_error(compile(at), "the 2nd argument of 'define' has to be a block");
+
+        // Test name constraint
+        let at = abstract_tree_item(vec![
+            Token(Symbol, "define".to_string(), Span::default()),
+            Token(Int, "2".to_string(), Span::default()),
+            Node(vec![
+                Token(Symbol, "block".to_string(), Span::default()),
+                Node(vec![], Span::default()),
+            ], Span::default()),
+        ]);
+        assert_returns_error(compile(at), "the 1st argument of 'define' has to be a name");
 
         // Test top level constraint
         let at = construct_define_item(vec![
-                Token(Symbol, "block".to_string(), Position(0,0)),
-                Node(vec![], Position(0,0)),
+                Token(Symbol, "block".to_string(), Span::default()),
                 Node(vec![construct_define_item(vec![
-                    Token(Symbol, "block".to_string(), Position(0,0)),
-                    Node(vec![], Position(0,0)),
-                    Node(vec![], Position(0,0)),
-                ])], Position(0,0)),
+                    Token(Symbol, "block".to_string(), Span::default()),
+                    Node(vec![], Span::default()),
+                ])], Span::default()),
             ]);
         assert_returns_error(compile(at),
                              "define was invoked without being on the top level");
     }
 
+    #[test]
+    fn test_reports_every_error() {
+        let at = Node(vec![
+            Node(vec![Token(Error, "unexpected ')'".to_string(), Span::default())],
+                 Span::default()),
+            Node(vec![
+                Token(Symbol, "define".to_string(), Span::default()),
+                Token(Int, "2".to_string(), Span::default()),
+            ], Span::default()),
+        ], Span::default());
+        let descriptions = compile(at).err().unwrap()
+                                      .into_iter()
+                                      .map(|error| error.description)
+                                      .collect::<Vec<_>>();
+        assert_eq!(descriptions, vec!["unexpected ')'", "'define' takes 2 arguments"]);
+
+        // a syntax error is reported once, and not again by the form it's in
+        let at = parse_recovering("define f { a ) b\na\n}").tree;
+        let descriptions = compile(at).err().unwrap()
+                                      .into_iter()
+                                      .map(|error| error.description)
+                                      .collect::<Vec<_>>();
+        assert_eq!(descriptions,
+                   vec!["encountered unexpected ')' while reading expressions of a block"]);
+    }
+
     #[test]
     fn test_block_constraints() {
         let at = construct_define_item(vec![
-            Token(Symbol, "block".to_string(), Position(0,0)),
+            Token(Symbol, "block".to_string(), Span::default()),
+        ]);
+        assert_returns_error(compile(at), "'block' takes at least 1 argument");
+
+        let at = construct_define_item(vec![
+            Token(Symbol, "block".to_string(), Span::default()),
+            Token(Int, "2".to_string(), Span::default()),
+            Token(Int, "2".to_string(), Span::default()),
         ]);
-        assert_returns_error(compile(at), "block takes at least 1 arguments");
+        assert_returns_error(compile(at), "the 2nd argument of 'block' has to be lines of code");
 
         let at = construct_define_item(vec![
-            Token(Symbol, "block".to_string(), Position(0,0)),
-            Token(Int, "2".to_string(), Position(0,0)),
-            Token(Int, "2".to_string(), Position(0,0)),
+            Token(Symbol, "block".to_string(), Span::default()),
+            Token(Int, "2".to_string(), Span::default()),
+            Node(vec![Token(Int, "2".to_string(), Span::default())], Span::default()),
         ]);
         assert_returns_error(compile(at),
-                             "a block takes a list of arguments followed by a list of expressions");
+                             "the 1st parameter of the block has to be a name or a keyword");
 
         let at = construct_define_item(vec![
-            Token(Symbol, "block".to_string(), Position(0,0)),
-            Token(Int, "2".to_string(), Position(0,0)),
-            Node(vec![Token(Int, "2".to_string(), Position(0,0))], Position(0,0)),
+            Token(Symbol, "block".to_string(), Span::default()),
+            Token(Symbol, "x".to_string(), Span::default()),
+            Node(vec![Token(Int, "2".to_string(), Span::default())], Span::default()),
         ]);
         compile(at).ok().unwrap();
 
     }
 
+    #[test]
+    fn test_compile_numbers() {
+        let at = construct_define_item(vec![
+            Token(Symbol, "block".to_string(), Span::default()),
+            Node(vec![
+                Node(vec![
+                    Token(Symbol, "set".to_string(), Span::default()),
+                    Token(Int, "3".to_string(), Span::default()),
+                    Token(Float, "1.5".to_string(), Span::default()),
+                    Token(Int, "-7".to_string(), Span::default()),
+                ], Span::default()),
+            ], Span::default()),
+        ]);
+        let ir = compile(at).ok().unwrap();
+        assert!(ir.contains(&"%ret.0 = insertvalue %object { i64 0, i64 0 }, i64 3, 1"
+                                 .to_string()));
+        assert!(ir.contains(&"%ret.1 = insertvalue %object { i64 2, i64 0 }, \
+                              i64 4609434218613702656, 1"
+                                 .to_string()));
+        assert!(ir.contains(&"%ret.2 = insertvalue %object { i64 0, i64 0 }, i64 -7, 1"
+                                 .to_string()));
+
+        let at = construct_define_item(vec![
+            Token(Symbol, "block".to_string(), Span::default()),
+            Node(vec![Token(Int, "12abc".to_string(), Span::default())], Span::default()),
+        ]);
+        assert_returns_error(compile(at), "invalid integer literal '12abc'");
+    }
+
+    #[test]
+    fn test_compile_string() {
+        let at = construct_define_item(vec![
+            Token(Symbol, "block".to_string(), Span::default()),
+            Node(vec![
+                Node(vec![
+                    Token(Symbol, "print".to_string(), Span::default()),
+                    Token(Str, "hi \"there\"\n".to_string(), Span::default()),
+                ], Span::default()),
+            ], Span::default()),
+        ]);
+        let ir = compile(at).ok().unwrap();
+        assert!(ir.contains(&"@str.0 = private unnamed_addr constant [12 x i8] \
+                              c\"hi \\22there\\22\\0A\\00\"".to_string()));
+        assert!(ir.contains(&"%ret.0 = call %object @_string_object(i8* getelementptr \
+                              inbounds ([12 x i8], [12 x i8]* @str.0, i64 0, i64 0), i64 11)"
+                                 .to_string()));
+    }
+
+    #[test]
+    fn test_compile_list() {
+        let at = construct_define_item(vec![
+            Token(Symbol, "block".to_string(), Span::default()),
+            Node(vec![
+                Node(vec![
+                    Token(Symbol, "print".to_string(), Span::default()),
+                    Node(vec![
+                        Token(Symbol, "list".to_string(), Span::default()),
+                        Token(Int, "1".to_string(), Span::default()),
+                        Node(vec![
+                            Token(Symbol, "list".to_string(), Span::default()),
+                        ], Span::default()),
+                    ], Span::default()),
+                ], Span::default()),
+            ], Span::default()),
+        ]);
+        let ir = compile(at).ok().unwrap();
+        let expected = ["%ret.1 = call %object @_list_object(%object* null, i64 0)",
+                        "%list.0 = alloca [2 x %object]",
+                        "%item.0 = getelementptr inbounds [2 x %object], \
+                         [2 x %object]* %list.0, i64 0, i64 0",
+                        "store %object %ret.0, %object* %item.0",
+                        "%item.1 = getelementptr inbounds [2 x %object], \
+                         [2 x %object]* %list.0, i64 0, i64 1",
+                        "store %object %ret.1, %object* %item.1",
+                        "%ret.2 = call %object @_list_object(%object* %item.0, i64 2)",
+                        "%ret.3 = call %object @print(%object %ret.2)"];
+        let start = ir.iter().position(|line| line == expected[0]).unwrap();
+        assert_eq!(&ir[start..start + expected.len()], &expected[..]);
+    }
+
+    #[test]
+    fn test_compile_members() {
+        let source = "define start { xs\nxs.push xs.length\n}";
+        let ir = compile(parse_recovering(source).tree).unwrap();
+        let calls = ir.iter()
+                      .filter_map(|line| line.split(" = call %object ").nth(1))
+                      .collect::<Vec<_>>();
+        assert_eq!(calls.len(), 5);
+        assert!(calls[1].starts_with("@_symbol_object"));
+        assert_eq!(calls[2], "@get_field(%object %ret.2, %object %ret.3)");
+        assert!(calls[3].starts_with("@_list_object"));
+        assert_eq!(calls[4], "@send(%object %ret.0, %object %ret.1, %object %ret.5)");
+
+        let at = parse_recovering("define start { xs\n(. xs (f))\n}").tree;
+        assert_returns_error(compile(at), "the 2nd argument of '.' has to be a name");
+    }
+
+    #[test]
+    fn test_compile_interpolation() {
+        let source = "define start { x\nprint \"x: #{x} (#{x.length})\"\n}";
+        let ir = compile(parse_recovering(source).tree).unwrap();
+        let calls = ir.iter()
+                      .filter_map(|line| line.split(" = call %object @").nth(1))
+                      .map(|call| call.split('(').next().unwrap())
+                      .collect::<Vec<_>>();
+        assert_eq!(calls,
+                   vec!["_string_object", "to_string", "concat", "_string_object", "concat",
+                        "_symbol_object", "get_field", "to_string", "concat", "_string_object",
+                        "concat", "print"]);
+
+        let source = "define start { count\nprint \"total: #{count + 1}\"\n}";
+        let ir = compile(parse_recovering(source).tree).unwrap();
+        let calls = ir.iter()
+                      .filter_map(|line| line.split(" = call %object @").nth(1))
+                      .map(|call| call.split('(').next().unwrap())
+                      .collect::<Vec<_>>();
+        assert_eq!(calls, vec!["_string_object", "_add", "to_string", "concat", "print"]);
+
+        // errors in the code point at it, in the string
+        let source = "define start { x\nprint \"#{x} #{y}\"\n}";
+        let error = compile(parse_recovering(source).tree).err().unwrap().remove(0);
+        assert_eq!(error.description, "'y' isn't defined");
+        assert_eq!(&source[error.span.start_offset..error.span.end_offset], "y");
+        assert_eq!(error.span.start, ::utils::Position(1, 14));
+    }
+
+    /// The functions `start` calls, in order, when its body is `line`.
+    fn calls(line: &str) -> Vec<String> {
+        let source = format!("define start {{ x\nlet y x\n{}\n}}", line);
+        let ir = compile(parse_recovering(&source).tree).unwrap();
+        ir.iter()
+          .filter_map(|line| line.split(" = call %object @").nth(1))
+          .map(|call| call.split('(').next().unwrap().to_string())
+          .collect()
+    }
+
+    #[test]
+    fn test_compile_operators() {
+        // one line for each level of precedence, from the loosest
+        assert_eq!(calls("y = x or y"), vec!["_or"]);
+        assert_eq!(calls("x or x and x"), vec!["_and", "_or"]);
+        assert_eq!(calls("x and not x"), vec!["_not", "_and"]);
+        assert_eq!(calls("not x < x"), vec!["_less", "_not"]);
+        assert_eq!(calls("x + 1 != x"), vec!["_add", "_not_equal"]);
+        assert_eq!(calls("x == x |> print"), vec!["print", "_equal"]);
+        assert_eq!(calls("x - 1 |> print"), vec!["_subtract", "print"]);
+        assert_eq!(calls("x + x * x"), vec!["_multiply", "_add"]);
+        assert_eq!(calls("x / - x % x"), vec!["_negate", "_divide", "_remainder"]);
+        assert_eq!(calls("- x ^ x"), vec!["_power", "_negate"]);
+        assert_eq!(calls("x ^ x ^ 2"), vec!["_power", "_power"]);
+        assert_eq!(calls("(x <= x) > (x >= x)"),
+                   vec!["_less_equal", "_greater_equal", "_greater"]);
+
+        // trees the parser wouldn't make
+        let call = |operator: &str, arity: usize| {
+            let mut items = vec![Token(Symbol, operator.to_string(), Span::default())];
+            items.extend(vec![Token(Symbol, "x".to_string(), Span::default()); arity]);
+            construct_define_item(vec![
+                Token(Symbol, "block".to_string(), Span::default()),
+                Token(Symbol, "x".to_string(), Span::default()),
+                Node(vec![Node(items, Span::default())], Span::default()),
+            ])
+        };
+        assert_returns_error(compile(call("+", 1)), "'+' takes 2 arguments");
+        assert_returns_error(compile(call("not", 2)), "'not' takes 1 argument");
+        assert_returns_error(compile(call("-", 3)), "'-' takes 1 or 2 arguments");
+    }
+
+    #[test]
+    fn test_compile_external_calls() {
+        // a function that isn't defined is declared once, to be linked in
+        let source = "define start { x\nputs x\nputs \"hi\"\nsend x 'push [1]\nready? x\n}";
+        let ir = compile(parse_recovering(source).tree).unwrap();
+        let declarations = ir.iter()
+                             .filter(|line| line.contains("@puts") || line.contains("ready"))
+                             .collect::<Vec<_>>();
+        assert_eq!(declarations,
+                   vec!["declare %object @puts(%object) #0",
+                        "declare %object @\"ready?\"(%object) #0",
+                        "%ret.1 = call %object @puts(%object %ret.0)",
+                        "%ret.3 = call %object @puts(%object %ret.2)",
+                        "%ret.10 = call %object @\"ready?\"(%object %ret.9)"]);
+        assert!(ir.iter().any(|line| line.contains("= call %object @send(")));
+
+        let at = parse_recovering("define start { x\nprint x x\n}").tree;
+        assert_returns_error(compile(at), "'print' takes 1 argument");
+
+        let source = "define start { x\nputs x\nputs x x\n}";
+        let error = compile(parse_recovering(source).tree).err().unwrap().remove(0);
+        assert_eq!(error.description, "'puts' is given 2 arguments here, but 1 before");
+        assert_eq!(error.notes[0].description, "it's first called here");
+        assert_eq!(error.notes[0].span.start_offset, 17);
+    }
 }
@@ -0,0 +1,185 @@
+// compiler/quote.rs
+
+use utils::{Result, IR, err_span};
+use compiler::abstract_tree::{AbstractTree, TokenType};
+use compiler::abstract_tree::AbstractTree::*;
+use compiler::backend::LLVMBackend;
+use compiler::hir::{Datum, Expr};
+use compiler::lower::{literal, Lowerer};
+
+/// A quasiquote lowers its own unquotes, so any
+/// unquote left to lower is outside of one.
+pub fn lower_unquote(_: &Lowerer, tree: &AbstractTree) -> Result<Expr> {
+    tree.err(format!("{} can only be used inside a quasiquote", tree.name()?))
+}
+
+/// `'(f x)` is a list of the symbols `f` and `x` - the
+/// quoted code is only data, so nothing in it is lowered.
+pub fn lower_quote(_: &Lowerer, tree: &AbstractTree) -> Result<Expr> {
+    Ok(Expr::Quote(datum(quoted(tree)?)?, tree.span()))
+}
+
+/// `` `(f ,x ,@xs) `` is like a quote, except `x` is lowered and
+/// its value put in the list, and the items of the list `xs` are
+/// spliced in. A nested quasiquote is kept as data, unquotes and
+/// all, until there are as many unquotes as quasiquotes.
+pub fn lower_quasiquote(lowerer: &Lowerer, tree: &AbstractTree) -> Result<Expr> {
+    Ok(Expr::Quote(quasiquote(lowerer, quoted(tree)?, 1)?, tree.span()))
+}
+
+fn quoted(tree: &AbstractTree) -> Result<&AbstractTree> {
+    tree.check_length(2)?;
+    tree.argument(1)
+}
+
+fn datum(tree: &AbstractTree) -> Result<Datum> {
+    match *tree {
+        Node(ref items, _) => items.iter().map(datum).collect::<Result<_>>().map(Datum::List),
+        Token(TokenType::Symbol, ref name, _) => Ok(Datum::Symbol(name.clone())),
+        _ => literal(tree).map(Datum::Literal),
+    }
+}
+
+/// The name of a quasiquote, unquote or unquote-splicing form.
+fn quasiquote_form(tree: &AbstractTree) -> Option<String> {
+    match *tree {
+        Node(ref items, _) if items.len() == 2 => {
+            match items[0] {
+                Token(TokenType::Symbol, ref name, _)
+                    if name == "quasiquote" || name == "unquote" ||
+                       name == "unquote-splicing" => Some(name.clone()),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn quasiquote(lowerer: &Lowerer, tree: &AbstractTree, depth: usize) -> Result<Datum> {
+    match quasiquote_form(tree).as_deref() {
+        Some("unquote") if depth == 1 => {
+            return Ok(Datum::Unquote(Box::new(lowerer.lower(tree.argument(1)?)?)));
+        }
+        Some("unquote-splicing") if depth == 1 => {
+            return tree.err("unquote-splicing can only be used inside a list".to_string());
+        }
+        Some(name) => {
+            let depth = if name == "quasiquote" { depth + 1 } else { depth - 1 };
+            return Ok(Datum::List(vec![Datum::Symbol(name.to_string()),
+                                       quasiquote(lowerer, tree.argument(1)?, depth)?]));
+        }
+        None => {}
+    }
+
+    let items = match *tree {
+        Node(ref items, _) => items,
+        _ => return datum(tree),
+    };
+    items.iter()
+         .map(|item| {
+             if depth == 1 && quasiquote_form(item).as_deref() == Some("unquote-splicing") {
+                 Ok(Datum::Splice(Box::new(lowerer.lower(item.argument(1)?)?)))
+             } else {
+                 quasiquote(lowerer, item, depth)
+             }
+         })
+         .collect::<Result<_>>()
+         .map(Datum::List)
+}
+
+/// Quoted symbols and lists become runtime objects,
+/// and literals compile as they always do.
+pub fn compile_quote(backend: &mut LLVMBackend, datum: &Datum) -> Result<IR> {
+    let items = match *datum {
+        Datum::List(ref items) => items,
+        Datum::Symbol(ref name) => return Ok(backend.symbol_ir(name)),
+        Datum::Literal(ref literal) => return Ok(backend.literal_ir(literal)),
+        Datum::Unquote(ref expr) => return backend.compile_inner(expr),
+        Datum::Splice(ref expr) => {
+            return err_span(expr.span(),
+                            "unquote-splicing can only be used inside a list".to_string())
+        }
+    };
+
+    // runs of items between splices become lists,
+    // and the lists are concatenated in order.
+    let mut ir = vec![];
+    let mut lists = vec![];
+    let mut values = vec![];
+    for item in items {
+        if let Datum::Splice(ref expr) = *item {
+            if !values.is_empty() || lists.is_empty() {
+                ir.append(&mut backend.list_ir(&values));
+                lists.push(backend.get_counter("ret"));
+                values.clear();
+            }
+            ir.append(&mut backend.compile_inner(expr)?);
+            lists.push(backend.get_counter("ret"));
+        } else {
+            ir.append(&mut compile_quote(backend, item)?);
+            values.push(backend.get_counter("ret"));
+        }
+    }
+    if !values.is_empty() || lists.is_empty() {
+        ir.append(&mut backend.list_ir(&values));
+        lists.push(backend.get_counter("ret"));
+    }
+
+    let mut lists = lists.into_iter();
+    let mut list = lists.next().unwrap();
+    for next in lists {
+        ir.append(&mut backend.list_concat_ir(&list, &next));
+        list = backend.get_counter("ret");
+    }
+    Ok(ir)
+}
+
+#[cfg(test)]
+mod tests {
+    use compiler::compile;
+    use parser::parse_recovering;
+    use utils::{MultiResult, IR};
+
+    fn compile_start(body: &str) -> MultiResult<IR> {
+        compile(parse_recovering(&format!("define start {{ x\n{}\n}}", body)).tree)
+    }
+
+    fn errors(body: &str) -> Vec<String> {
+        compile_start(body).err().unwrap().into_iter().map(|error| error.description).collect()
+    }
+
+    #[test]
+    fn test_compile_quote() {
+        let ir = compile_start("print '(f \"s\" (g))").unwrap();
+        let calls = ir.iter()
+                      .filter_map(|line| line.split(" = call %object @").nth(1))
+                      .map(|call| call.split('(').next().unwrap())
+                      .collect::<Vec<_>>();
+        assert_eq!(calls,
+                   vec!["_symbol_object", "_string_object", "_symbol_object",
+                        "_list_object", "_list_object", "print"]);
+        // print is passed the outer list, not the first thing built
+        assert!(ir.contains(&"%ret.5 = call %object @print(%object %ret.4)".to_string()));
+        assert!(ir.iter().any(|line| line.ends_with("c\"f\\00\"")));
+    }
+
+    #[test]
+    fn test_compile_quasiquote() {
+        let ir = compile_start("print `(a ,x ,@'(b) `(c ,d))").unwrap();
+        // x is loaded, but d is still quoted at the inner quasiquote's depth
+        assert_eq!(ir.iter().filter(|line| line.contains("= load %object, %object* %x")).count(),
+                   1);
+        assert!(!ir.iter().any(|line| line.contains("@d(")));
+        assert_eq!(ir.iter().filter(|line| line.contains("= call %object @_list_concat")).count(),
+                   2);
+    }
+
+    #[test]
+    fn test_quote_errors() {
+        assert_eq!(errors("print ,x"), vec!["unquote can only be used inside a quasiquote"]);
+        assert_eq!(errors("(quote a b)"), vec!["'quote' takes 1 argument"]);
+        assert_eq!(errors("print `,@x"), vec!["unquote-splicing can only be used inside a list"]);
+        // quoted code is only data, so it isn't checked
+        assert!(compile_start("print '(define ,x)").is_ok());
+    }
+}
@@ -0,0 +1,386 @@
+// compiler/schema.rs
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+use utils::{Error, Span};
+use compiler::abstract_tree::{AbstractTree, TokenType, BLOCK_IDENTIFIER, KEYWORD_IDENTIFIER};
+use compiler::abstract_tree::AbstractTree::*;
+
+/// What an argument of a special form can be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Any,
+    Symbol,
+    /// A symbol that can name a function or a local.
+    Name,
+    /// An int, float or string.
+    Literal,
+    /// `width: 1`.
+    Keyword,
+    /// The lines of a block's body.
+    Lines,
+    /// A block - and, if it's given, what each of its parameters can be.
+    Block(Option<Box<Pattern>>),
+}
+
+/// An argument of a special form - it can be any of `kinds`.
+/// A repeated pattern matches any number of arguments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    pub kinds: Vec<Kind>,
+    pub repeated: bool,
+}
+
+/// A Schema is the shape of a special form's arguments, written
+/// as a list of patterns like `Name, Block(params: Name | Keyword*)`:
+///
+/// ```text
+/// schema  = [ pattern { "," pattern } ]
+/// pattern = kind { "|" kind } [ "*" ]
+/// kind    = "Any" | "Symbol" | "Name" | "Literal" | "Keyword" | "Lines"
+///         | "Block" [ "(" "params" ":" pattern ")" ]
+/// ```
+///
+/// `|` binds tighter than `*`, so `Name | Keyword*` is any number
+/// of names and keywords. Only one pattern can be repeated, and a
+/// block's params have to be.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schema {
+    pub patterns: Vec<Pattern>,
+}
+
+impl Schema {
+    pub fn parse(source: &str) -> ::std::result::Result<Schema, String> {
+        let mut reader = Reader { chars: source.chars().peekable() };
+        let mut patterns = vec![];
+        if reader.peek().is_some() {
+            patterns.push(reader.pattern()?);
+            while reader.eat(',') {
+                patterns.push(reader.pattern()?);
+            }
+        }
+        if let Some(c) = reader.peek() {
+            return Err(format!("unexpected '{}'", c));
+        }
+        if patterns.iter().filter(|pattern| pattern.repeated).count() > 1 {
+            return Err("only one pattern can be repeated".to_string());
+        }
+        Ok(Schema { patterns })
+    }
+
+    /// The errors in the arguments of `form`, a node named `name`:
+    /// its arity first, and if that's right, each argument that
+    /// isn't what it should be.
+    pub fn validate(&self, name: &str, form: &AbstractTree) -> Vec<Error> {
+        let arguments = match *form {
+            Node(ref items, _) if !items.is_empty() => &items[1..],
+            _ => return vec![],
+        };
+        let fixed = self.patterns.iter().filter(|pattern| !pattern.repeated).count();
+        let repeated = self.patterns.iter().any(|pattern| pattern.repeated);
+        if arguments.len() < fixed || (!repeated && arguments.len() > fixed) {
+            let (quantity, span) = match (repeated, arguments.get(fixed)) {
+                (true, _) => (format!("at least {}", count(fixed, "argument")), form.span()),
+                (false, Some(extra)) => (count(fixed, "argument"), extra.span()),
+                (false, None) => (count(fixed, "argument"), form.span()),
+            };
+            return vec![error(span, format!("'{}' takes {}", name, quantity))];
+        }
+
+        // the repeated pattern takes the arguments the others don't
+        let mut patterns = vec![];
+        for pattern in &self.patterns {
+            let times = if pattern.repeated { arguments.len() - fixed } else { 1 };
+            patterns.extend(vec![pattern; times]);
+        }
+        patterns.iter()
+                .zip(arguments)
+                .enumerate()
+                .filter_map(|(i, (pattern, argument))| {
+                    let what = format!("the {} argument of '{}'", ordinal(i + 1), name);
+                    pattern.check(argument, &what).err()
+                })
+                .collect()
+    }
+}
+
+impl Pattern {
+    /// Ensures `tree` is one of the pattern's kinds -
+    /// `what` is how to refer to it in an error.
+    fn check(&self, tree: &AbstractTree, what: &str) -> ::std::result::Result<(), Error> {
+        for kind in &self.kinds {
+            if let Some(result) = kind.check(tree) {
+                return result;
+            }
+        }
+        let kinds = self.kinds.iter().map(Kind::describe).collect::<Vec<_>>();
+        Err(error(tree.span(), format!("{} has to be {}", what, list(&kinds))))
+    }
+}
+
+impl Kind {
+    /// None if `tree` isn't this kind of thing at all - otherwise
+    /// whether what's inside it is right, like a name's characters
+    /// or a block's parameters. A syntax error fits any kind, as
+    /// it's already reported.
+    fn check(&self, tree: &AbstractTree) -> Option<::std::result::Result<(), Error>> {
+        match (self, tree) {
+            (&Kind::Any, _) |
+            (_, &Token(TokenType::Error, _, _)) => Some(Ok(())),
+            (&Kind::Symbol, &Token(TokenType::Symbol, _, _)) => Some(Ok(())),
+            (&Kind::Name, &Token(TokenType::Symbol, ref name, ref span)) => {
+                if is_valid_name(name) {
+                    Some(Ok(()))
+                } else {
+                    Some(Err(error(span.clone(), format!("'{}' isn't a valid name", name))))
+                }
+            }
+            (&Kind::Literal, &Token(TokenType::Int, _, _)) |
+            (&Kind::Literal, &Token(TokenType::Float, _, _)) |
+            (&Kind::Literal, &Token(TokenType::Str, _, _)) => Some(Ok(())),
+            (&Kind::Keyword, _) if tree.is_form(KEYWORD_IDENTIFIER) => Some(Ok(())),
+            (&Kind::Lines, &Node(..)) => Some(Ok(())),
+            (&Kind::Block(None), _) if tree.is_form(BLOCK_IDENTIFIER) => Some(Ok(())),
+            (&Kind::Block(Some(ref pattern)), _) if tree.is_form(BLOCK_IDENTIFIER) => {
+                Some(check_parameters(pattern, tree))
+            }
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match *self {
+            Kind::Any => "anything",
+            Kind::Symbol => "a symbol",
+            Kind::Name => "a name",
+            Kind::Literal => "a literal",
+            Kind::Keyword => "a keyword",
+            Kind::Lines => "lines of code",
+            Kind::Block(_) => "a block",
+        }
+    }
+}
+
+/// Ensures each parameter of a block fits `pattern` - a block
+/// without a body is the block's own schema's to report.
+fn check_parameters(pattern: &Pattern, block: &AbstractTree) -> ::std::result::Result<(), Error> {
+    let items = match *block {
+        Node(ref items, _) if items.len() >= 2 && items[items.len() - 1].is_node() => items,
+        _ => return Ok(()),
+    };
+    items[1..items.len() - 1].iter().enumerate().try_for_each(|(i, parameter)| {
+        pattern.check(parameter, &format!("the {} parameter of the block", ordinal(i + 1)))
+    })
+}
+
+/// Reads a schema, skipping whitespace.
+struct Reader<'s> {
+    chars: Peekable<Chars<'s>>,
+}
+
+impl<'s> Reader<'s> {
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+        self.chars.peek().copied()
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.chars.next();
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, expected: char) -> ::std::result::Result<(), String> {
+        if self.eat(expected) {
+            Ok(())
+        } else {
+            Err(format!("expected '{}'", expected))
+        }
+    }
+
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        self.peek();
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_alphanumeric() {
+                break;
+            }
+            word.push(c);
+            self.chars.next();
+        }
+        word
+    }
+
+    fn pattern(&mut self) -> ::std::result::Result<Pattern, String> {
+        let mut kinds = vec![self.kind()?];
+        while self.eat('|') {
+            kinds.push(self.kind()?);
+        }
+        Ok(Pattern {
+            kinds,
+            repeated: self.eat('*'),
+        })
+    }
+
+    fn kind(&mut self) -> ::std::result::Result<Kind, String> {
+        let kind = match self.word().as_str() {
+            "Any" => Kind::Any,
+            "Symbol" => Kind::Symbol,
+            "Name" => Kind::Name,
+            "Literal" => Kind::Literal,
+            "Keyword" => Kind::Keyword,
+            "Lines" => Kind::Lines,
+            "Block" if self.eat('(') => {
+                if self.word() != "params" {
+                    return Err("expected 'params' in 'Block(..)'".to_string());
+                }
+                self.expect(':')?;
+                let parameters = self.pattern()?;
+                if !parameters.repeated {
+                    return Err("a block's params have to be repeated, like 'Name*'".to_string());
+                }
+                self.expect(')')?;
+                Kind::Block(Some(Box::new(parameters)))
+            }
+            "Block" => Kind::Block(None),
+            "" => return Err("expected a kind".to_string()),
+            word => return Err(format!("'{}' isn't a kind", word)),
+        };
+        Ok(kind)
+    }
+}
+
+/// Whether `name` can name a function or a local: it starts
+/// with a letter or `_`, and has only letters, digits, `_` and `-`.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_') &&
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// `count(1, "argument")` is "1 argument", and
+/// `count(2, "argument")` is "2 arguments".
+pub fn count(n: usize, noun: &str) -> String {
+    if n == 1 {
+        format!("{} {}", n, noun)
+    } else {
+        format!("{} {}s", n, noun)
+    }
+}
+
+/// 1st, 2nd, 3rd, 4th ... 11th, 12th, 13th ... 21st.
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+/// "a", "a or b", "a, b or c".
+fn list(items: &[&str]) -> String {
+    match items.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+        Some((last, _)) => last.to_string(),
+        None => String::new(),
+    }
+}
+
+fn error(span: Span, description: String) -> Error {
+    Error {
+        description,
+        span,
+        notes: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Kind, Pattern, Schema};
+    use compiler::abstract_tree::AbstractTree;
+    use parser::parse;
+
+    fn pattern(kinds: Vec<Kind>, repeated: bool) -> Pattern {
+        Pattern { kinds, repeated }
+    }
+
+    /// The first line of `source`.
+    fn form(source: &str) -> AbstractTree {
+        parse(source).unwrap().unwrap().arguments().unwrap()[0].clone()
+    }
+
+    /// The errors in the first line of `source`, with the source they point at.
+    fn errors(schema: &str, source: &str) -> Vec<(String, String)> {
+        let tree = form(source);
+        let name = tree.name().unwrap().clone();
+        Schema::parse(schema)
+            .unwrap()
+            .validate(&name, &tree)
+            .into_iter()
+            .map(|error| {
+                (error.description,
+                 source[error.span.start_offset..error.span.end_offset].to_string())
+            })
+            .collect()
+    }
+
+    fn error(description: &str, at: &str) -> (String, String) {
+        (description.to_string(), at.to_string())
+    }
+
+    #[test]
+    fn test_parse_schema() {
+        let schema = Schema::parse("Name, Block(params: Name | Keyword*)").unwrap();
+        assert_eq!(schema.patterns,
+                   vec![pattern(vec![Kind::Name], false),
+                        pattern(vec![Kind::Block(Some(Box::new(pattern(vec![Kind::Name,
+                                                                              Kind::Keyword],
+                                                                         true))))],
+                                false)]);
+        assert_eq!(Schema::parse("").unwrap().patterns, vec![]);
+        assert_eq!(Schema::parse("Any*, Lines").unwrap().patterns.len(), 2);
+
+        assert_eq!(Schema::parse("Any, Number").err().unwrap(), "'Number' isn't a kind");
+        assert_eq!(Schema::parse("Any*, Name*").err().unwrap(),
+                   "only one pattern can be repeated");
+        assert_eq!(Schema::parse("Block(params: Name)").err().unwrap(),
+                   "a block's params have to be repeated, like 'Name*'");
+        assert_eq!(Schema::parse("Name Name").err().unwrap(), "unexpected 'N'");
+    }
+
+    #[test]
+    fn test_arity() {
+        assert_eq!(errors("Name, Block", "define f"),
+                   vec![error("'define' takes 2 arguments", "define f")]);
+        assert_eq!(errors("Any", "quote a b"), vec![error("'quote' takes 1 argument", "b")]);
+        assert_eq!(errors("Any, Lines*", "f"), vec![error("'f' takes at least 1 argument", "f")]);
+        assert!(errors("Any, Lines*", "f a (b) (c)").is_empty());
+    }
+
+    #[test]
+    fn test_kinds() {
+        assert_eq!(errors("Name, Any", "let 2 x"),
+                   vec![error("the 1st argument of 'let' has to be a name", "2")]);
+        assert_eq!(errors("Name, Any", "let x? 1"), vec![error("'x?' isn't a valid name", "x?")]);
+        assert_eq!(errors("Symbol | Literal*", "f a 1 \"s\" (b) [c]"),
+                   vec![error("the 4th argument of 'f' has to be a symbol or a literal", "(b)"),
+                        error("the 5th argument of 'f' has to be a symbol or a literal", "[c]")]);
+
+        let define = "Name, Block(params: Name | Keyword*)";
+        assert!(errors(define, "define f { a b: 1\na\n}").is_empty());
+        assert_eq!(errors(define, "define 2 { a\na\n}"),
+                   vec![error("the 1st argument of 'define' has to be a name", "2")]);
+        assert_eq!(errors(define, "define f 2"),
+                   vec![error("the 2nd argument of 'define' has to be a block", "2")]);
+        assert_eq!(errors(define, "define f { a b!\na\n}"),
+                   vec![error("'b!' isn't a valid name", "b!")]);
+    }
+}
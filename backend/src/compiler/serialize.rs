@@ -0,0 +1,855 @@
+// compiler/serialize.rs
+
+use std::str::FromStr;
+
+use lexer::advance_position;
+use parser::MAX_HEIGHT;
+use utils::{Position, Result, Span, err_span};
+use compiler::abstract_tree::{AbstractTree, TokenType};
+use compiler::abstract_tree::AbstractTree::*;
+
+/// An AbstractTree can be written out, and read back, in two forms:
+///
+/// The S-expression form is compact, for reading trees in tests
+/// and while debugging. A node is `(a b c)`, a string is `"text"`,
+/// and a symbol, int or float is written bare. A token that would
+/// be read back as something else is tagged with its type, like
+/// `#symbol"12"`, as is an error - `#error"unexpected ')'"`.
+/// A span is the byte offsets after a tree, like `x@4-5`.
+///
+/// The JSON form is for other tools, like editor plugins. A node
+/// is `{"type":"node","items":[..]}`, a token is
+/// `{"type":"symbol","text":"x"}`, and a span is
+/// `"span":{"start":{"line":0,"column":4,"offset":4},"end":{..}}`.
+impl AbstractTree {
+    /// The tree as an S-expression - with the span
+    /// of each tree, if `positions`.
+    pub fn to_sexp(&self, positions: bool) -> String {
+        let mut sexp = String::new();
+        write_sexp(self, positions, &mut sexp);
+        sexp
+    }
+
+    /// Reads a tree written by `to_sexp`. Spans are only offsets,
+    /// so the lines and columns come from `source` - without it,
+    /// they're left at the start.
+    pub fn from_sexp(sexp: &str, source: Option<&str>) -> Result<AbstractTree> {
+        let mut reader = Reader::new(sexp);
+        let tree = read_sexp(&mut reader, source)?;
+        reader.end()?;
+        Ok(tree)
+    }
+
+    /// The tree as JSON - with the span of
+    /// each tree, if `positions`.
+    pub fn to_json(&self, positions: bool) -> String {
+        let mut json = String::new();
+        write_json(self, positions, &mut json);
+        json
+    }
+
+    /// Reads a tree written by `to_json`. A tree
+    /// without a span has the default one.
+    pub fn from_json(json: &str) -> Result<AbstractTree> {
+        let mut reader = Reader::new(json);
+        let value = reader.json()?;
+        reader.end()?;
+        tree_from_json(&value)
+    }
+}
+
+fn type_name(token_type: &TokenType) -> &'static str {
+    match *token_type {
+        TokenType::Symbol => "symbol",
+        TokenType::Int => "int",
+        TokenType::Str => "str",
+        TokenType::Float => "float",
+        TokenType::Error => "error",
+    }
+}
+
+fn token_type(name: &str) -> Option<TokenType> {
+    match name {
+        "symbol" => Some(TokenType::Symbol),
+        "int" => Some(TokenType::Int),
+        "str" => Some(TokenType::Str),
+        "float" => Some(TokenType::Float),
+        "error" => Some(TokenType::Error),
+        _ => None,
+    }
+}
+
+// S-expressions
+
+/// Whether `c` ends a bare word.
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || c == '(' || c == ')' || c == '"' || c == '@'
+}
+
+/// What a bare word is read as: digits are an int, digits with
+/// a point or exponent are a float, and anything else is a symbol.
+fn bare_type(word: &str) -> TokenType {
+    let digits = word.strip_prefix('-').unwrap_or(word);
+    let numeric = word.chars().all(|c| c.is_ascii_digit() || "+-.eE".contains(c)) &&
+                  word.chars().any(|c| c.is_ascii_digit());
+    if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        TokenType::Int
+    } else if numeric && word.parse::<f64>().is_ok() {
+        TokenType::Float
+    } else {
+        TokenType::Symbol
+    }
+}
+
+/// Where a walk through a tree is.
+enum Visit<'a> {
+    /// The start of a tree - a token is all start.
+    Start(&'a AbstractTree),
+    /// Between two items of a node.
+    Between,
+    /// The end of a tree, after its items.
+    End(&'a AbstractTree),
+}
+
+/// Walks through a tree in the order it's written. Like the readers,
+/// it keeps the nodes it's in here rather than on the stack, so a
+/// tree of any height can be written.
+fn walk<'a, F: FnMut(Visit<'a>)>(tree: &'a AbstractTree, mut visit: F) {
+    let mut open = vec![];
+    let mut next = Some(tree);
+    loop {
+        if let Some(tree) = next.take() {
+            visit(Visit::Start(tree));
+            match *tree {
+                Node(ref items, _) => open.push((tree, items.iter().enumerate())),
+                Token(..) => visit(Visit::End(tree)),
+            }
+        }
+        let (node, items) = match open.last_mut() {
+            Some(&mut (node, ref mut items)) => (node, items),
+            None => return,
+        };
+        match items.next() {
+            Some((i, item)) => {
+                if i > 0 {
+                    visit(Visit::Between);
+                }
+                next = Some(item);
+            }
+            None => {
+                open.pop();
+                visit(Visit::End(node));
+            }
+        }
+    }
+}
+
+fn write_sexp(tree: &AbstractTree, positions: bool, sexp: &mut String) {
+    walk(tree, |visit| match visit {
+        Visit::Start(&Node(..)) => sexp.push('('),
+        Visit::Start(&Token(TokenType::Str, ref text, _)) => write_string(text, sexp),
+        Visit::Start(Token(token_type, text, _)) => {
+            let bare = !text.is_empty() && !text.starts_with('#') &&
+                       !text.chars().any(is_delimiter) &&
+                       bare_type(text) == *token_type;
+            if bare {
+                sexp.push_str(text);
+            } else {
+                sexp.push('#');
+                sexp.push_str(type_name(token_type));
+                write_string(text, sexp);
+            }
+        }
+        Visit::Between => sexp.push(' '),
+        Visit::End(tree) => {
+            if tree.is_node() {
+                sexp.push(')');
+            }
+            if positions {
+                let span = tree.span();
+                sexp.push_str(&format!("@{}-{}", span.start_offset, span.end_offset));
+            }
+        }
+    });
+}
+
+fn read_sexp(reader: &mut Reader, source: Option<&str>) -> Result<AbstractTree> {
+    // the items of each node being read, innermost last - kept
+    // here rather than on the stack, so deep trees can't overflow it
+    let mut open: Vec<Vec<AbstractTree>> = vec![];
+    loop {
+        reader.skip_whitespace();
+        let tree = match reader.peek() {
+            Some('(') => {
+                if open.len() == MAX_SEXP_DEPTH {
+                    return reader.too_deep(MAX_SEXP_DEPTH);
+                }
+                reader.next();
+                open.push(vec![]);
+                continue;
+            }
+            Some(')') if !open.is_empty() => {
+                reader.next();
+                Node(open.pop().unwrap(), Span::default())
+            }
+            None if !open.is_empty() => return reader.err("expected ')'"),
+            _ => read_token(reader)?,
+        };
+        let tree = read_span(reader, source, tree)?;
+        match open.last_mut() {
+            Some(items) => items.push(tree),
+            None => return Ok(tree),
+        }
+    }
+}
+
+fn read_token(reader: &mut Reader) -> Result<AbstractTree> {
+    Ok(match reader.peek() {
+        Some('"') => Token(TokenType::Str, reader.string()?, Span::default()),
+        Some('#') => {
+            reader.next();
+            let tag = reader.word();
+            let token_type = match token_type(&tag) {
+                Some(token_type) => token_type,
+                None => return reader.err(&format!("'{}' isn't a type of token", tag)),
+            };
+            Token(token_type, reader.string()?, Span::default())
+        }
+        Some(')') => return reader.err("unexpected ')'"),
+        None => return reader.err("expected a tree"),
+        _ => {
+            let word = reader.word();
+            Token(bare_type(&word), word, Span::default())
+        }
+    })
+}
+
+/// Gives `tree` the span right after it, if there is one.
+fn read_span(reader: &mut Reader,
+             source: Option<&str>,
+             tree: AbstractTree)
+             -> Result<AbstractTree> {
+    if reader.peek() != Some('@') {
+        return Ok(tree);
+    }
+    reader.next();
+    let start = reader.integer()?;
+    reader.expect('-')?;
+    let end = reader.integer()?;
+    let span = Span {
+        start: position(reader, source, start)?,
+        end: position(reader, source, end)?,
+        start_offset: start,
+        end_offset: end,
+    };
+    Ok(match tree {
+        Node(items, _) => Node(items, span),
+        Token(token_type, text, _) => Token(token_type, text, span),
+    })
+}
+
+/// The line and column at `offset` in `source`.
+fn position(reader: &Reader, source: Option<&str>, offset: usize) -> Result<Position> {
+    let mut position = Position(0, 0);
+    if let Some(source) = source {
+        match source.get(..offset) {
+            Some(before) => before.chars().for_each(|c| advance_position(&mut position, c)),
+            None => return reader.err(&format!("{} isn't an offset in the source", offset)),
+        }
+    }
+    Ok(position)
+}
+
+/// Writes `text` in quotes, escaping what
+/// has to be - the same for both forms.
+fn write_string(text: &str, out: &mut String) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// JSON
+
+fn write_json(tree: &AbstractTree, positions: bool, json: &mut String) {
+    walk(tree, |visit| match visit {
+        Visit::Start(&Node(..)) => json.push_str("{\"type\":\"node\",\"items\":["),
+        Visit::Start(Token(token_type, text, _)) => {
+            json.push_str(&format!("{{\"type\":\"{}\",\"text\":", type_name(token_type)));
+            write_string(text, json);
+        }
+        Visit::Between => json.push(','),
+        Visit::End(tree) => {
+            if tree.is_node() {
+                json.push(']');
+            }
+            if positions {
+                let span = tree.span();
+                let point = |position: &Position, offset| {
+                    format!("{{\"line\":{},\"column\":{},\"offset\":{}}}",
+                            position.0,
+                            position.1,
+                            offset)
+                };
+                json.push_str(&format!(",\"span\":{{\"start\":{},\"end\":{}}}",
+                                       point(&span.start, span.start_offset),
+                                       point(&span.end, span.end_offset)));
+            }
+            json.push('}');
+        }
+    });
+}
+
+/// A JSON value, and where it starts in the JSON.
+struct Json {
+    value: Value,
+    span: Span,
+}
+
+enum Value {
+    /// `null`, `true` or `false` - a tree
+    /// doesn't have any, but they can be skipped.
+    Constant,
+    /// The number as it's written.
+    Number(String),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn err<T>(&self, description: String) -> Result<T> {
+        err_span(self.span.clone(), description)
+    }
+
+    fn string(&self) -> Result<&String> {
+        match self.value {
+            Value::Str(ref string) => Ok(string),
+            _ => self.err("expected a string".to_string()),
+        }
+    }
+
+    fn array(&self) -> Result<&Vec<Json>> {
+        match self.value {
+            Value::Array(ref items) => Ok(items),
+            _ => self.err("expected an array".to_string()),
+        }
+    }
+
+    /// A number that has to fit in a `T`, like a line or an offset.
+    fn number<T: FromStr>(&self) -> Result<T> {
+        match self.value {
+            Value::Number(ref number) => {
+                match number.parse() {
+                    Ok(number) => Ok(number),
+                    Err(_) => self.err(format!("{} isn't a line, column or offset", number)),
+                }
+            }
+            _ => self.err("expected a number".to_string()),
+        }
+    }
+
+    /// The field `name` of an object, if it has one.
+    fn field(&self, name: &str) -> Result<Option<&Json>> {
+        match self.value {
+            Value::Object(ref fields) => {
+                Ok(fields.iter().find(|field| field.0 == name).map(|field| &field.1))
+            }
+            _ => self.err("expected an object".to_string()),
+        }
+    }
+
+    fn required(&self, name: &str) -> Result<&Json> {
+        match self.field(name)? {
+            Some(field) => Ok(field),
+            None => self.err(format!("expected a \"{}\" field", name)),
+        }
+    }
+}
+
+fn tree_from_json(json: &Json) -> Result<AbstractTree> {
+    let type_field = json.required("type")?;
+    if type_field.string()? != "node" {
+        return token_from_json(json, type_field);
+    }
+    // a loop rather than an iterator, so each level
+    // of nesting takes as little stack as it can
+    let mut items = vec![];
+    for item in json.required("items")?.array()? {
+        items.push(tree_from_json(item)?);
+    }
+    Ok(Node(items, optional_span(json)?))
+}
+
+fn token_from_json(json: &Json, type_field: &Json) -> Result<AbstractTree> {
+    let name = type_field.string()?;
+    match token_type(name) {
+        Some(token_type) => {
+            Ok(Token(token_type,
+                     json.required("text")?.string()?.clone(),
+                     optional_span(json)?))
+        }
+        None => type_field.err(format!("'{}' isn't a type of tree", name)),
+    }
+}
+
+fn optional_span(json: &Json) -> Result<Span> {
+    match json.field("span")? {
+        Some(span) => span_from_json(span),
+        None => Ok(Span::default()),
+    }
+}
+
+fn span_from_json(json: &Json) -> Result<Span> {
+    let point = |name| -> Result<(Position, usize)> {
+        let point = json.required(name)?;
+        Ok((Position(point.required("line")?.number()?, point.required("column")?.number()?),
+            point.required("offset")?.number()?))
+    };
+    let (start, start_offset) = point("start")?;
+    let (end, end_offset) = point("end")?;
+    Ok(Span {
+        start,
+        end,
+        start_offset,
+        end_offset,
+    })
+}
+
+/// How deeply an S-expression can be nested - reading doesn't
+/// recurse, but what's read is walked by functions that do. It
+/// has a paren for each level of nodes, so any tree the parser
+/// makes, at most MAX_HEIGHT tall, can be read back.
+const MAX_SEXP_DEPTH: usize = MAX_HEIGHT;
+
+/// How deeply JSON can be nested - twice as deep, as a node is an
+/// object with an array of items, and one more for a token.
+const MAX_JSON_DEPTH: usize = 2 * MAX_HEIGHT + 1;
+
+/// A JSON object or array that's being read, and where it starts.
+enum Open {
+    /// The fields so far, and the name of the one being read.
+    Object(Span, Vec<(String, Json)>, String),
+    Array(Span, Vec<Json>),
+}
+
+/// Reads either form, keeping track of where it is for errors.
+struct Reader<'a> {
+    text: &'a str,
+    offset: usize,
+    position: Position,
+}
+
+impl<'a> Reader<'a> {
+    fn new(text: &'a str) -> Reader<'a> {
+        Reader {
+            text,
+            offset: 0,
+            position: Position(0, 0),
+        }
+    }
+
+    fn too_deep<T>(&self, depth: usize) -> Result<T> {
+        self.err(&format!("this can only be nested {} deep", depth))
+    }
+
+    fn here(&self) -> Span {
+        Span::point(self.position.clone(), self.offset)
+    }
+
+    fn err<T>(&self, description: &str) -> Result<T> {
+        err_span(self.here(), description.to_string())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.offset..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+        advance_position(&mut self.position, c);
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.next();
+        }
+    }
+
+    /// Skips whitespace, then `c` if it's next.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.next();
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            self.err(&format!("expected '{}'", c))
+        }
+    }
+
+    /// Ensures nothing but whitespace is left.
+    fn end(&mut self) -> Result<()> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) => self.err(&format!("expected the end, not '{}'", c)),
+            None => Ok(()),
+        }
+    }
+
+    /// Everything up to the next delimiter.
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(c) = self.peek().filter(|&c| !is_delimiter(c)) {
+            word.push(c);
+            self.next();
+        }
+        word
+    }
+
+    fn integer(&mut self) -> Result<usize> {
+        let mut digits = String::new();
+        while let Some(c) = self.peek().filter(char::is_ascii_digit) {
+            digits.push(c);
+            self.next();
+        }
+        match digits.parse() {
+            Ok(integer) => Ok(integer),
+            Err(_) => self.err("expected an offset"),
+        }
+    }
+
+    /// A string in quotes, with its escapes resolved.
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => {
+                    let c = match self.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => self.unicode_escape()?,
+                        Some(c @ '"') | Some(c @ '\\') | Some(c @ '/') => c,
+                        _ => return self.err("invalid escape in a string"),
+                    };
+                    string.push(c);
+                }
+                Some(c) => string.push(c),
+                None => return self.err("expected the end of a string"),
+            }
+        }
+    }
+
+    /// The character of a `\uXXXX` escape, after its `u` - a character
+    /// past `\uffff` is two escapes, of the halves of its UTF-16 surrogate
+    /// pair, like `\ud83c\udf30`.
+    fn unicode_escape(&mut self) -> Result<char> {
+        let mut hex = (0..4).filter_map(|_| self.next()).collect::<String>();
+        let mut code = u32::from_str_radix(&hex, 16).ok();
+        if let Some(high @ 0xd800..=0xdbff) = code {
+            if self.text[self.offset..].starts_with("\\u") {
+                self.next();
+                self.next();
+                let low = (0..4).filter_map(|_| self.next()).collect::<String>();
+                code = match u32::from_str_radix(&low, 16) {
+                    Ok(low @ 0xdc00..=0xdfff) => {
+                        Some(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))
+                    }
+                    _ => None,
+                };
+                hex = format!("{}\\u{}", hex, low);
+            }
+        }
+        match code.and_then(char::from_u32) {
+            Some(c) => Ok(c),
+            None => self.err(&format!("'\\u{}' isn't a character", hex)),
+        }
+    }
+
+    fn json(&mut self) -> Result<Json> {
+        // the objects and arrays being read, innermost last - kept
+        // here rather than on the stack, so deep JSON can't overflow it
+        let mut open: Vec<Open> = vec![];
+        loop {
+            self.skip_whitespace();
+            let span = self.here();
+            let mut json = match self.peek() {
+                Some(c @ '{') | Some(c @ '[') => {
+                    if open.len() == MAX_JSON_DEPTH {
+                        return self.too_deep(MAX_JSON_DEPTH);
+                    }
+                    self.next();
+                    if c == '{' && !self.eat('}') {
+                        let name = self.field_name()?;
+                        open.push(Open::Object(span, vec![], name));
+                        continue;
+                    }
+                    if c == '[' && !self.eat(']') {
+                        open.push(Open::Array(span, vec![]));
+                        continue;
+                    }
+                    let value = if c == '{' {
+                        Value::Object(vec![])
+                    } else {
+                        Value::Array(vec![])
+                    };
+                    Json { value, span }
+                }
+                _ => {
+                    Json {
+                        value: self.json_scalar(&span)?,
+                        span,
+                    }
+                }
+            };
+            // add the value to what it's in - closing
+            // each object or array it's the last of
+            loop {
+                match open.last_mut() {
+                    None => return Ok(json),
+                    Some(&mut Open::Object(_, ref mut fields, ref mut name)) => {
+                        fields.push((::std::mem::take(name), json));
+                        if !self.eat('}') {
+                            self.expect(',')?;
+                            *name = self.field_name()?;
+                            break;
+                        }
+                    }
+                    Some(&mut Open::Array(_, ref mut items)) => {
+                        items.push(json);
+                        if !self.eat(']') {
+                            self.expect(',')?;
+                            break;
+                        }
+                    }
+                }
+                json = match open.pop().unwrap() {
+                    Open::Object(span, fields, _) => {
+                        Json {
+                            value: Value::Object(fields),
+                            span,
+                        }
+                    }
+                    Open::Array(span, items) => {
+                        Json {
+                            value: Value::Array(items),
+                            span,
+                        }
+                    }
+                };
+            }
+        }
+    }
+
+    /// The name of an object's field, and its `:`.
+    fn field_name(&mut self) -> Result<String> {
+        self.skip_whitespace();
+        let name = self.string()?;
+        self.expect(':')?;
+        Ok(name)
+    }
+
+    /// A string, number, or constant like `null`.
+    fn json_scalar(&mut self, span: &Span) -> Result<Value> {
+        Ok(match self.peek() {
+            Some('"') => Value::Str(self.string()?),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                let is_numeric = |c: &char| c.is_ascii_digit() || "+-.eE".contains(*c);
+                while let Some(c) = self.peek().filter(is_numeric) {
+                    number.push(c);
+                    self.next();
+                }
+                Value::Number(number)
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = self.peek().filter(char::is_ascii_alphabetic) {
+                    word.push(c);
+                    self.next();
+                }
+                match word.as_str() {
+                    "null" | "true" | "false" => Value::Constant,
+                    _ => return err_span(span.clone(), "expected a JSON value".to_string()),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use compiler::abstract_tree::{AbstractTree, TokenType};
+    use compiler::abstract_tree::AbstractTree::*;
+    use compiler::abstract_tree::TokenType::*;
+    use parser::{parse_recovering, MAX_HEIGHT};
+    use utils::{Position, Span};
+
+    /// `x` in `height - 1` nodes, one inside the next.
+    fn tall(height: usize) -> AbstractTree {
+        let mut tree = token(Symbol, "x");
+        for _ in 1..height {
+            tree = Node(vec![tree], Span::default());
+        }
+        tree
+    }
+
+    /// Takes a tree apart a level at a time - dropping
+    /// a tall one whole would recurse through it.
+    fn take_apart(mut tree: AbstractTree) {
+        while let Node(mut items, _) = tree {
+            tree = items.pop().unwrap();
+        }
+    }
+
+    fn token(token_type: TokenType, text: &str) -> AbstractTree {
+        Token(token_type, text.to_string(), Span::default())
+    }
+
+    #[test]
+    fn test_to_sexp() {
+        let source = "print \"hi \\\"there\\\"\" [1 2.5] 'x.y\nf )";
+        let tree = parse_recovering(source).tree;
+        assert_eq!(tree.to_sexp(false),
+                   "((print \"hi \\\"there\\\"\" (list 1 2.5) (quote (. x y))) \
+                    (f #error\"encountered unexpected ')' while reading top level expressions\"))");
+        assert_eq!(parse_recovering("a (b)").tree.to_sexp(true),
+                   "((a@0-1 (b@3-4)@2-5)@0-5)@0-5");
+
+        // tokens that would be read as something else are tagged
+        let tree = Node(vec![token(Symbol, "12"),
+                             token(Symbol, "a b"),
+                             token(Symbol, ""),
+                             token(Symbol, "#x"),
+                             token(Int, "12abc"),
+                             token(Float, "1"),
+                             token(Symbol, "-"),
+                             token(Symbol, "inf")],
+                        Span::default());
+        assert_eq!(tree.to_sexp(false),
+                   "(#symbol\"12\" #symbol\"a b\" #symbol\"\" #symbol\"#x\" #int\"12abc\" \
+                    #float\"1\" - inf)");
+        assert_eq!(AbstractTree::from_sexp(&tree.to_sexp(false), None).unwrap(), tree);
+    }
+
+    #[test]
+    fn test_from_sexp() {
+        let source = "define f { x\n  print \"é\\n\" -7 1e3\n}";
+        let tree = parse_recovering(source).tree;
+        assert_eq!(AbstractTree::from_sexp(&tree.to_sexp(true), Some(source)).unwrap(), tree);
+        assert!(AbstractTree::from_sexp(&tree.to_sexp(false), None)
+                    .unwrap()
+                    .eq_ignoring_spans(&tree));
+
+        // without the source, only the offsets are known
+        let read = AbstractTree::from_sexp("(a@0-1 b@2-3)@0-3", None).unwrap();
+        assert_eq!(read.arguments().unwrap()[1].span(),
+                   Span {
+                       start: Position(0, 0),
+                       end: Position(0, 0),
+                       start_offset: 2,
+                       end_offset: 3,
+                   });
+
+        let error = |sexp: &str| {
+            let error = AbstractTree::from_sexp(sexp, Some("ab")).err().unwrap();
+            (error.description, error.span.start_offset)
+        };
+        assert_eq!(error("(a (b)"), ("expected ')'".to_string(), 6));
+        assert_eq!(error("a)"), ("expected the end, not ')'".to_string(), 1));
+        assert_eq!(error("#sym\"a\""), ("'sym' isn't a type of token".to_string(), 4));
+        assert_eq!(error("\"a"), ("expected the end of a string".to_string(), 2));
+        assert_eq!(error("a@0-"), ("expected an offset".to_string(), 4));
+        assert_eq!(error("a@0-9"), ("9 isn't an offset in the source".to_string(), 5));
+        assert_eq!(error(&"(".repeat(20000)),
+                   (format!("this can only be nested {} deep", MAX_HEIGHT), MAX_HEIGHT));
+
+        // a tree as deep as the parser makes can be read back
+        let source = format!("{}x{}", "{ a\n".repeat(126), "\n}".repeat(126));
+        let tree = parse_recovering(&source).tree;
+        assert_eq!(AbstractTree::from_sexp(&tree.to_sexp(true), Some(&source)).unwrap(), tree);
+        assert_eq!(AbstractTree::from_json(&tree.to_json(true)).unwrap(), tree);
+
+        // and so can the tallest tree a custom reader can make
+        let tree = tall(MAX_HEIGHT);
+        assert_eq!(AbstractTree::from_sexp(&tree.to_sexp(true), None).unwrap(), tree);
+        assert_eq!(AbstractTree::from_json(&tree.to_json(true)).unwrap(), tree);
+    }
+
+    #[test]
+    fn test_write_tall_trees() {
+        // writing doesn't recurse, however tall the tree is
+        let tree = tall(100000);
+        let sexp = tree.to_sexp(false);
+        assert_eq!(sexp, format!("{}x{}", "(".repeat(99999), ")".repeat(99999)));
+        let json = tree.to_json(true);
+        assert!(json.starts_with("{\"type\":\"node\",\"items\":[{\"type\":\"node\""));
+        assert_eq!(json.matches("\"span\"").count(), 100000);
+        take_apart(tree);
+    }
+
+    #[test]
+    fn test_json() {
+        let source = "f \"a\\tb\" 2\n";
+        let tree = parse_recovering(source).tree;
+        assert_eq!(tree.to_json(false),
+                   "{\"type\":\"node\",\"items\":[{\"type\":\"node\",\"items\":[\
+                    {\"type\":\"symbol\",\"text\":\"f\"},{\"type\":\"str\",\"text\":\"a\\tb\"},\
+                    {\"type\":\"int\",\"text\":\"2\"}]}]}");
+        assert!(tree.to_json(true).starts_with(
+            "{\"type\":\"node\",\"items\":[{\"type\":\"node\",\"items\":[{\"type\":\"symbol\",\
+             \"text\":\"f\",\"span\":{\"start\":{\"line\":0,\"column\":0,\"offset\":0},\
+             \"end\":{\"line\":0,\"column\":1,\"offset\":1}}}"));
+        assert_eq!(AbstractTree::from_json(&tree.to_json(true)).unwrap(), tree);
+        assert!(AbstractTree::from_json(&tree.to_json(false)).unwrap().eq_ignoring_spans(&tree));
+
+        // other tools can write it however they like
+        let json = "{ \"items\": [ {\"text\": \"\\u00e9\", \"type\": \"symbol\",\n\
+                    \"extra\": null} ], \"type\": \"node\" }";
+        assert_eq!(AbstractTree::from_json(json).unwrap(),
+                   Node(vec![token(Symbol, "é")], Span::default()));
+
+        let error = |json: &str| {
+            let error = AbstractTree::from_json(json).err().unwrap();
+            (error.description, error.span.start_offset)
+        };
+        assert_eq!(error("{\"type\": \"tree\"}"), ("'tree' isn't a type of tree".to_string(), 9));
+        assert_eq!(error("{\"type\": \"int\"}"), ("expected a \"text\" field".to_string(), 0));
+        assert_eq!(error("[1, 2"), ("expected ','".to_string(), 5));
+        assert_eq!(error("{\"type\": \"node\", \"items\": [3]}"),
+                   ("expected an object".to_string(), 27));
+        assert_eq!(error(&"[".repeat(20000)),
+                   (format!("this can only be nested {} deep", 2 * MAX_HEIGHT + 1),
+                    2 * MAX_HEIGHT + 1));
+
+        // characters past \uffff are escaped as surrogate pairs
+        assert_eq!(AbstractTree::from_json("{\"type\":\"symbol\",\"text\":\"\\ud83c\\udf30\"}")
+                       .unwrap(),
+                   token(Symbol, "🌰"));
+        assert_eq!(error("\"\\ud83c\""), ("'\\ud83c' isn't a character".to_string(), 7));
+        assert_eq!(error("\"\\ud83c\\u0041\""),
+                   ("'\\ud83c\\u0041' isn't a character".to_string(), 13));
+    }
+}
@@ -0,0 +1,179 @@
+// compiler/variables.rs
+
+use std::collections::HashMap;
+
+use utils::{Result, Span, IR, err_span};
+use compiler::abstract_tree::{AbstractTree, TokenType};
+use compiler::abstract_tree::AbstractTree::*;
+use compiler::backend::LLVMBackend;
+use compiler::hir::{Expr, Parameter};
+use compiler::lower::Lowerer;
+
+/// The name a `let` or `=` assigns to.
+fn variable(at: &AbstractTree) -> Result<&String> {
+    match *at.argument(1)? {
+        Token(TokenType::Symbol, ref name, _) => Ok(name),
+        ref other => other.err(format!("{} needs a name to assign to", at.name()?)),
+    }
+}
+
+/// `let x value`.
+pub fn lower_let(lowerer: &Lowerer, tree: &AbstractTree) -> Result<Expr> {
+    let (name, name_span, value) = assignment(lowerer, tree)?;
+    Ok(Expr::Let {
+        name,
+        name_span,
+        value,
+        span: tree.span(),
+    })
+}
+
+/// `x = value`.
+pub fn lower_assign(lowerer: &Lowerer, tree: &AbstractTree) -> Result<Expr> {
+    let (name, name_span, value) = assignment(lowerer, tree)?;
+    Ok(Expr::Assign {
+        name,
+        name_span,
+        value,
+        span: tree.span(),
+    })
+}
+
+fn assignment(lowerer: &Lowerer, tree: &AbstractTree) -> Result<(String, Span, Box<Expr>)> {
+    tree.check_length(3)?;
+    Ok((variable(tree)?.clone(),
+        tree.argument(1)?.span(),
+        Box::new(lowerer.lower(tree.argument(2)?)?)))
+}
+
+/// Every name a `let` in `body` declares, at its `let` - a
+/// name can only be declared once, and not if it's a parameter.
+pub fn declarations(parameters: &[Parameter], body: &[Expr]) -> Result<HashMap<String, Span>> {
+    let mut declarations = HashMap::new();
+    for expression in body {
+        add_declarations(parameters, expression, &mut declarations)?;
+    }
+    Ok(declarations)
+}
+
+fn add_declarations(parameters: &[Parameter],
+                    expression: &Expr,
+                    declarations: &mut HashMap<String, Span>)
+                    -> Result<()> {
+    if let Expr::Let { ref name, ref name_span, .. } = *expression {
+        if let Some(parameter) = parameters.iter().find(|parameter| parameter.name == *name) {
+            return err_span(name_span.clone(), format!("'{}' is already a parameter", name))
+                .map_err(|error| {
+                    error.note(parameter.span.clone(), "it's declared here".to_string())
+                });
+        }
+        if let Some(first) = declarations.get(name) {
+            return err_span(name_span.clone(), format!("'{}' is already declared", name))
+                .map_err(|error| {
+                    error.note(first.clone(), "it's first declared here".to_string())
+                });
+        }
+        declarations.insert(name.clone(), name_span.clone());
+    }
+    for child in expression.children() {
+        add_declarations(parameters, child, declarations)?;
+    }
+    Ok(())
+}
+
+/// `let x value` declares the local `x` - the value is
+/// compiled first, so it can't use `x` itself.
+pub fn compile_let(backend: &mut LLVMBackend, name: &str, value: &Expr) -> Result<IR> {
+    let mut ir = backend.compile_inner(value)?;
+    let value = backend.get_counter("ret");
+    ir.append(&mut backend.set_var_ir(name, value));
+    Ok(ir)
+}
+
+/// `x = value` assigns to a local that's already declared.
+pub fn compile_assign(backend: &mut LLVMBackend,
+                      name: &str,
+                      span: &Span,
+                      value: &Expr)
+                      -> Result<IR> {
+    backend.check_declared(name, span)?;
+    if backend.get_assignee(name).is_none() {
+        return err_span(span.clone(),
+                        format!("'{}' isn't declared - declare it with let", name));
+    }
+    let mut ir = backend.compile_inner(value)?;
+    let value = backend.get_counter("ret");
+    ir.append(&mut backend.set_var_ir(name, value));
+    Ok(ir)
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::tests::compile_source;
+
+    #[test]
+    fn test_compile_let() {
+        let ir = compile_source("define start { x\nlet y x\ny = [y]\nprint y\n}").unwrap();
+        let start = ir.iter().position(|line| line.starts_with("define %object @start")).unwrap();
+        assert_eq!(&ir[start..],
+                   &["define %object @start(%object %in_arg.0) {",
+                     "%x = alloca %object",
+                     "%y = alloca %object",
+                     "store %object %in_arg.0, %object* %x",
+                     "%ret.0 = load %object, %object* %x",
+                     "store %object %ret.0, %object* %y",
+                     "%ret.1 = load %object, %object* %y",
+                     "%list.0 = alloca [1 x %object]",
+                     "%item.0 = getelementptr inbounds [1 x %object], \
+                      [1 x %object]* %list.0, i64 0, i64 0",
+                     "store %object %ret.1, %object* %item.0",
+                     "%ret.2 = call %object @_list_object(%object* %item.0, i64 1)",
+                     "store %object %ret.2, %object* %y",
+                     "%ret.3 = load %object, %object* %y",
+                     "%ret.4 = call %object @print(%object %ret.3)",
+                     "ret %object %ret.4",
+                     "}"][..]);
+    }
+
+    #[test]
+    fn test_used_before_let() {
+        let source = "define start { x\nprint y\nlet y x\n}";
+        let error = compile_source(source).err().unwrap();
+        assert_eq!(error.description, "'y' is used before it's declared");
+        assert_eq!(&source[error.span.start_offset..error.span.end_offset], "y");
+        assert_eq!(error.span.start_offset, 23);
+        assert_eq!(error.notes[0].description, "'y' is declared here");
+        assert_eq!(error.notes[0].span.start_offset, 29);
+    }
+
+    #[test]
+    fn test_compile_redeclaration() {
+        let source = "define start { x\nlet y 1\nlet y 2\n}";
+        let error = compile_source(source).err().unwrap();
+        assert_eq!(error.description, "'y' is already declared");
+        assert_eq!(error.span.start_offset, 29);
+        assert_eq!(error.notes[0].description, "it's first declared here");
+        assert_eq!(error.notes[0].span.start_offset, 21);
+
+        let source = "define start { x\nlet x 1\n}";
+        let error = compile_source(source).err().unwrap();
+        assert_eq!(error.description, "'x' is already a parameter");
+        assert_eq!(error.span.start_offset, 21);
+        assert_eq!(error.notes[0].description, "it's declared here");
+        assert_eq!(error.notes[0].span.start_offset, 15);
+    }
+
+    #[test]
+    fn test_variable_errors() {
+        let error = |body: &str| {
+            compile_source(&format!("define start {{ x\n{}\n}}", body)).err().unwrap().description
+        };
+        assert_eq!(error("y = 2"), "'y' isn't declared - declare it with let");
+        assert_eq!(error("print y"), "'y' isn't defined");
+        assert_eq!(error("x 1"), "unimplemented: no support for calling closures yet implemented");
+        assert_eq!(error("let 1 2"), "the 1st argument of 'let' has to be a name");
+        assert_eq!(error("let y"), "'let' takes 2 arguments");
+        // functions can be called before they're defined
+        assert!(compile_source("define start { x\nlater x\n}\ndefine later { y\ny\n}").is_ok());
+    }
+}
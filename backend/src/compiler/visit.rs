@@ -0,0 +1,297 @@
+// compiler/visit.rs
+
+use utils::Result;
+use compiler::abstract_tree::{AbstractTree, TokenType};
+use compiler::abstract_tree::AbstractTree::*;
+
+/// Where in a tree a visitor is. `depth` is how many nodes
+/// the tree is inside - a line of the top level is at depth 1 -
+/// and `parent` is the name of the node it's directly inside,
+/// if that node has one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Context {
+    pub depth: usize,
+    pub parent: Option<String>,
+}
+
+impl Context {
+    /// The context of the items of a node.
+    fn inside(&self, items: &[AbstractTree]) -> Context {
+        Context {
+            depth: self.depth + 1,
+            parent: match items.first() {
+                Some(&Token(TokenType::Symbol, ref name, _)) => Some(name.clone()),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// What a visitor wants done next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Walk {
+    Continue,
+    /// Don't visit the items of this node - from `leave`,
+    /// it's the same as `Continue`.
+    Skip,
+    /// Don't visit anything else.
+    Stop,
+}
+
+/// A Visitor is shown each tree in turn: `enter` before the items
+/// of a node, and `leave` after them.
+///
+/// A closure taking a tree and its context is a Visitor
+/// that only enters.
+pub trait Visitor {
+    fn enter(&mut self, _tree: &AbstractTree, _context: &Context) -> Walk {
+        Walk::Continue
+    }
+
+    fn leave(&mut self, _tree: &AbstractTree, _context: &Context) -> Walk {
+        Walk::Continue
+    }
+}
+
+/// Like a Visitor, but it can change the trees as it goes - the
+/// items of a node are visited after `enter` has changed it.
+pub trait VisitorMut {
+    fn enter(&mut self, _tree: &mut AbstractTree, _context: &Context) -> Walk {
+        Walk::Continue
+    }
+
+    fn leave(&mut self, _tree: &mut AbstractTree, _context: &Context) -> Walk {
+        Walk::Continue
+    }
+}
+
+/// A Folder builds a new tree out of an old one: `enter` rewrites a
+/// tree before its items are folded, and `leave` after. An error
+/// stops the fold.
+///
+/// A closure taking a tree and its context is a Folder
+/// that only leaves, so it sees its items already folded.
+pub trait Folder {
+    fn enter(&mut self, tree: AbstractTree, _context: &Context) -> Result<AbstractTree> {
+        Ok(tree)
+    }
+
+    fn leave(&mut self, tree: AbstractTree, _context: &Context) -> Result<AbstractTree> {
+        Ok(tree)
+    }
+}
+
+impl<F> Visitor for F
+    where F: FnMut(&AbstractTree, &Context) -> Walk
+{
+    fn enter(&mut self, tree: &AbstractTree, context: &Context) -> Walk {
+        self(tree, context)
+    }
+}
+
+impl<F> VisitorMut for F
+    where F: FnMut(&mut AbstractTree, &Context) -> Walk
+{
+    fn enter(&mut self, tree: &mut AbstractTree, context: &Context) -> Walk {
+        self(tree, context)
+    }
+}
+
+impl<F> Folder for F
+    where F: FnMut(AbstractTree, &Context) -> Result<AbstractTree>
+{
+    fn leave(&mut self, tree: AbstractTree, context: &Context) -> Result<AbstractTree> {
+        self(tree, context)
+    }
+}
+
+impl AbstractTree {
+    /// Shows `visitor` this tree and everything in it, in order.
+    pub fn walk<V: Visitor>(&self, visitor: &mut V) {
+        walk(self, visitor, &Context::default());
+    }
+
+    /// Shows `visitor` this tree and everything in
+    /// it, in order - letting it change them.
+    pub fn walk_mut<V: VisitorMut>(&mut self, visitor: &mut V) {
+        walk_mut(self, visitor, &Context::default());
+    }
+
+    /// The tree `folder` makes out of this one.
+    pub fn fold<F: Folder>(self, folder: &mut F) -> Result<AbstractTree> {
+        fold(self, folder, &Context::default())
+    }
+}
+
+fn walk<V: Visitor>(tree: &AbstractTree, visitor: &mut V, context: &Context) -> Walk {
+    match visitor.enter(tree, context) {
+        Walk::Stop => return Walk::Stop,
+        Walk::Skip => {}
+        Walk::Continue => {
+            if let Node(ref items, _) = *tree {
+                let inside = context.inside(items);
+                for item in items {
+                    if walk(item, visitor, &inside) == Walk::Stop {
+                        return Walk::Stop;
+                    }
+                }
+            }
+        }
+    }
+    visitor.leave(tree, context)
+}
+
+fn walk_mut<V: VisitorMut>(tree: &mut AbstractTree, visitor: &mut V, context: &Context) -> Walk {
+    match visitor.enter(tree, context) {
+        Walk::Stop => return Walk::Stop,
+        Walk::Skip => {}
+        Walk::Continue => {
+            if let Node(ref mut items, _) = *tree {
+                let inside = context.inside(items);
+                for item in items.iter_mut() {
+                    if walk_mut(item, visitor, &inside) == Walk::Stop {
+                        return Walk::Stop;
+                    }
+                }
+            }
+        }
+    }
+    visitor.leave(tree, context)
+}
+
+fn fold<F: Folder>(tree: AbstractTree, folder: &mut F, context: &Context) -> Result<AbstractTree> {
+    let tree = match folder.enter(tree, context)? {
+        Node(items, span) => {
+            let inside = context.inside(&items);
+            let items = items.into_iter()
+                             .map(|item| fold(item, folder, &inside))
+                             .collect::<Result<_>>()?;
+            Node(items, span)
+        }
+        token => token,
+    };
+    folder.leave(tree, context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Context, Folder, Visitor, Walk};
+    use compiler::abstract_tree::AbstractTree;
+    use compiler::abstract_tree::AbstractTree::*;
+    use compiler::abstract_tree::TokenType;
+    use parser::parse;
+    use utils::Result;
+
+    /// Writes down what it's shown, with each tree's
+    /// depth and parent.
+    #[derive(Default)]
+    struct Trace {
+        events: Vec<String>,
+    }
+
+    impl Visitor for Trace {
+        fn enter(&mut self, tree: &AbstractTree, context: &Context) -> Walk {
+            if let Token(_, ref text, _) = *tree {
+                self.events.push(format!("{} {} {}",
+                                         text,
+                                         context.depth,
+                                         context.parent.as_deref().unwrap_or("-")));
+            }
+            Walk::Continue
+        }
+
+        fn leave(&mut self, tree: &AbstractTree, context: &Context) -> Walk {
+            if tree.is_node() {
+                self.events.push(format!("leave {}", context.depth));
+            }
+            Walk::Continue
+        }
+    }
+
+    #[test]
+    fn test_walk_order_and_context() {
+        let tree = parse("f (g x)").unwrap().unwrap();
+        let mut trace = Trace::default();
+        tree.walk(&mut trace);
+        assert_eq!(trace.events,
+                   vec!["f 2 f", "g 3 g", "x 3 g", "leave 2", "leave 1", "leave 0"]);
+    }
+
+    #[test]
+    fn test_walk_with_closures() {
+        let mut tree = parse("a (b c) (d (e))\nf").unwrap().unwrap();
+
+        // stops at the first symbol deeper than the top-level lines' items
+        let mut seen = vec![];
+        tree.walk(&mut |tree: &AbstractTree, context: &Context| {
+            if let Token(_, ref text, _) = *tree {
+                seen.push(text.clone());
+                if context.depth > 2 {
+                    return Walk::Stop;
+                }
+            }
+            Walk::Continue
+        });
+        assert_eq!(seen, vec!["a", "b"]);
+
+        // skips the items of every node headed by `d`
+        let mut count = 0;
+        tree.walk_mut(&mut |tree: &mut AbstractTree, _: &Context| {
+            if let Token(_, ref mut text, _) = *tree {
+                text.push('!');
+                count += 1;
+            }
+            if tree.is_node() && matches!(tree.name(), Ok(name) if name == "d") {
+                return Walk::Skip;
+            }
+            Walk::Continue
+        });
+        assert_eq!(count, 4);
+        assert_eq!(tree.arguments().unwrap()[1].name().unwrap(), "f!");
+    }
+
+    /// Swaps the two items of every `pair`.
+    struct Swap;
+
+    impl Folder for Swap {
+        fn enter(&mut self, tree: AbstractTree, _: &Context) -> Result<AbstractTree> {
+            match tree {
+                Node(mut items, span) => {
+                    if matches!(items.first(), Some(Token(_, name, _)) if name == "pair") {
+                        items.swap(1, 2);
+                    }
+                    Ok(Node(items, span))
+                }
+                token => Ok(token),
+            }
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        let tree = parse("pair a (pair b c)").unwrap().unwrap();
+        let swapped = tree.clone().fold(&mut Swap).unwrap();
+        assert!(swapped.eq_ignoring_spans(&parse("pair (pair c b) a").unwrap().unwrap()));
+
+        // a closure is shown each tree after its items
+        let doubled = tree.fold(&mut |tree: AbstractTree, _: &Context| -> Result<AbstractTree> {
+            match tree {
+                Token(TokenType::Symbol, ref name, ref span) if name != "pair" => {
+                    Ok(Token(TokenType::Symbol, name.repeat(2), span.clone()))
+                }
+                tree => Ok(tree),
+            }
+        });
+        assert!(doubled.unwrap().eq_ignoring_spans(&parse("pair aa (pair bb cc)")
+                                                       .unwrap()
+                                                       .unwrap()));
+
+        // an error stops the fold
+        let mut no_b = |tree: AbstractTree, _: &Context| match tree {
+            Token(_, ref name, _) if name == "b" => tree.err("no b".to_string()),
+            tree => Ok(tree),
+        };
+        let error = parse("a (b c)").unwrap().unwrap().fold(&mut no_b).err().unwrap();
+        assert_eq!(error.description, "no b");
+    }
+}
@@ -0,0 +1,460 @@
+// formatter/mod.rs
+
+use lexer::TokenKind;
+use parser::cst::{parse_cst, GroupKind, SyntaxTree};
+use parser::{is_indentation_sensitive, parse_recovering, Comment, Parsed};
+use utils::{Error, MultiResult, Span};
+
+/// Calls are wrapped to fit in this many columns, where they can be.
+pub const MAX_WIDTH: usize = 80;
+
+/// How far the lines of a block are indented.
+const INDENT: usize = 4;
+
+/// format lays out acorn source the one canonical way:
+///
+///   - the lines of a multi-line `{ }` block are indented,
+///     and its `}` goes on a line of its own
+///   - expressions are separated by one space, with none
+///     just inside parens
+///   - there's at most one blank line in a row, and none
+///     at the start or end of a file or block
+///   - calls in parens that are too wide are wrapped, with
+///     their arguments lined up under the first one
+///
+/// Comments are kept. Source that doesn't parse is an error,
+/// and so is any change to what the source means - the result
+/// is parsed again, and has to give the same AbstractTree.
+/// Indentation-sensitive source is left as it is, since its
+/// layout is part of what it means.
+pub fn format(source: &str) -> MultiResult<String> {
+    let parsed = parse_recovering(source);
+    if !parsed.errors.is_empty() {
+        return Err(parsed.errors);
+    }
+    if is_indentation_sensitive(source) {
+        return Err(vec![Error {
+                            description: "indentation-sensitive source can't be formatted"
+                                .to_string(),
+                            span: Span::default(),
+                            notes: vec![],
+                        }]);
+    }
+
+    let cst = parse_cst(source);
+    let lines = match cst.tree {
+        SyntaxTree::Group(_, ref lines) => lines.iter().collect::<Vec<_>>(),
+        SyntaxTree::Token(_) => vec![],
+    };
+    let mut formatted = format_lines(&lines, 0);
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+
+    if same_meaning(&parsed, &parse_recovering(&formatted)) {
+        Ok(formatted)
+    } else {
+        Err(vec![Error {
+            description: "formatting would change what this source means, \
+                          so it's been left as it is"
+                .to_string(),
+            span: Span::default(),
+            notes: vec![],
+        }])
+    }
+}
+
+/// Whether `source` is already formatted.
+pub fn is_formatted(source: &str) -> MultiResult<bool> {
+    format(source).map(|formatted| formatted == source)
+}
+
+fn same_meaning(original: &Parsed, formatted: &Parsed) -> bool {
+    let comments = |comments: &[Comment]| {
+        comments.iter().map(|comment| comment.text.trim_end().to_string()).collect::<Vec<_>>()
+    };
+    formatted.errors.is_empty() && formatted.tree.eq_ignoring_spans(&original.tree) &&
+    comments(&formatted.comments) == comments(&original.comments)
+}
+
+/// The expressions and comments of a group - everything
+/// but whitespace, newlines and the group's delimiters.
+fn items(children: &[SyntaxTree]) -> Vec<&SyntaxTree> {
+    children.iter()
+            .filter(|child| match **child {
+                SyntaxTree::Token(ref token) => {
+                    !matches!(token.kind,
+                              TokenKind::Whitespace | TokenKind::Newline |
+                              TokenKind::OpenParen | TokenKind::CloseParen |
+                              TokenKind::OpenSquare | TokenKind::CloseSquare |
+                              TokenKind::OpenCurly | TokenKind::CloseCurly)
+                }
+                SyntaxTree::Group(_, _) => true,
+            })
+            .collect()
+}
+
+/// The lines of a block.
+fn block_lines(children: &[SyntaxTree]) -> Vec<&SyntaxTree> {
+    children.iter()
+            .filter(|child| matches!(**child, SyntaxTree::Group(GroupKind::Line, _)))
+            .collect()
+}
+
+/// The items of a line.
+fn line_items(line: &SyntaxTree) -> Vec<&SyntaxTree> {
+    match *line {
+        SyntaxTree::Group(_, ref children) => items(children),
+        SyntaxTree::Token(_) => vec![],
+    }
+}
+
+fn is_line_comment(tree: &SyntaxTree) -> bool {
+    matches!(*tree, SyntaxTree::Token(ref token) if token.kind == TokenKind::LineComment)
+}
+
+fn is_block_comment(tree: &SyntaxTree) -> bool {
+    matches!(*tree, SyntaxTree::Token(ref token) if token.kind == TokenKind::BlockComment)
+}
+
+fn ends_with_newline(tree: &SyntaxTree) -> bool {
+    match *tree {
+        SyntaxTree::Group(_, ref children) => {
+            matches!(children.last(),
+                     Some(SyntaxTree::Token(token)) if token.kind == TokenKind::Newline)
+        }
+        SyntaxTree::Token(_) => false,
+    }
+}
+
+/// The width of the last line of some formatted text.
+fn width(text: &str) -> usize {
+    text.rsplit('\n').next().unwrap_or("").chars().count()
+}
+
+/// The column just after some formatted text that started at
+/// `column` - the lines after its first are already indented.
+fn column_after(column: usize, text: &str) -> usize {
+    if text.contains('\n') {
+        width(text)
+    } else {
+        column + width(text)
+    }
+}
+
+/// Formats lines at an indentation, dropping blank lines
+/// at the start and end and squashing the rest to one.
+fn format_lines(lines: &[&SyntaxTree], indent: usize) -> String {
+    let mut output = vec![];
+    let mut blank = false;
+    for line in lines {
+        let children = match **line {
+            SyntaxTree::Group(_, ref children) => children,
+            SyntaxTree::Token(_) => continue,
+        };
+        let items = items(children);
+        if items.is_empty() {
+            blank = true;
+            continue;
+        }
+        if blank && !output.is_empty() {
+            output.push(String::new());
+        }
+        blank = false;
+        output.push(format!("{}{}", " ".repeat(indent), format_line(&items, indent, indent)));
+    }
+    output.join("\n")
+}
+
+/// Formats the items of a line one space apart,
+/// starting at `column`.
+fn format_line(items: &[&SyntaxTree], column: usize, indent: usize) -> String {
+    let mut line = String::new();
+    for item in items {
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        let item_column = column_after(column, &line);
+        line.push_str(&format_item(item, item_column, indent));
+    }
+    line
+}
+
+/// Formats an item starting at `column`, in a line at `indent`.
+fn format_item(item: &SyntaxTree, column: usize, indent: usize) -> String {
+    match *item {
+        SyntaxTree::Token(ref token) if token.kind == TokenKind::LineComment => {
+            token.text.trim_end().to_string()
+        }
+        SyntaxTree::Token(ref token) => token.text.clone(),
+        SyntaxTree::Group(GroupKind::Block, ref children) => format_block(children, indent),
+        SyntaxTree::Group(GroupKind::Prefixed, ref children) => {
+            format_prefixed(children, column, indent)
+        }
+        SyntaxTree::Group(GroupKind::Square, ref children) => {
+            format_delimited(children, ("[", "]"), false, column, indent)
+        }
+        SyntaxTree::Group(_, ref children) => {
+            format_delimited(children, ("(", ")"), true, column, indent)
+        }
+    }
+}
+
+/// A paren or a list goes on one line if it fits - otherwise each
+/// item goes on a line of its own. The arguments of a call line
+/// up under the first argument, and the items of a list under
+/// the first item.
+fn format_delimited(children: &[SyntaxTree],
+                    (open, close): (&str, &str),
+                    call: bool,
+                    column: usize,
+                    indent: usize)
+                    -> String {
+    let items = items(children);
+
+    let mut one_line = open.to_string();
+    if push_flat_items(&items, MAX_WIDTH.saturating_sub(column), &mut one_line).is_some() {
+        one_line.push_str(close);
+        if column + width(&one_line) <= MAX_WIDTH {
+            return one_line;
+        }
+    }
+
+    let mut output = open.to_string();
+    let mut align = column + 1;
+    let mut after_comment = false;
+    for (i, item) in items.iter().enumerate() {
+        if i == 0 {
+            output.push_str(&format_item(item, column + 1, indent));
+            if call && !output.contains('\n') && !is_line_comment(item) {
+                align = column + width(&output) + 1;
+            }
+        } else if ((call && i == 1) || is_line_comment(item)) && !after_comment {
+            output.push(' ');
+            let item_column = column_after(column, &output);
+            output.push_str(&format_item(item, item_column, indent));
+        } else {
+            output.push('\n');
+            output.push_str(&" ".repeat(align));
+            output.push_str(&format_item(item, align, indent));
+        }
+        after_comment = is_line_comment(item);
+    }
+    if after_comment {
+        // a comment runs to the end of its line
+        output.push('\n');
+        output.push_str(&" ".repeat(align));
+    }
+    output.push_str(close);
+    output
+}
+
+/// Lays out `item` on one line, appending it to `line` - or gives
+/// None if it can't go on one line, or `line` gets wider than
+/// `room`. It stops as soon as it's too wide, so trying the one
+/// line layout of a group doesn't lay out all that's inside it.
+fn push_flat(item: &SyntaxTree, room: usize, line: &mut String) -> Option<()> {
+    match *item {
+        SyntaxTree::Token(ref token) if token.kind == TokenKind::LineComment => return None,
+        SyntaxTree::Token(ref token) => line.push_str(&token.text),
+        SyntaxTree::Group(GroupKind::Block, ref children) => {
+            let lines = block_lines(children);
+            if lines.iter().any(|line| ends_with_newline(line)) {
+                return None;
+            }
+            let items = lines.first().map(|line| line_items(line)).unwrap_or_default();
+            if items.is_empty() {
+                line.push_str("{ }");
+            } else {
+                line.push_str("{ ");
+                push_flat_items(&items, room, line)?;
+                line.push_str(" }");
+            }
+        }
+        SyntaxTree::Group(GroupKind::Prefixed, ref children) => {
+            let mut after_comment = false;
+            for item in items(children) {
+                let is_comment = is_block_comment(item);
+                if is_comment || after_comment {
+                    line.push(' ');
+                }
+                push_flat(item, room, line)?;
+                after_comment = is_comment;
+            }
+        }
+        SyntaxTree::Group(kind, ref children) => {
+            let (open, close) = if kind == GroupKind::Square { ("[", "]") } else { ("(", ")") };
+            line.push_str(open);
+            push_flat_items(&items(children), room, line)?;
+            line.push_str(close);
+        }
+    }
+    if line.contains('\n') || line.chars().count() > room {
+        None
+    } else {
+        Some(())
+    }
+}
+
+/// Lays out items one space apart with `push_flat`.
+fn push_flat_items(items: &[&SyntaxTree], room: usize, line: &mut String) -> Option<()> {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            line.push(' ');
+        }
+        push_flat(item, room, line)?;
+    }
+    Some(())
+}
+
+/// A reader prefix goes right up against what it reads: `'x`.
+/// (A line comment can't come between them - its newline would
+/// end the expression - but a block comment can.)
+fn format_prefixed(children: &[SyntaxTree], column: usize, indent: usize) -> String {
+    let mut prefixed = String::new();
+    let mut after_comment = false;
+    for item in items(children) {
+        let is_comment = is_block_comment(item);
+        if is_comment || after_comment {
+            prefixed.push(' ');
+        }
+        let item_column = column_after(column, &prefixed);
+        prefixed.push_str(&format_item(item, item_column, indent));
+        after_comment = is_comment;
+    }
+    prefixed
+}
+
+/// A block that's on one line stays on one line - putting
+/// it over several would turn its body into parameters.
+fn format_block(children: &[SyntaxTree], indent: usize) -> String {
+    let lines = block_lines(children);
+
+    if !lines.iter().any(|line| ends_with_newline(line)) {
+        let items = lines.first().map(|line| line_items(line)).unwrap_or_default();
+        if items.is_empty() {
+            return "{ }".to_string();
+        }
+        return format!("{{ {} }}", format_line(&items, indent + 2, indent));
+    }
+
+    // the first line holds the block's parameters
+    let mut block = "{".to_string();
+    if let Some(SyntaxTree::Group(_, children)) = lines.first() {
+        let parameters = items(children);
+        if !parameters.is_empty() {
+            block.push(' ');
+            block.push_str(&format_line(&parameters, indent + 2, indent));
+        }
+    }
+    let body = format_lines(&lines[1..], indent + INDENT);
+    if !body.is_empty() {
+        block.push('\n');
+        block.push_str(&body);
+    }
+    block.push('\n');
+    block.push_str(&" ".repeat(indent));
+    block.push('}');
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format, is_formatted};
+
+    fn assert_formats(source: &str, expected: &str) {
+        assert_eq!(format(source).unwrap(), expected);
+        // formatting twice changes nothing
+        assert_eq!(format(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_spacing() {
+        assert_formats("print   ( add  1 2 )   \"s\"", "print (add 1 2) \"s\"\n");
+        assert_formats("f(x)#c  ", "f (x) #c\n");
+        assert_formats("", "");
+    }
+
+    #[test]
+    fn test_format_blocks() {
+        assert_formats("define start {   x\nprint_number x\n  print x }",
+                       "define start { x\n    print_number x\n    print x\n}\n");
+        assert_formats("{ print   x }", "{ print x }\n");
+        assert_formats("{\n\n{ a\nb\n}\n}", "{\n    { a\n        b\n    }\n}\n");
+        assert_formats("{  }", "{ }\n");
+        assert_formats("map {a  |  times  a 2}", "map { a | times a 2 }\n");
+        assert_formats("f { a | g a\nh }", "f { a | g a\n    h\n}\n");
+    }
+
+    #[test]
+    fn test_format_blank_lines() {
+        assert_formats("\n\na\n\n\n\nb\n\n", "a\n\nb\n");
+        assert_formats("{\n\n a\n\n\n b\n\n}", "{\n    a\n\n    b\n}\n");
+    }
+
+    #[test]
+    fn test_format_comments() {
+        assert_formats("#| head |#   a\n   # own line\nb # end",
+                       "#| head |# a\n# own line\nb # end\n");
+        assert_formats("(f a # first\n b)", "(f a # first\n   b)\n");
+    }
+
+    #[test]
+    fn test_format_wraps_long_calls() {
+        let source = format!("print (combine {} {} (join {} {}))",
+                             "a".repeat(20),
+                             "b".repeat(20),
+                             "c".repeat(20),
+                             "d".repeat(20));
+        let expected = format!("print (combine {}\n               {}\n               \
+                                (join {} {}))\n",
+                               "a".repeat(20),
+                               "b".repeat(20),
+                               "c".repeat(20),
+                               "d".repeat(20));
+        assert_formats(&source, &expected);
+    }
+
+    #[test]
+    fn test_format_lists() {
+        assert_formats("f [ 1  2\n 3 ] [ ]", "f [1 2 3] []\n");
+        let source = format!("print [{} {} ({} {})]",
+                             "a".repeat(25),
+                             "b".repeat(25),
+                             "c".repeat(10),
+                             "d".repeat(10));
+        let expected = format!("print [{}\n       {}\n       ({} {})]\n",
+                               "a".repeat(25),
+                               "b".repeat(25),
+                               "c".repeat(10),
+                               "d".repeat(10));
+        assert_formats(&source, &expected);
+    }
+
+    #[test]
+    fn test_format_deep_nesting() {
+        // each group is laid out once, however deep it is
+        let source = format!("{}a{}\n", "(".repeat(100), ")".repeat(100));
+        assert_formats(&source, &source);
+        let source = format!("{}a{}\n", "(f ".repeat(100), ")".repeat(100));
+        assert_formats(&source, &source);
+        let source = format!("print {}a{}", "[(g ".repeat(50), ")]".repeat(50));
+        assert_formats(&source, &format!("{}\n", source));
+    }
+
+    #[test]
+    fn test_format_prefixes() {
+        assert_formats("print ' x `( a ,@ b)", "print 'x `(a ,@b)\n");
+        assert_formats("f '#| why |# x", "f ' #| why |# x\n");
+    }
+
+    #[test]
+    fn test_format_errors() {
+        assert_eq!(format("(a").err().unwrap()[0].description,
+                   "hit end of file while reading an open paren");
+        assert!(is_formatted("a b\n").unwrap());
+        assert!(!is_formatted("a  b").unwrap());
+        assert_eq!(format("#pragma indent\nf:\n  x\n").err().unwrap()[0].description,
+                   "indentation-sensitive source can't be formatted");
+    }
+}
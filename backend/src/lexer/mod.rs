@@ -0,0 +1,741 @@
+// lexer/mod.rs
+
+use std::iter::Peekable;
+use std::str::Chars;
+use utils::{Result, Error, err_span, Position, Span};
+
+/// The kinds of token the lexer produces. Unlike
+/// `TokenType`, these include the delimiters and the
+/// trivia (whitespace and comments) between expressions,
+/// so the tokens cover every byte of the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+    Symbol,
+    Int,
+    Float,
+    Str,
+    OpenParen,
+    CloseParen,
+    OpenCurly,
+    CloseCurly,
+    OpenSquare,
+    CloseSquare,
+    Newline,
+    Whitespace,
+    /// `# ...` up to the end of the line
+    LineComment,
+    /// `#| ... |#`, which can be nested
+    BlockComment,
+    /// Text the lexer couldn't read - the lexer itself
+    /// returns an error, but the parser carries on past
+    /// it with one of these in its place.
+    Error,
+    /// A dispatch sequence registered with `Lexer::add_prefix`,
+    /// like `#[` - the parser hands it to the reader for it.
+    Reader,
+}
+
+impl TokenKind {
+    /// Trivia is whatever the parser skips over - it matters
+    /// to tools that keep the source's layout, not to the AbstractTree.
+    pub fn is_trivia(self) -> bool {
+        matches!(self,
+                 TokenKind::Whitespace | TokenKind::LineComment | TokenKind::BlockComment)
+    }
+
+    /// Closers end whatever expression is being read.
+    pub fn is_closer(self) -> bool {
+        matches!(self,
+                 TokenKind::CloseParen | TokenKind::CloseCurly | TokenKind::CloseSquare |
+                 TokenKind::Newline)
+    }
+}
+
+/// A token is a kind, the exact source text it was read
+/// from, and where that text is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub span: Span,
+}
+
+/// The Lexer reads tokens off the source on demand - the
+/// parser pulls them one at a time, and `tokenize` collects
+/// all of them for tools that just want the token stream.
+pub struct Lexer<'a> {
+    source: &'a str,
+    stream: Peekable<Chars<'a>>,
+    position: Position,
+    offset: usize,
+    prefixes: Vec<String>, // longest first, so `#[[` beats `#[`
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Lexer<'a> {
+        Lexer {
+            source,
+            stream: source.chars().peekable(),
+            position: Position(0, 0),
+            offset: 0,
+            prefixes: vec![],
+        }
+    }
+
+    /// Registers a dispatch sequence - wherever a token could
+    /// start with it, it's read as a `TokenKind::Reader` token
+    /// instead of whatever it would otherwise be.
+    pub fn add_prefix(&mut self, prefix: &str) {
+        if prefix.is_empty() || self.prefixes.iter().any(|p| p == prefix) {
+            return;
+        }
+        self.prefixes.push(prefix.to_string());
+        self.prefixes.sort_by_key(|p| ::std::cmp::Reverse(p.len()));
+    }
+
+    /// Moves back to an earlier point, so the
+    /// source after it is read again.
+    pub fn rewind(&mut self, point: &Span) {
+        self.stream = self.source[point.start_offset..].chars().peekable();
+        self.position = point.start.clone();
+        self.offset = point.start_offset;
+    }
+
+    /// Reads the raw source up to and including `terminator`,
+    /// returning the span of everything read.
+    pub fn read_until(&mut self, terminator: &str) -> Result<Span> {
+        let start = self.point();
+        while !self.source[self.offset..].starts_with(terminator) {
+            if self.advance_char().is_none() {
+                return err_span(self.span_from(&start),
+                                format!("hit end of file while looking for '{}'", terminator));
+            }
+        }
+        for _ in terminator.chars() {
+            self.advance_char();
+        }
+        Ok(self.span_from(&start))
+    }
+
+    /// The empty span where the next token will start.
+    pub fn point(&self) -> Span {
+        Span::point(self.position.clone(), self.offset)
+    }
+
+    /// The source text a span covers.
+    pub fn text(&self, span: &Span) -> &'a str {
+        &self.source[span.start_offset..span.end_offset]
+    }
+
+    fn span_from(&self, start: &Span) -> Span {
+        start.to(&self.point())
+    }
+
+    fn advance_char(&mut self) -> Option<char> {
+        let current_char = self.stream.next();
+        if let Some(c) = current_char {
+            advance_position(&mut self.position, c);
+            self.offset += c.len_utf8();
+        }
+        current_char
+    }
+
+    fn current_char(&mut self) -> Option<char> {
+        self.stream.peek().cloned()
+    }
+
+    fn advance_while(&mut self, f: fn(char) -> bool) {
+        while self.current_char().is_some_and(f) {
+            self.advance_char();
+        }
+    }
+
+    /// Reads the next token, or returns None at the end of the source.
+    pub fn next_token(&mut self) -> Result<Option<Token>> {
+        let start = self.point();
+
+        let source = self.source;
+        let rest = &source[self.offset..];
+        if let Some(length) = self.prefixes
+                                  .iter()
+                                  .find(|p| rest.starts_with(p.as_str()))
+                                  .map(|p| p.len()) {
+            while self.offset < start.start_offset + length {
+                self.advance_char();
+            }
+            return Ok(Some(Token {
+                kind: TokenKind::Reader,
+                text: rest[..length].to_string(),
+                span: self.span_from(&start),
+            }));
+        }
+
+        let kind = match self.advance_char() {
+            None => return Ok(None),
+            Some('(') => TokenKind::OpenParen,
+            Some(')') => TokenKind::CloseParen,
+            Some('{') => TokenKind::OpenCurly,
+            Some('}') => TokenKind::CloseCurly,
+            Some('[') => TokenKind::OpenSquare,
+            Some(']') => TokenKind::CloseSquare,
+            Some('\n') => TokenKind::Newline,
+            Some(c) if is_whitespace(c) => {
+                self.advance_while(is_whitespace);
+                TokenKind::Whitespace
+            }
+            Some('"') => self.read_string(&start)?,
+            Some('#') => self.read_comment(&start)?,
+            Some(_) => {
+                self.advance_while(is_symbol_char);
+                let text = &self.source[start.start_offset..self.offset];
+                if is_number(text) {
+                    number_token_kind(text, self.span_from(&start))?
+                } else {
+                    TokenKind::Symbol
+                }
+            }
+        };
+
+        Ok(Some(Token {
+            kind,
+            text: self.source[start.start_offset..self.offset].to_string(),
+            span: self.span_from(&start),
+        }))
+    }
+
+    /// Like next_token, but text that can't be read becomes a
+    /// `TokenKind::Error` token - returned along with its error.
+    pub fn next_token_recovering(&mut self) -> (Option<Token>, Option<Error>) {
+        let start = self.point();
+        match self.next_token() {
+            Ok(token) => (token, None),
+            Err(error) => {
+                // the lexer has already moved past the text it couldn't
+                // read - which can be more than the error is about.
+                let span = self.span_from(&start);
+                let token = Token {
+                    kind: TokenKind::Error,
+                    text: self.text(&span).to_string(),
+                    span,
+                };
+                (Some(token), Some(error))
+            }
+        }
+    }
+
+    /// Reads every token that's left, carrying on past errors.
+    pub fn tokenize_recovering(mut self) -> (Vec<Token>, Vec<Error>) {
+        let mut tokens = vec![];
+        let mut errors = vec![];
+        while let (Some(token), error) = self.next_token_recovering() {
+            tokens.push(token);
+            errors.extend(error);
+        }
+        (tokens, errors)
+    }
+
+    /// Reads up to the closing '"' - skipping over the code in
+    /// any `#{..}`, strings and all. The escapes are checked
+    /// here, but only resolved when the parser asks for the
+    /// string's value.
+    fn read_string(&mut self, start: &Span) -> Result<TokenKind> {
+        let end = match string_length(&self.source[self.offset..]) {
+            Some(length) => self.offset + length,
+            None => {
+                while self.advance_char().is_some() {}
+                return err_span(self.span_from(start),
+                                "hit end of file while reading a string".to_string());
+            }
+        };
+        while self.offset < end {
+            self.advance_char();
+        }
+        literal_parts(&self.source[start.start_offset..self.offset], start)?;
+        Ok(TokenKind::Str)
+    }
+
+    /// Reads either kind of comment. A line comment stops
+    /// before the '\n', so newlines still end expressions.
+    fn read_comment(&mut self, start: &Span) -> Result<TokenKind> {
+        if self.current_char() != Some('|') {
+            self.advance_while(|c| c != '\n');
+            return Ok(TokenKind::LineComment);
+        }
+        self.advance_char();
+        let mut depth = 1;
+        while depth > 0 {
+            match self.advance_char() {
+                Some('|') if self.current_char() == Some('#') => {
+                    self.advance_char();
+                    depth -= 1;
+                }
+                Some('#') if self.current_char() == Some('|') => {
+                    self.advance_char();
+                    depth += 1;
+                }
+                Some(_) => {}
+                None => {
+                    return err_span(self.span_from(start),
+                                    "hit end of file while reading a block comment"
+                                        .to_string())
+                }
+            }
+        }
+        Ok(TokenKind::BlockComment)
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token>;
+
+    fn next(&mut self) -> Option<Result<Token>> {
+        self.next_token().transpose()
+    }
+}
+
+/// tokenize reads all of the source's tokens, trivia
+/// included - joining their text gives back the source.
+pub fn tokenize(source: &str) -> Result<Vec<Token>> {
+    Lexer::new(source).collect()
+}
+
+/// Like tokenize, but carries on past errors - so the
+/// tokens cover the source even when it's malformed.
+pub fn tokenize_recovering(source: &str) -> (Vec<Token>, Vec<Error>) {
+    Lexer::new(source).tokenize_recovering()
+}
+
+/// Moves a position past one character.
+pub fn advance_position(position: &mut Position, c: char) {
+    if c == '\n' {
+        // column set to 0
+        // line incremented
+        position.0 += 1;
+        position.1 = 0;
+    } else {
+        // column incremented
+        position.1 += 1;
+    }
+}
+
+fn is_whitespace(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\r'
+}
+
+/// Symbols and numbers run until whitespace or a
+/// character that starts some other token.
+fn is_symbol_char(c: char) -> bool {
+    !is_whitespace(c) && !"\n(){}[]\"#".contains(c)
+}
+
+/// The value of an Int token.
+pub fn int_value(token: &Token) -> Result<i64> {
+    parse_int(&token.text, &token.span)
+}
+
+/// A piece of a string literal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StringPart {
+    /// Text, with its escapes resolved.
+    Text(String, Span),
+    /// The code of a `#{..}`, between its braces.
+    Code(Span),
+}
+
+/// The value of a Str token: its text without the
+/// quotes, with the escapes resolved. The code of
+/// any `#{..}` is left out.
+pub fn string_value(token: &Token) -> Result<String> {
+    let parts = string_parts(token)?;
+    Ok(parts.into_iter()
+            .filter_map(|part| match part {
+                StringPart::Text(text, _) => Some(text),
+                StringPart::Code(_) => None,
+            })
+            .collect())
+}
+
+/// The parts of a Str token: the text between its `#{..}`s,
+/// with the escapes resolved, and the code in them. A string
+/// without any code is one Text part - even if it's empty.
+pub fn string_parts(token: &Token) -> Result<Vec<StringPart>> {
+    literal_parts(&token.text, &token.span)
+}
+
+/// The length of a string literal, closing '"' included,
+/// from just after its opening '"' - or None if it isn't
+/// closed.
+fn string_length(literal: &str) -> Option<usize> {
+    nested_length(literal, Nested::Text)
+}
+
+/// The length of the code in a `#{..}`, from just after the
+/// `#{` up to the '}' that closes it. The braces and strings
+/// in the code are skipped over.
+fn interpolation_length(code: &str) -> Option<usize> {
+    nested_length(code, Nested::Code(0))
+}
+
+/// What's being scanned in a string literal: its text, or the
+/// code of a `#{..}`, with how many of the code's own braces
+/// are open.
+enum Nested {
+    Text,
+    Code(usize),
+}
+
+/// The length of `source` up to what closes `outer` - a '"',
+/// which is counted, or a '}', which isn't. The strings and
+/// `#{..}`s inside are kept on a stack rather than scanned
+/// with recursion, so however deeply they're nested can't
+/// overflow.
+fn nested_length(source: &str, outer: Nested) -> Option<usize> {
+    let mut nested = vec![outer];
+    let mut i = 0;
+    loop {
+        let c = source[i..].chars().next()?;
+        let closed = match *nested.last_mut()? {
+            Nested::Text => {
+                match c {
+                    '"' => true,
+                    '\\' => {
+                        i += 1 + source[i + 1..].chars().next().map_or(0, char::len_utf8);
+                        continue;
+                    }
+                    '#' if source[i + 1..].starts_with('{') => {
+                        i += 2;
+                        nested.push(Nested::Code(0));
+                        continue;
+                    }
+                    _ => false,
+                }
+            }
+            Nested::Code(ref mut depth) => {
+                match c {
+                    '}' if *depth == 0 => true,
+                    '}' => {
+                        *depth -= 1;
+                        false
+                    }
+                    '{' => {
+                        *depth += 1;
+                        false
+                    }
+                    '"' => {
+                        i += 1;
+                        nested.push(Nested::Text);
+                        continue;
+                    }
+                    _ => false,
+                }
+            }
+        };
+        if closed {
+            match nested.pop()? {
+                Nested::Text if nested.is_empty() => return Some(i + 1),
+                Nested::Code(_) if nested.is_empty() => return Some(i),
+                _ => {}
+            }
+        }
+        i += c.len_utf8();
+    }
+}
+
+/// Splits a quoted string literal that starts at `start` into
+/// its parts, resolving the escapes in its text.
+fn literal_parts(literal: &str, start: &Span) -> Result<Vec<StringPart>> {
+    let mut chars = LiteralChars {
+        chars: literal[1..literal.len() - 1].chars(),
+        point: start.start_point(),
+    };
+    chars.point = chars.point.to(&advance_point(&chars.point, '"'));
+    let mut parts = vec![];
+    let mut text_start = advance_point(&start.start_point(), '"');
+    let mut contents = String::new();
+    loop {
+        // errors cover the whole escape sequence
+        let escape_start = chars.point.clone();
+        let c = match chars.next() {
+            Some('\\') => {
+                match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('#') => '#',
+                    Some('u') => read_unicode_escape(&mut chars, &escape_start)?,
+                    Some(c) => {
+                        return err_span(escape_start.to(&chars.point),
+                                        format!("unknown escape sequence '\\{}' in a string", c))
+                    }
+                    None => {
+                        return err_span(start.clone(),
+                                        "hit end of file while reading a string".to_string())
+                    }
+                }
+            }
+            Some('#') if chars.chars.as_str().starts_with('{') => {
+                if !contents.is_empty() {
+                    parts.push(StringPart::Text(contents, text_start.to(&escape_start)));
+                    contents = String::new();
+                }
+                chars.next();
+                let code_start = chars.point.clone();
+                let length = match interpolation_length(chars.chars.as_str()) {
+                    Some(length) => length,
+                    None => {
+                        return err_span(escape_start.to(&chars.point),
+                                        "a '#{' in a string needs a '}' to close it".to_string())
+                    }
+                };
+                let end_offset = code_start.end_offset + length;
+                while chars.point.end_offset < end_offset {
+                    chars.next();
+                }
+                parts.push(StringPart::Code(code_start.to(&chars.point)));
+                chars.next();
+                text_start = chars.point.clone();
+                continue;
+            }
+            Some(c) => c,
+            None => break,
+        };
+        contents.push(c);
+    }
+    if !contents.is_empty() || parts.is_empty() {
+        parts.push(StringPart::Text(contents, text_start.to(&chars.point)));
+    }
+    Ok(parts)
+}
+
+/// The empty span just past `c`, if `c` starts at `point`.
+fn advance_point(point: &Span, c: char) -> Span {
+    let mut position = point.end.clone();
+    advance_position(&mut position, c);
+    Span::point(position, point.end_offset + c.len_utf8())
+}
+
+/// The characters of a string literal, along with the
+/// empty span where the next one starts.
+struct LiteralChars<'a> {
+    chars: Chars<'a>,
+    point: Span,
+}
+
+impl<'a> Iterator for LiteralChars<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if let Some(c) = c {
+            self.point = advance_point(&self.point, c);
+        }
+        c
+    }
+}
+
+/// Reads the `{..}` part of a `\u{..}` escape: one to
+/// six hex digits naming a unicode scalar value.
+fn read_unicode_escape(chars: &mut LiteralChars, escape_start: &Span) -> Result<char> {
+    if chars.next() != Some('{') {
+        return err_span(escape_start.to(&chars.point),
+                        "expected '{' after '\\u' in a string".to_string());
+    }
+    let mut digits = String::new();
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some(c) if c.is_ascii_hexdigit() && digits.len() < 6 => digits.push(c),
+            _ => {
+                return err_span(escape_start.to(&chars.point),
+                                "a unicode escape takes one to six hex digits \
+                                 followed by '}'"
+                                    .to_string())
+            }
+        }
+    }
+    match u32::from_str_radix(&digits, 16).ok().and_then(::std::char::from_u32) {
+        Some(c) => Ok(c),
+        None => {
+            err_span(escape_start.to(&chars.point),
+                     format!("'\\u{{{}}}' is not a valid unicode character", digits))
+        }
+    }
+}
+
+/// Numbers start with a digit, or a '-' and then a digit -
+/// `-5` is a literal, while `- 5` is a call to `-`.
+fn is_number(text: &str) -> bool {
+    text.strip_prefix('-').unwrap_or(text).starts_with(|c: char| c.is_ascii_digit())
+}
+
+/// Works out whether a number is an Int or a Float. Anything
+/// with a '.' or an exponent after its leading digits is a Float,
+/// and has to be a well formed one - and an Int has to fit in
+/// the payload of an object.
+fn number_token_kind(chars: &str, span: Span) -> Result<TokenKind> {
+    let unsigned = chars.strip_prefix('-').unwrap_or(chars);
+    let after_digits = unsigned.trim_start_matches(|c: char| c.is_ascii_digit());
+    if radix(unsigned).is_some() || !after_digits.starts_with(['.', 'e', 'E']) {
+        return parse_int(chars, &span).map(|_| TokenKind::Int);
+    }
+    if !is_float_literal(unsigned) {
+        return err_span(span, format!("malformed float literal '{}'", chars));
+    }
+    match chars.parse::<f64>() {
+        Ok(float) if float.is_finite() => Ok(TokenKind::Float),
+        _ => err_span(span, format!("float literal '{}' is out of range", chars)),
+    }
+}
+
+/// The radix of an int literal with a `0x`, `0b` or `0o`
+/// prefix, and its digits after the prefix.
+fn radix(unsigned: &str) -> Option<(u32, &str)> {
+    let radixes = [("0x", 16), ("0X", 16), ("0b", 2), ("0B", 2), ("0o", 8), ("0O", 8)];
+    radixes.iter()
+           .find(|&&(prefix, _)| unsigned.starts_with(prefix))
+           .map(|&(prefix, radix)| (radix, &unsigned[prefix.len()..]))
+}
+
+/// Reads an int literal: an optional '-', an optional radix
+/// prefix, then digits - which can be separated by single '_'s,
+/// like `1_000`, but can't start or end with one.
+fn parse_int(chars: &str, span: &Span) -> Result<i64> {
+    let (sign, unsigned) = match chars.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", chars),
+    };
+    let (radix, digits) = radix(unsigned).unwrap_or((10, unsigned));
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        return err_span(span.clone(), format!("malformed integer literal '{}'", chars));
+    }
+    let digits = digits.replace('_', "");
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return err_span(span.clone(), format!("malformed integer literal '{}'", chars));
+    }
+    match i64::from_str_radix(&format!("{}{}", sign, digits), radix) {
+        Ok(int) => Ok(int),
+        Err(_) => err_span(span.clone(), format!("integer literal '{}' is out of range", chars)),
+    }
+}
+
+/// Matches `digits ['.' digits] [('e' | 'E') ['+' | '-'] digits]`.
+fn is_float_literal(chars: &str) -> bool {
+    // returns what's left, and whether there were any digits
+    fn skip_digits(s: &str) -> (&str, bool) {
+        let rest = s.trim_start_matches(|c: char| c.is_ascii_digit());
+        (rest, rest.len() < s.len())
+    }
+
+    let (mut rest, _) = skip_digits(chars);
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let (after, any) = skip_digits(fraction);
+        if !any {
+            return false;
+        }
+        rest = after;
+    }
+    if let Some(exponent) = rest.strip_prefix(['e', 'E']) {
+        let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+        let (after, any) = skip_digits(exponent);
+        if !any {
+            return false;
+        }
+        rest = after;
+    }
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use lexer::{tokenize, string_parts, string_value, Lexer, StringPart};
+    use lexer::TokenKind::*;
+    use utils::{Position, Span};
+
+    fn kinds(source: &str) -> Vec<::lexer::TokenKind> {
+        tokenize(source).unwrap().iter().map(|token| token.kind).collect()
+    }
+
+    #[test]
+    fn test_tokenize_kinds() {
+        assert_eq!(kinds("define (f 2.5) { x\n  \"s\" # c\n} #| b |#"),
+                   vec![Symbol, Whitespace, OpenParen, Symbol, Whitespace, Float, CloseParen,
+                        Whitespace, OpenCurly, Whitespace, Symbol, Newline, Whitespace, Str,
+                        Whitespace, LineComment, Newline, CloseCurly, Whitespace, BlockComment]);
+        assert_eq!(kinds("[a[1]]"),
+                   vec![OpenSquare, Symbol, OpenSquare, Int, CloseSquare, CloseSquare]);
+    }
+
+    #[test]
+    fn test_tokens_cover_source() {
+        let source = "map { a\ntimes a 2 # twice\n}\n\"\\u{e9}t\u{e9}\"";
+        let tokens = tokenize(source).unwrap();
+        let text = tokens.iter().map(|token| token.text.clone()).collect::<String>();
+        assert_eq!(text, source);
+        assert_eq!(tokens.last().unwrap().span,
+                   Span {
+                       start: Position(3, 0),
+                       end: Position(3, 10),
+                       start_offset: 28,
+                       end_offset: 39,
+                   });
+        assert_eq!(string_value(tokens.last().unwrap()).unwrap(), "\u{e9}t\u{e9}");
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        let error = tokenize("one \"two\\x\"").err().unwrap();
+        assert_eq!(error.description, "unknown escape sequence '\\x' in a string");
+        assert_eq!(error.span,
+                   Span {
+                       start: Position(0, 8),
+                       end: Position(0, 10),
+                       start_offset: 8,
+                       end_offset: 10,
+                   });
+
+        let error = tokenize("one\n  #| two").err().unwrap();
+        assert_eq!(error.description, "hit end of file while reading a block comment");
+        assert_eq!(error.span.start, Position(1, 2));
+        assert_eq!(error.span.end, Position(1, 8));
+    }
+
+    #[test]
+    fn test_tokenize_interpolation() {
+        // the code's strings and braces are part of the string
+        assert_eq!(kinds("\"a #{f \"}\" {b}} \\#{\" c"), vec![Str, Whitespace, Symbol]);
+        let tokens = tokenize("\"é#{x}\\n\"").unwrap();
+        // columns count characters, and offsets count bytes
+        let at = |start, end| Span {
+            start: Position(0, start as i64 - 1),
+            end: Position(0, end as i64 - 1),
+            start_offset: start,
+            end_offset: end,
+        };
+        assert_eq!(string_parts(&tokens[0]).unwrap(),
+                   vec![StringPart::Text("é".to_string(),
+                                         Span { start: Position(0, 1), ..at(1, 3) }),
+                        StringPart::Code(at(5, 6)),
+                        StringPart::Text("\n".to_string(), at(7, 9))]);
+        assert_eq!(tokenize("\"#{\"}\"").err().unwrap().description,
+                   "hit end of file while reading a string");
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let mut lexer = Lexer::new("#[a #[[ # c");
+        lexer.add_prefix("#[");
+        lexer.add_prefix("#[[");
+        let tokens = lexer.map(|token| token.unwrap()).collect::<Vec<_>>();
+        assert_eq!(tokens.iter().map(|token| token.kind).collect::<Vec<_>>(),
+                   vec![Reader, Symbol, Whitespace, Reader, Whitespace, LineComment]);
+        assert_eq!(tokens[3].text, "#[[");
+
+        let mut lexer = Lexer::new("raw\" \"# after");
+        let span = lexer.read_until("\"#").unwrap();
+        assert_eq!(span.end_offset, 7);
+        assert_eq!(lexer.next_token().unwrap().unwrap().kind, Whitespace);
+        assert_eq!(lexer.read_until("|").err().unwrap().description,
+                   "hit end of file while looking for '|'");
+    }
+}
@@ -0,0 +1,7 @@
+// lib.rs
+
+pub mod compiler;
+pub mod utils;
+pub mod lexer;
+pub mod parser;
+pub mod formatter;
@@ -1,27 +1,158 @@
 // main.rs
 
-#[macro_use(puts)]
-extern crate puts;
+extern crate acorn;
 
-mod compiler;
-mod utils;
-mod parser;
-
-use utils::ErrorHandling;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+use acorn::{compiler, formatter, parser};
+use acorn::utils::{report_error, ErrorHandling};
 
 fn main() {
+    let arguments = env::args().skip(1).collect::<Vec<_>>();
+    if arguments.first().map(|a| a.as_str()) == Some("fmt") {
+        process::exit(fmt(&arguments[1..]));
+    }
+    if arguments.first().map(|a| a.as_str()) == Some("parse") {
+        process::exit(parse(&arguments[1..]));
+    }
+
     let source =
 "
 define start { x
     print_number x
-    print_number x
+    let greeting \"hello from acorn\"
+    print greeting
+    print greeting.length
+    print 4.25
+    print '(hello (from) acorn)
+    print (push [1 2.5] \"three\")
+    print \"#{greeting.length} characters in #{greeting}\"
+    greeting |> concat \"!\" |> print
+    print (1 + 2 * 3 ^ 2 - 4 / 2)
+    print (x < 1 and not 2.5 == 3)
+    show x
+    show label: 'total 42
+}
+
+define show { value label: 'value
+    print [label value]
 }";
 
-    let abstract_tree = parser::parse(source)
-                            .unpack_error()
-                            .expect("failed to parse anything, weird");
-    let ir = compiler::compile(abstract_tree).unpack_error();
+    // syntax errors are reported by compile, along with any others
+    let abstract_tree = parser::parse_recovering(source).tree;
+    let ir = compiler::compile(abstract_tree).unpack_error(source);
     for line in ir {
         println!("{}", line);
     }
 }
+
+/// `acorn fmt [--check] [files...]` formats each file in place -
+/// or stdin to stdout, without any files. With `--check` nothing
+/// is written: the exit code is 1 if anything isn't formatted.
+/// Sources that can't be formatted exit with 2.
+fn fmt(arguments: &[String]) -> i32 {
+    let check = arguments.iter().any(|a| a == "--check");
+    let paths = arguments.iter().filter(|a| *a != "--check").collect::<Vec<_>>();
+
+    if paths.is_empty() {
+        let mut source = String::new();
+        if io::stdin().read_to_string(&mut source).is_err() {
+            eprintln!("couldn't read stdin");
+            return 2;
+        }
+        return match formatter::format(&source) {
+            Ok(ref formatted) if check => (formatted != &source) as i32,
+            Ok(formatted) => {
+                print!("{}", formatted);
+                0
+            }
+            Err(errors) => {
+                for error in &errors {
+                    report_error(error, &source);
+                }
+                2
+            }
+        };
+    }
+
+    let mut status = 0;
+    for path in paths {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("couldn't read {}: {}", path, error);
+                status = 2;
+                continue;
+            }
+        };
+        match formatter::format(&source) {
+            Ok(ref formatted) if formatted == &source => {}
+            Ok(_) if check => {
+                eprintln!("{} isn't formatted", path);
+                status = status.max(1);
+            }
+            Ok(formatted) => {
+                if let Err(error) = fs::write(path, formatted) {
+                    eprintln!("couldn't write {}: {}", path, error);
+                    status = 2;
+                }
+            }
+            Err(errors) => {
+                eprintln!("couldn't format {}:", path);
+                for error in &errors {
+                    report_error(error, &source);
+                }
+                status = 2;
+            }
+        }
+    }
+    status
+}
+
+/// `acorn parse [--json] [--positions] [file]` prints the tree a
+/// file - or stdin, without one - parses to, as an S-expression
+/// or as JSON, for other tools to read. Syntax errors are left in
+/// the tree, and reported: the exit code is then 1.
+fn parse(arguments: &[String]) -> i32 {
+    let json = arguments.iter().any(|a| a == "--json");
+    let positions = arguments.iter().any(|a| a == "--positions");
+    let paths = arguments.iter()
+                         .filter(|a| *a != "--json" && *a != "--positions")
+                         .collect::<Vec<_>>();
+
+    let mut source = String::new();
+    match paths.as_slice() {
+        [] => {
+            if io::stdin().read_to_string(&mut source).is_err() {
+                eprintln!("couldn't read stdin");
+                return 2;
+            }
+        }
+        [path] => {
+            match fs::read_to_string(path) {
+                Ok(contents) => source = contents,
+                Err(error) => {
+                    eprintln!("couldn't read {}: {}", path, error);
+                    return 2;
+                }
+            }
+        }
+        _ => {
+            eprintln!("acorn parse takes one file at most");
+            return 2;
+        }
+    }
+
+    let parsed = parser::parse_recovering(&source);
+    if json {
+        println!("{}", parsed.tree.to_json(positions));
+    } else {
+        println!("{}", parsed.tree.to_sexp(positions));
+    }
+    for error in &parsed.errors {
+        report_error(error, &source);
+    }
+    !parsed.errors.is_empty() as i32
+}
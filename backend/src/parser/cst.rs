@@ -0,0 +1,315 @@
+// parser/cst.rs
+
+use std::fmt;
+use utils::{Error, Span};
+use lexer::{Lexer, Token, TokenKind};
+use parser::{Parsed, Parser, MAX_NESTING, QUOTING_PREFIXES};
+
+/// What a group of the concrete syntax tree is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupKind {
+    /// The whole source: a list of lines.
+    Root,
+    /// The expressions of a line, ending with its newline
+    /// (if it has one).
+    Line,
+    /// `(` ... `)`, newlines included.
+    Paren,
+    /// `[` ... `]`, newlines included.
+    Square,
+    /// `{` then a list of lines, then `}`.
+    Block,
+    /// A reader prefix like `'`, and the expression it reads.
+    Prefixed,
+}
+
+/// The concrete syntax tree keeps every byte of the source -
+/// whitespace, comments and delimiters included - so tools can
+/// rewrite a file without losing its layout. Printing it gives
+/// back the source exactly.
+///
+/// A group that isn't closed just ends without its closer,
+/// and a closer that doesn't close anything stays where it is,
+/// as a token of its line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyntaxTree {
+    Token(Token),
+    Group(GroupKind, Vec<SyntaxTree>),
+}
+
+impl SyntaxTree {
+    /// Every token of the tree, in source order.
+    pub fn tokens(&self) -> Vec<&Token> {
+        match *self {
+            SyntaxTree::Token(ref token) => vec![token],
+            SyntaxTree::Group(_, ref children) => {
+                children.iter().flat_map(|child| child.tokens()).collect()
+            }
+        }
+    }
+
+    /// The span from the first token to the last - an
+    /// empty group is an empty span at the start of the source.
+    pub fn span(&self) -> Span {
+        let tokens = self.tokens();
+        match (tokens.first(), tokens.last()) {
+            (Some(first), Some(last)) => first.span.to(&last.span),
+            _ => Span::default(),
+        }
+    }
+
+    /// Converts the tree to an AbstractTree - by parsing its
+    /// text with the standard parser, so the two always agree.
+    ///
+    /// The tree isn't built from the groups: they only know the
+    /// standard readers, and source read with others - like a
+    /// `#'` registered with `Parser::read_prefix` - is grouped as
+    /// something else, a comment say. Its text is still all there,
+    /// so `to_abstract_tree_with` reads it again with those readers.
+    pub fn to_abstract_tree(&self) -> Parsed {
+        self.to_abstract_tree_with(|parser| parser)
+    }
+
+    /// Like `to_abstract_tree`, with the readers `readers`
+    /// registers on the standard parser.
+    pub fn to_abstract_tree_with<F>(&self, readers: F) -> Parsed
+        where F: for<'a> FnOnce(Parser<'a>) -> Parser<'a>
+    {
+        readers(Parser::new(&self.to_string())).parse()
+    }
+}
+
+impl fmt::Display for SyntaxTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.tokens().iter().try_for_each(|token| f.write_str(&token.text))
+    }
+}
+
+/// The concrete syntax tree of some source, and
+/// any errors from reading its tokens.
+pub struct Cst {
+    pub tree: SyntaxTree,
+    pub errors: Vec<Error>,
+}
+
+/// Reads the concrete syntax tree of `source`. Text that
+/// can't be read becomes a `TokenKind::Error` token.
+///
+/// The standard reader prefixes (like the `'` of `'x`) are
+/// read as `TokenKind::Reader` tokens, as the parser reads them.
+pub fn parse_cst(source: &str) -> Cst {
+    let mut lexer = Lexer::new(source);
+    for &(prefix, _) in QUOTING_PREFIXES {
+        lexer.add_prefix(prefix);
+    }
+    let (tokens, errors) = lexer.tokenize_recovering();
+    let mut builder = Builder {
+        tokens: tokens.into_iter().peekable(),
+        open_delimiters: vec![],
+        depth: 0,
+    };
+    let lines = builder.lines();
+    Cst {
+        tree: SyntaxTree::Group(GroupKind::Root, lines),
+        errors,
+    }
+}
+
+struct Builder {
+    tokens: ::std::iter::Peekable<::std::vec::IntoIter<Token>>,
+    // the closers that enclosing groups are waiting for
+    open_delimiters: Vec<TokenKind>,
+    depth: usize, // how many groups are being read, one inside the next
+}
+
+impl Builder {
+    fn peek_kind(&mut self) -> Option<TokenKind> {
+        self.tokens.peek().map(|token| token.kind)
+    }
+
+    fn closes_enclosing(&mut self) -> bool {
+        match self.peek_kind() {
+            Some(kind) => self.open_delimiters.contains(&kind),
+            None => false,
+        }
+    }
+
+    /// Reads lines up to the end of the source, or
+    /// the closer of an enclosing group.
+    fn lines(&mut self) -> Vec<SyntaxTree> {
+        let mut lines = vec![];
+        while self.peek_kind().is_some() && !self.closes_enclosing() {
+            let mut line = vec![];
+            loop {
+                if self.closes_enclosing() {
+                    break;
+                }
+                match self.peek_kind() {
+                    None => break,
+                    Some(TokenKind::Newline) => {
+                        line.push(SyntaxTree::Token(self.tokens.next().unwrap()));
+                        break;
+                    }
+                    Some(_) => line.push(self.item()),
+                }
+            }
+            lines.push(SyntaxTree::Group(GroupKind::Line, line));
+        }
+        lines
+    }
+
+    /// Reads a token, or the group it opens. Past MAX_NESTING,
+    /// where the parser stops, what would open a group is kept
+    /// as a token - so the tree still prints back as the source.
+    fn item(&mut self) -> SyntaxTree {
+        let token = self.tokens.next().unwrap();
+        if self.depth == MAX_NESTING {
+            return SyntaxTree::Token(token);
+        }
+        self.depth += 1;
+        let item = self.group(token);
+        self.depth -= 1;
+        item
+    }
+
+    fn group(&mut self, token: Token) -> SyntaxTree {
+        match token.kind {
+            TokenKind::OpenParen | TokenKind::OpenSquare => {
+                let (kind, closer) = if token.kind == TokenKind::OpenParen {
+                    (GroupKind::Paren, TokenKind::CloseParen)
+                } else {
+                    (GroupKind::Square, TokenKind::CloseSquare)
+                };
+                let mut children = vec![SyntaxTree::Token(token)];
+                self.open_delimiters.push(closer);
+                loop {
+                    match self.peek_kind() {
+                        Some(next) if next == closer => {
+                            children.push(SyntaxTree::Token(self.tokens.next().unwrap()));
+                            break;
+                        }
+                        Some(_) if self.closes_enclosing() => break,
+                        Some(_) => children.push(self.item()),
+                        None => break,
+                    }
+                }
+                self.open_delimiters.pop();
+                SyntaxTree::Group(kind, children)
+            }
+            TokenKind::OpenCurly => {
+                let mut children = vec![SyntaxTree::Token(token)];
+                self.open_delimiters.push(TokenKind::CloseCurly);
+                children.append(&mut self.lines());
+                self.open_delimiters.pop();
+                if self.peek_kind() == Some(TokenKind::CloseCurly) {
+                    children.push(SyntaxTree::Token(self.tokens.next().unwrap()));
+                }
+                SyntaxTree::Group(GroupKind::Block, children)
+            }
+            TokenKind::Reader => {
+                let mut children = vec![SyntaxTree::Token(token)];
+                loop {
+                    // like the parser, reads past trivia but not past a closer
+                    match self.peek_kind() {
+                        Some(kind) if kind.is_trivia() => {
+                            children.push(SyntaxTree::Token(self.tokens.next().unwrap()));
+                        }
+                        Some(kind) if !kind.is_closer() => {
+                            children.push(self.item());
+                            break;
+                        }
+                        _ => break,
+                    }
+                }
+                SyntaxTree::Group(GroupKind::Prefixed, children)
+            }
+            _ => SyntaxTree::Token(token),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cst, SyntaxTree};
+    use compiler::abstract_tree::{AbstractTree, TokenType};
+    use parser::{parse_recovering, Parser};
+
+    fn kinds(tree: &SyntaxTree) -> String {
+        match *tree {
+            SyntaxTree::Token(ref token) => format!("{:?}", token.kind),
+            SyntaxTree::Group(kind, ref children) => {
+                format!("{:?}[{}]",
+                        kind,
+                        children.iter().map(kinds).collect::<Vec<_>>().join(" "))
+            }
+        }
+    }
+
+    #[test]
+    fn test_cst_structure() {
+        let cst = parse_cst("f (a\n b) # c\n{ x\n  y }");
+        assert_eq!(kinds(&cst.tree),
+                   "Root[Line[Symbol Whitespace \
+                    Paren[OpenParen Symbol Newline Whitespace Symbol CloseParen] \
+                    Whitespace LineComment Newline] \
+                    Line[Block[OpenCurly Line[Whitespace Symbol Newline] \
+                    Line[Whitespace Symbol Whitespace] CloseCurly]]]");
+    }
+
+    #[test]
+    fn test_cst_malformed() {
+        let source = "(a }\n) \"b\\q\" {";
+        let cst = parse_cst(source);
+        assert_eq!(cst.tree.to_string(), source);
+        assert_eq!(cst.errors.len(), 1);
+        assert_eq!(kinds(&cst.tree),
+                   "Root[Line[Paren[OpenParen Symbol Whitespace CloseCurly Newline CloseParen] \
+                    Whitespace Error Whitespace Block[OpenCurly]]]");
+    }
+
+    #[test]
+    fn test_cst_squares() {
+        let cst = parse_cst("[a (b]) c");
+        assert_eq!(kinds(&cst.tree),
+                   "Root[Line[Square[OpenSquare Symbol Whitespace \
+                    Paren[OpenParen Symbol] CloseSquare] CloseParen Whitespace Symbol]]");
+    }
+
+    #[test]
+    fn test_cst_prefixes() {
+        let cst = parse_cst("'x `(a ,@b)");
+        assert_eq!(kinds(&cst.tree),
+                   "Root[Line[Prefixed[Reader Symbol] Whitespace \
+                    Prefixed[Reader Paren[OpenParen Symbol Whitespace \
+                    Prefixed[Reader Symbol] CloseParen]]]]");
+    }
+
+    #[test]
+    fn test_cst_to_abstract_tree() {
+        let source = "define f { x\n  print x # shown\n}\n";
+        let cst = parse_cst(source);
+        assert_eq!(cst.tree.span().end_offset, source.len());
+        assert_eq!(cst.tree.to_abstract_tree().tree, parse_recovering(source).tree);
+
+        // `#'x` reads as `(quote x)` with a reader the cst doesn't know
+        // about - so it's grouped as a comment, but reads the same
+        fn hash_quote(parser: Parser) -> Parser {
+            parser.read_prefix("#'", |parser| {
+                let prefix = parser.next_token().unwrap();
+                let expression = parser.read_expression()?;
+                let span = prefix.span.to(&expression.span());
+                let quote = AbstractTree::Token(TokenType::Symbol,
+                                                "quote".to_string(),
+                                                prefix.span);
+                Ok(Some(AbstractTree::Node(vec![quote, expression], span)))
+            })
+        }
+        let source = "print #'x\n";
+        let cst = parse_cst(source);
+        assert_eq!(kinds(&cst.tree), "Root[Line[Symbol Whitespace LineComment Newline]]");
+        let parsed = hash_quote(Parser::new(source)).parse();
+        assert!(parsed.errors.is_empty());
+        assert_eq!(cst.tree.to_abstract_tree_with(hash_quote).tree, parsed.tree);
+        assert!(cst.tree.to_abstract_tree().tree != parsed.tree);
+    }
+}
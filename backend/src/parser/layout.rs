@@ -0,0 +1,244 @@
+// parser/layout.rs
+
+use std::collections::VecDeque;
+use utils::{Error, Span};
+use super::split_colon;
+use lexer::{Lexer, Token, TokenKind};
+
+/// A file that starts with this line is indentation-sensitive.
+pub const INDENT_PRAGMA: &str = "#pragma indent";
+
+/// Whether `source` opts in to indentation-sensitive blocks.
+pub fn is_indentation_sensitive(source: &str) -> bool {
+    source.lines().next().map(|line| line.trim_end()) == Some(INDENT_PRAGMA)
+}
+
+/// Layout turns indentation into the tokens of `{ }` blocks,
+/// a line at a time, so the parser reads both the same way.
+/// A line ending in `:` opens a block, and the lines indented
+/// under it are the block's body:
+///
+/// ```text
+/// define start | x:
+///     print_number x
+/// ```
+///
+/// is read as `define start { x` ... `}`: the `|` (if there is
+/// one) becomes the `{`, with the block's parameters after it.
+/// Without a `|` the `:` becomes the `{`, and the block has no
+/// parameters. The end of the block is a `}` and a newline, as
+/// wide as nothing, where the indentation goes back out.
+///
+/// Lines inside parens, squares and `{ }` are left alone.
+pub struct Layout {
+    /// The indentation of each enclosing block's body,
+    /// starting with the top level's.
+    indents: Vec<usize>,
+    /// Whether the last line opened a block.
+    expect_indent: bool,
+    /// How many delimiters are open, of the tokens handed out.
+    depth: usize,
+    at_line_start: bool,
+    pending: VecDeque<(Token, Option<Error>)>,
+}
+
+impl Layout {
+    pub fn new() -> Layout {
+        Layout {
+            indents: vec![0],
+            expect_indent: false,
+            depth: 0,
+            at_line_start: true,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// The next token, and the error behind it if it's
+    /// a `TokenKind::Error` - like `Lexer::next_token_recovering`.
+    pub fn next_token(&mut self, lexer: &mut Lexer) -> (Option<Token>, Option<Error>) {
+        if self.pending.is_empty() {
+            self.read_line(lexer);
+        }
+        match self.pending.pop_front() {
+            Some((token, error)) => {
+                self.hand_out(&token);
+                (Some(token), error)
+            }
+            None => (None, None),
+        }
+    }
+
+    /// Forgets the rest of the line - the lexer has been
+    /// rewound into it, to read it some other way.
+    pub fn rewind(&mut self) {
+        self.pending.clear();
+        self.expect_indent = false;
+    }
+
+    /// Where the lexer has to be rewound to, to
+    /// read the tokens that haven't been handed out.
+    pub fn rewind_point(&self) -> Option<Span> {
+        self.pending.front().map(|(token, _)| token.span.start_point())
+    }
+
+    fn hand_out(&mut self, token: &Token) {
+        match (token.kind, token.text.as_str()) {
+            (TokenKind::OpenParen, _) |
+            (TokenKind::OpenSquare, _) |
+            (TokenKind::OpenCurly, "{") => {
+                self.depth += 1;
+            }
+            (TokenKind::CloseParen, _) |
+            (TokenKind::CloseSquare, _) |
+            (TokenKind::CloseCurly, "}") => {
+                self.depth = self.depth.saturating_sub(1);
+            }
+            _ => {}
+        }
+        self.at_line_start = token.kind == TokenKind::Newline;
+    }
+
+    fn read_line(&mut self, lexer: &mut Lexer) {
+        let mut line = vec![];
+        let mut at_eof = true;
+        while let (Some(token), error) = lexer.next_token_recovering() {
+            let newline = token.kind == TokenKind::Newline;
+            line.push((token, error));
+            if newline {
+                at_eof = false;
+                break;
+            }
+        }
+
+        let has_content = line.iter()
+                              .any(|(token, _)| {
+                                  !token.kind.is_trivia() && token.kind != TokenKind::Newline
+                              });
+        if self.at_line_start && self.depth == 0 && has_content {
+            let column = match line[0].0.kind {
+                TokenKind::Whitespace => line[0].0.text.chars().count(),
+                _ => 0,
+            };
+            self.indent(column, line[0].0.span.start_point());
+            self.open_block(&mut line);
+        }
+        self.pending.extend(line);
+
+        if at_eof {
+            let point = lexer.point();
+            if self.expect_indent {
+                self.expect_indent = false;
+                self.push_error(&point, "expected an indented block after ':'");
+                self.push_close(&point);
+            }
+            while self.indents.len() > 1 {
+                self.indents.pop();
+                self.push_close(&point);
+            }
+        }
+    }
+
+    /// Opens or closes blocks for a line indented to `column`.
+    fn indent(&mut self, column: usize, point: Span) {
+        if self.expect_indent {
+            self.expect_indent = false;
+            if column > *self.indents.last().unwrap() {
+                self.indents.push(column);
+                return;
+            }
+            self.push_error(&point, "expected an indented block after ':'");
+            self.push_close(&point);
+        }
+        let mut dedented = false;
+        while column < *self.indents.last().unwrap() {
+            self.indents.pop();
+            self.push_close(&point);
+            dedented = true;
+        }
+        if column == *self.indents.last().unwrap() {
+            return;
+        }
+        if dedented {
+            self.push_error(&point, "this line's indentation doesn't match an enclosing block's");
+        } else {
+            self.push_error(&point, "unexpected indentation");
+        }
+    }
+
+    /// Turns a trailing `:` (and a `|` before it) into a `{`.
+    fn open_block(&mut self, line: &mut Vec<(Token, Option<Error>)>) {
+        // the last token that isn't trivia, outside of any delimiters
+        let mut depth = 0usize;
+        let mut last = None;
+        let mut bar = None;
+        for (i, (token, _)) in line.iter().enumerate() {
+            match token.kind {
+                TokenKind::OpenParen | TokenKind::OpenSquare | TokenKind::OpenCurly => depth += 1,
+                TokenKind::CloseParen | TokenKind::CloseSquare | TokenKind::CloseCurly => {
+                    depth = depth.saturating_sub(1)
+                }
+                _ => {}
+            }
+            if token.kind.is_trivia() || token.kind == TokenKind::Newline {
+                continue;
+            }
+            last = Some((i, depth));
+            if depth == 0 && token.kind == TokenKind::Symbol && token.text == "|" {
+                bar = Some(i);
+            }
+        }
+
+        let i = match last {
+            Some((i, 0)) if line[i].0.kind == TokenKind::Symbol &&
+                            line[i].0.text.ends_with(':') => i,
+            _ => return,
+        };
+        let token = line[i].0.clone();
+        let (name_span, colon_span) = split_colon(&token);
+        let colon = Token {
+            kind: TokenKind::OpenCurly,
+            text: ":".to_string(),
+            span: colon_span,
+        };
+        let mut replacement = vec![];
+        if token.text.len() > 1 {
+            replacement.push((Token {
+                                  kind: TokenKind::Symbol,
+                                  text: token.text[..token.text.len() - 1].to_string(),
+                                  span: name_span,
+                              },
+                              None));
+        }
+        match bar {
+            Some(bar) if bar < i => line[bar].0.kind = TokenKind::OpenCurly,
+            _ => replacement.push((colon, None)),
+        }
+        line.splice(i..i + 1, replacement);
+        self.expect_indent = true;
+    }
+
+    fn push_close(&mut self, point: &Span) {
+        for &kind in &[TokenKind::CloseCurly, TokenKind::Newline] {
+            let token = Token {
+                kind,
+                text: String::new(),
+                span: point.clone(),
+            };
+            self.pending.push_back((token, None));
+        }
+    }
+
+    fn push_error(&mut self, point: &Span, description: &str) {
+        let token = Token {
+            kind: TokenKind::Error,
+            text: String::new(),
+            span: point.clone(),
+        };
+        let error = Error {
+            description: description.to_string(),
+            span: point.clone(),
+            notes: vec![],
+        };
+        self.pending.push_back((token, Some(error)));
+    }
+}
@@ -1,149 +1,603 @@
 // parser/mod.rs
 
+mod operators;
+mod layout;
+pub mod cst;
+
 use std::collections::HashMap;
-use std::iter::Peekable;
-use std::str::Chars;
-use utils::{Result, err_position, Position};
-use super::compiler::abstract_tree::{AbstractTree, TokenType, BLOCK_IDENTIFIER};
+use std::rc::Rc;
+use utils::{Result, Error, err_span, Position, Span};
+use lexer::{self, Lexer, StringPart, Token, TokenKind};
+use self::layout::Layout;
+pub use self::layout::{INDENT_PRAGMA, is_indentation_sensitive};
+use super::compiler::abstract_tree::{AbstractTree, TokenType, BLOCK_IDENTIFIER,
+                                     INTERPOLATION_IDENTIFIER, KEYWORD_IDENTIFIER,
+                                     LIST_IDENTIFIER, MEMBER_IDENTIFIER};
+
+/// The `block` token that starts a block's node - its
+/// span is that of the block's '{'.
+fn node_token(span: Span) -> AbstractTree {
+    AbstractTree::Token(TokenType::Symbol, BLOCK_IDENTIFIER.to_string(), span)
+}
 
-fn node_token() -> AbstractTree {
-    AbstractTree::Token(TokenType::Symbol,
-                        BLOCK_IDENTIFIER.to_string(),
-                        Position(0, 0))
+/// Comments aren't part of the AbstractTree, but
+/// the parser keeps them (with their positions) so
+/// tools can find them again - e.g. to attach a doc
+/// comment to the `define` that follows it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    pub kind: CommentKind,
+    /// The comment's text without its delimiters.
+    pub text: String,
+    pub span: Span,
 }
 
-struct Parser<'a> {
-    table: HashMap<char, fn(&mut Parser) -> Result<Option<AbstractTree>>>,
-    stream: Peekable<Chars<'a>>,
-    position: Position,
-    last_char: Option<char>, // this is helpful for parsing blocks
-    return_char: Option<String> // maybe make this neater.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommentKind {
+    /// `# ...` up to the end of the line
+    Line,
+    /// `#| ... |#`, which can be nested
+    Block,
 }
 
+/// A reader is called when a token of its kind (or its
+/// dispatch sequence) is at the front of the stream - that
+/// token is still there to be consumed. It returns the
+/// expression it read, if any: `None` means it read trivia.
+///
+/// If a reader fails, its error is recorded and the parser
+/// skips ahead to the end of the line (or the next closing
+/// delimiter) before carrying on.
+pub type Reader = Rc<dyn Fn(&mut Parser) -> Result<Option<AbstractTree>>>;
+
+/// The Parser reads an AbstractTree off the source,
+/// dispatching on each token to the reader registered
+/// for it. The standard readers are registered by `new`,
+/// and more can be added - or the standard ones replaced -
+/// with `read_as` and `read_prefix`:
+///
+/// ```
+/// use acorn::parser::Parser;
+/// use acorn::compiler::abstract_tree::{AbstractTree, TokenType};
+///
+/// // `#'x` reads as `(quote x)`
+/// let parsed = Parser::new("#'x")
+///     .read_prefix("#'", |parser| {
+///         let prefix = parser.next_token().unwrap();
+///         let expression = parser.read_expression()?;
+///         let span = prefix.span.to(&expression.span());
+///         let quote = AbstractTree::Token(TokenType::Symbol, "quote".to_string(), prefix.span);
+///         Ok(Some(AbstractTree::Node(vec![quote, expression], span)))
+///     })
+///     .parse();
+/// assert!(parsed.errors.is_empty());
+/// ```
+pub struct Parser<'a> {
+    table: HashMap<TokenKind, Reader>,
+    prefix_table: HashMap<String, Reader>, // readers for TokenKind::Reader tokens
+    lexer: Lexer<'a>,
+    // only for sources that start with INDENT_PRAGMA
+    layout: Option<Layout>,
+    peeked: Option<Token>,
+    lexer_error: Option<Error>, // the error behind a peeked TokenKind::Error
+    last_closer: Option<Token>, // this is helpful for parsing blocks
+    return_closer: Option<TokenKind>, // maybe make this neater.
+    // the closers that enclosing expressions are waiting for
+    open_delimiters: Vec<TokenKind>,
+    depth: usize, // how many expressions are being read, one inside the next
+    comments: Vec<Comment>,
+    errors: Vec<Error>,
+}
+
+/// How deeply expressions can be nested - each one is read inside
+/// the reader of the one around it, so there has to be a limit for
+/// the stack to stay within.
+pub const MAX_NESTING: usize = 128;
+
+/// How tall a parsed tree can be. A standard reader makes at most
+/// three levels of it - a block is a node, with a node of lines that
+/// are nodes too - and the calls of operators are nested within
+/// MAX_NESTING, so the standard readers keep well within this. A
+/// custom reader could make any tree, so it's checked as well.
+pub const MAX_HEIGHT: usize = 4 * MAX_NESTING;
+
 impl<'a> Parser<'a> {
-    fn new(string: &'a str) -> Parser<'a> {
-        Parser {
-            table: HashMap::new(),
-            stream: string.chars().peekable(),
-            position: Position(0, 0),
-            last_char: None,
-            return_char: None,
-        }
-    }
-
-    fn advance_char(&mut self) -> Option<char> {
-        let current_char = self.stream.next();
-        self.last_char = current_char.clone();
-
-        match current_char {
-            Some(c) => {
-                if c == '\n' {
-                    // column set to 0
-                    // line incremented
-                    self.position.0 += 1;
-                    self.position.1 = 0;
+    /// A parser for `string`, with the standard readers. If
+    /// `string` starts with INDENT_PRAGMA, a line ending in `:`
+    /// opens a block of the indented lines under it - see Layout.
+    pub fn new(string: &'a str) -> Parser<'a> {
+        let parser = Parser {
+                table: HashMap::new(),
+                prefix_table: HashMap::new(),
+                lexer: Lexer::new(string),
+                layout: if is_indentation_sensitive(string) {
+                    Some(Layout::new())
                 } else {
-                    // column incremented
-                    self.position.1 += 1;
-                }
+                    None
+                },
+                peeked: None,
+                lexer_error: None,
+                last_closer: None,
+                return_closer: None,
+                open_delimiters: vec![],
+                depth: 0,
+                comments: vec![],
+                errors: vec![],
+            }
+            .read_as(TokenKind::Whitespace, no_op)
+            .read_as(TokenKind::Symbol, atom)
+            .read_as(TokenKind::Int, atom)
+            .read_as(TokenKind::Float, atom)
+            .read_as(TokenKind::Str, atom)
+            .read_as(TokenKind::OpenParen, open_paren)
+            .read_as(TokenKind::OpenSquare, open_square)
+            .read_as(TokenKind::OpenCurly, open_curly)
+            .read_as(TokenKind::LineComment, comment)
+            .read_as(TokenKind::BlockComment, comment)
+            .read_as(TokenKind::Error, lexer_error);
+        QUOTING_PREFIXES.iter()
+                        .fold(parser, |parser, &(prefix, name)| {
+                            parser.read_prefix(prefix, quoting(name))
+                        })
+    }
+
+    /// Look at the next token without consuming it.
+    pub fn peek(&mut self) -> Option<&Token> {
+        if self.peeked.is_none() {
+            let (token, error) = match self.layout {
+                Some(ref mut layout) => layout.next_token(&mut self.lexer),
+                None => self.lexer.next_token_recovering(),
+            };
+            self.peeked = token;
+            if error.is_some() {
+                self.lexer_error = error;
             }
-            None => {}
         }
-        current_char
+        self.peeked.as_ref()
     }
 
-    fn at_eof(&mut self) -> bool {
-        self.current_char().is_none()
+    pub fn peek_kind(&mut self) -> Option<TokenKind> {
+        self.peek().map(|token| token.kind)
     }
 
-    fn read_as(mut self,
-               key: char,
-               f: fn(&mut Parser) -> Result<Option<AbstractTree>>)
-               -> Parser<'a> {
-        self.table.insert(key, f);
-        self
+    pub fn next_token(&mut self) -> Option<Token> {
+        self.peek();
+        self.peeked.take()
     }
 
-    fn parse_expression(&mut self) -> Result<Option<AbstractTree>> {
-        let current_reader = self.current_reader().unwrap_or(Parser::default_parse);
-        current_reader(self)
+    pub fn at_eof(&mut self) -> bool {
+        self.peek().is_none()
     }
 
-    fn current_reader(&mut self) -> Option<fn(&mut Parser) -> Result<Option<AbstractTree>>> {
-        if self.current_char().is_none() {
-            return None;
+    /// The empty span where the next token starts.
+    pub fn point(&mut self) -> Span {
+        match self.peek() {
+            Some(token) => token.span.start_point(),
+            None => self.lexer.point(),
         }
-        self.table.get(self.stream.peek().unwrap()).map(|f| *f)
     }
 
-    fn current_char(&mut self) -> Option<&char> {
-        self.stream.peek()
+    fn last_closer_kind(&self) -> Option<TokenKind> {
+        self.last_closer.as_ref().map(|token| token.kind)
     }
 
-    fn default_parse<'b>(parser: &mut Parser) -> Result<Option<AbstractTree>> {
-        let mut chars = String::new();
-        let starting_position = parser.position.clone();
+    /// Records an error, and returns the error token
+    /// that stands in for whatever couldn't be parsed.
+    pub fn error(&mut self, span: Span, description: String) -> AbstractTree {
+        self.errors.push(Error {
+            description: description.clone(),
+            span: span.clone(),
+            notes: vec![],
+        });
+        AbstractTree::Token(TokenType::Error, description, span)
+    }
 
-        loop {
-            // refactor this to a while - let
-            // if parser.at_eof() { break; } // I don't think this is necessary
-            let current_reader = parser.current_reader();
-            match current_reader {
-                Some(_) => {
-                    break;
-                }
-                None => {
-                    match parser.advance_char() {
-                        Some(c) => chars.push(c),
-                        None => {
-                            break;
-                        }
+    /// Skips ahead to the next newline or closing delimiter,
+    /// so parsing can carry on after an error.
+    fn synchronize(&mut self) {
+        while self.peek_kind().is_some_and(|kind| !kind.is_closer()) {
+            self.next_token();
+        }
+    }
+
+    /// Registers the reader for tokens of a kind.
+    pub fn read_as<F>(mut self, key: TokenKind, f: F) -> Parser<'a>
+        where F: Fn(&mut Parser) -> Result<Option<AbstractTree>> + 'static
+    {
+        self.table.insert(key, Rc::new(f));
+        self
+    }
+
+    /// Registers the reader for a dispatch sequence, like `#[`.
+    /// The sequence is recognised wherever a token could start,
+    /// and takes precedence over the standard tokens - so `#[`
+    /// is no longer the start of a comment.
+    pub fn read_prefix<F>(mut self, prefix: &str, f: F) -> Parser<'a>
+        where F: Fn(&mut Parser) -> Result<Option<AbstractTree>> + 'static
+    {
+        self.lexer.add_prefix(prefix);
+        self.prefix_table.insert(prefix.to_string(), Rc::new(f));
+        self
+    }
+
+    /// Reads whatever is at the front of the stream with its
+    /// reader - `None` if that was trivia or the end of the source.
+    pub fn parse_expression(&mut self) -> Result<Option<AbstractTree>> {
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return Ok(None),
+        };
+        if self.depth == MAX_NESTING {
+            self.skip_expression();
+            return err_span(token.span,
+                            format!("expressions can only be nested {} deep", MAX_NESTING));
+        }
+        let reader = match token.kind {
+            TokenKind::Reader => self.prefix_table.get(&token.text).cloned(),
+            kind => self.table.get(&kind).cloned(),
+        };
+        self.depth += 1;
+        let result = match reader {
+            Some(reader) => reader(self),
+            None => {
+                self.next_token();
+                err_span(token.span, format!("unexpected '{}'", token.text))
+            }
+        };
+        self.depth -= 1;
+        result
+    }
+
+    /// Skips to the closer of whatever encloses the next
+    /// expression - so the rest of it isn't full of errors.
+    fn skip_expression(&mut self) {
+        let mut open = 0;
+        while let Some(kind) = self.peek_kind() {
+            match kind {
+                TokenKind::OpenParen | TokenKind::OpenSquare | TokenKind::OpenCurly => open += 1,
+                TokenKind::CloseParen | TokenKind::CloseSquare | TokenKind::CloseCurly => {
+                    if open == 0 {
+                        break;
                     }
+                    open -= 1;
                 }
+                _ => {}
             }
+            self.next_token();
         }
-        if chars.len() == 0 {
-            return Ok(None);
+    }
+
+    /// Reads the next expression on the line, skipping any
+    /// trivia before it - for readers that take an expression
+    /// after their dispatch sequence.
+    pub fn read_expression(&mut self) -> Result<AbstractTree> {
+        loop {
+            match self.peek_kind() {
+                Some(kind) if !kind.is_closer() => {
+                    if let Some(expression) = self.parse_expression()? {
+                        return Ok(expression);
+                    }
+                }
+                _ => return err_span(self.point(), "expected an expression".to_string()),
+            }
         }
-        let token_type = if chars.chars().next().unwrap().is_digit(10) {
-            TokenType::Int
-        } else {
-            TokenType::Symbol
+    }
+
+    /// Reads the raw source up to `terminator`, without
+    /// tokenizing it - for readers of literals, like raw
+    /// strings. Returns the text before the terminator, and
+    /// the span of everything read, terminator included.
+    pub fn read_raw_until(&mut self, terminator: &str) -> Result<(String, Span)> {
+        // a peeked token gets read again, as raw source.
+        // so do the rest of the line's, if the layout has read them.
+        let rewind_point = match self.layout {
+            Some(ref mut layout) => {
+                let point = layout.rewind_point();
+                layout.rewind();
+                point
+            }
+            None => None,
         };
-        Ok(Some(AbstractTree::Token(token_type, chars, starting_position)))
+        if let Some(token) = self.peeked.take() {
+            self.lexer.rewind(&token.span.start_point());
+            self.lexer_error = None;
+        } else if let Some(point) = rewind_point {
+            self.lexer.rewind(&point);
+        }
+        let span = self.lexer.read_until(terminator)?;
+        let text = self.lexer.text(&span);
+        Ok((text[..text.len() - terminator.len()].to_string(), span))
     }
 
-    // Newline-specific logic - top level only
-    //
-    fn remove_newlines(&mut self) {
-        self.table.insert('\n', no_op);
+    /// Reads with `read` from `point` in the source, then carries
+    /// on from where the parser was - for readers of literals with
+    /// code in them, like strings with `#{..}`. The code is read
+    /// by the same lexer, so its spans are where it is in the source.
+    pub fn read_at<T, F>(&mut self, point: &Span, read: F) -> T
+        where F: FnOnce(&mut Parser) -> T
+    {
+        let resume = self.lexer.point();
+        let peeked = self.peeked.take();
+        let lexer_error = self.lexer_error.take();
+        let last_closer = self.last_closer.take();
+        let return_closer = self.return_closer.take();
+        // the code is inside the literal, so it isn't laid out
+        let layout = self.layout.take();
+        let open_delimiters = std::mem::take(&mut self.open_delimiters);
+
+        self.lexer.rewind(point);
+        let result = read(self);
+
+        self.lexer.rewind(&resume);
+        self.peeked = peeked;
+        self.lexer_error = lexer_error;
+        self.last_closer = last_closer;
+        self.return_closer = return_closer;
+        self.layout = layout;
+        self.open_delimiters = open_delimiters;
+        result
     }
 
-    fn add_newlines(&mut self) {
-        self.table.insert('\n', newline);
+    /// Parses the whole source - carrying on after each
+    /// error from the next line or closing delimiter.
+    pub fn parse(mut self) -> Parsed {
+        let expressions = match complete_parse(&mut self) {
+            Ok(Some(AbstractTree::Node(expressions, _))) => expressions,
+            Ok(_) => vec![],
+            Err(error) => vec![self.error(error.span, error.description)],
+        };
+        // what's parsed has to be safe to walk, and to write out
+        // and read back - the top level node is one more level
+        let mut checked = vec![];
+        for expression in expressions {
+            if expression.height() < MAX_HEIGHT {
+                checked.push(expression);
+            } else {
+                let description = format!("expressions can only be nested {} deep", MAX_NESTING);
+                checked.push(self.error(expression.span(), description));
+            }
+        }
+        // the top level node covers the whole source
+        Parsed {
+            tree: AbstractTree::Node(checked, Span::default().to(&self.point())),
+            errors: self.errors,
+            comments: self.comments,
+        }
     }
 }
 
 fn no_op(parser: &mut Parser) -> Result<Option<AbstractTree>> {
-    parser.advance_char();
+    parser.next_token();
     Ok(None)
 }
 
+/// atom turns a symbol or literal token into a
+/// Token of the AbstractTree.
+fn atom(parser: &mut Parser) -> Result<Option<AbstractTree>> {
+    let token = parser.next_token().unwrap();
+    let (token_type, data) = match token.kind {
+        TokenKind::Str => return string(parser, token),
+        TokenKind::Int => (TokenType::Int, lexer::int_value(&token)?.to_string()),
+        TokenKind::Float => (TokenType::Float, token.text),
+        _ if token.text.len() > 1 && token.text.ends_with(':') => return keyword(parser, token),
+        _ => {
+            // each member access is nested in the next
+            let room = MAX_NESTING + 1 - parser.depth;
+            return member_access(token.text, token.span, room).map(Some);
+        }
+    };
+    Ok(Some(AbstractTree::Token(token_type, data, token.span)))
+}
+
+/// string reads a string literal. One with code in it, like
+/// `"total: #{count + 1}"`, is read as `(interpolate "total: "
+/// (+ count 1))` - its text, and the code of each `#{..}`.
+fn string(parser: &mut Parser, token: Token) -> Result<Option<AbstractTree>> {
+    let parts = lexer::string_parts(&token)?;
+    if let [StringPart::Text(ref text, _)] = parts[..] {
+        return Ok(Some(AbstractTree::Token(TokenType::Str, text.clone(), token.span)));
+    }
+
+    // the `interpolate` token's span is that of the opening '"'
+    let quote = Span {
+        end: Position(token.span.start.0, token.span.start.1 + 1),
+        end_offset: token.span.start_offset + 1,
+        ..token.span.clone()
+    };
+    let mut items = vec![AbstractTree::Token(TokenType::Symbol,
+                                             INTERPOLATION_IDENTIFIER.to_string(),
+                                             quote)];
+    for part in parts {
+        let item = match part {
+            StringPart::Text(text, span) => AbstractTree::Token(TokenType::Str, text, span),
+            StringPart::Code(span) => {
+                parser.read_at(&span.start_point(), |parser| interpolation(parser, &span))
+            }
+        };
+        items.push(item);
+    }
+    Ok(Some(AbstractTree::Node(items, token.span)))
+}
+
+/// Reads the code of a `#{..}`, which ends at its '}'.
+fn interpolation(parser: &mut Parser, span: &Span) -> AbstractTree {
+    parser.open_delimiters.push(TokenKind::CloseCurly);
+    let code = parse_interpolation(parser);
+    parser.open_delimiters.pop();
+    match code {
+        Ok(Some(code)) => code,
+        Ok(None) => parser.error(span.clone(), "expected an expression in '#{}'".to_string()),
+        Err(error) => parser.error(error.span, error.description),
+    }
+}
+
+/// The spans of a symbol ending in `:` without its
+/// colon, and of the colon.
+pub fn split_colon(token: &Token) -> (Span, Span) {
+    let colon = Span {
+        start: Position(token.span.end.0, token.span.end.1 - 1),
+        end: token.span.end.clone(),
+        start_offset: token.span.end_offset - 1,
+        end_offset: token.span.end_offset,
+    };
+    let name = Span {
+        end: colon.start.clone(),
+        end_offset: colon.start_offset,
+        ..token.span.clone()
+    };
+    (name, colon)
+}
+
+/// keyword reads `name: value` as `(: name value)` - a keyword
+/// argument, or a block's keyword parameter and its default.
+/// The value is the one expression after the keyword, so an
+/// infix one needs parens: `f width: (a + 1)`.
+fn keyword(parser: &mut Parser, token: Token) -> Result<Option<AbstractTree>> {
+    // newlines don't end anything inside parens and squares
+    let ignore_newlines = matches!(parser.open_delimiters.last(),
+                                   Some(&TokenKind::CloseParen) | Some(&TokenKind::CloseSquare));
+    let value = loop {
+        match parser.peek_kind() {
+            Some(TokenKind::Newline) if ignore_newlines => {
+                parser.next_token();
+            }
+            Some(kind) if !kind.is_closer() => {
+                if let Some(value) = parser.parse_expression()? {
+                    break value;
+                }
+            }
+            _ => {
+                return err_span(parser.point(),
+                                format!("expected a value after '{}'", token.text));
+            }
+        }
+    };
+    let (name_span, colon_span) = split_colon(&token);
+    let name = token.text[..token.text.len() - 1].to_string();
+    let span = token.span.to(&value.span());
+    Ok(Some(AbstractTree::Node(vec![AbstractTree::Token(TokenType::Symbol,
+                                                        KEYWORD_IDENTIFIER.to_string(),
+                                                        colon_span),
+                                    AbstractTree::Token(TokenType::Symbol, name, name_span),
+                                    value],
+                               span)))
+}
+
+/// member_access splits a dotted symbol into member accesses,
+/// from the left: `a.b.c` is read as `(. (. a b) c)`. A symbol
+/// with an empty part, like `.` or `a..b`, is left as it is.
+/// The accesses can only be nested `room` deep - past that,
+/// it's an error at the `.` that goes too deep.
+fn member_access(name: String, span: Span, room: usize) -> Result<AbstractTree> {
+    let parts = name.split('.').collect::<Vec<_>>();
+    if parts.len() < 2 || parts.iter().any(|part| part.is_empty()) {
+        return Ok(AbstractTree::Token(TokenType::Symbol, name, span));
+    }
+
+    // the span of `text`, which starts where the last one ended
+    let mut position = span.start.clone();
+    let mut offset = span.start_offset;
+    let mut next_span = |text: &str| {
+        let start = position.clone();
+        let start_offset = offset;
+        for c in text.chars() {
+            lexer::advance_position(&mut position, c);
+        }
+        offset += text.len();
+        Span {
+            start,
+            end: position.clone(),
+            start_offset,
+            end_offset: offset,
+        }
+    };
+
+    let receiver = parts[0].to_string();
+    let mut tree = AbstractTree::Token(TokenType::Symbol, receiver, next_span(parts[0]));
+    for (height, part) in parts[1..].iter().enumerate().map(|(i, part)| (i + 2, part)) {
+        let dot = AbstractTree::Token(TokenType::Symbol,
+                                      MEMBER_IDENTIFIER.to_string(),
+                                      next_span("."));
+        if height > room {
+            return dot.err(format!("expressions can only be nested {} deep", MAX_NESTING));
+        }
+        let member = AbstractTree::Token(TokenType::Symbol, part.to_string(), next_span(part));
+        let span = tree.span().to(&member.span());
+        tree = AbstractTree::Node(vec![dot, tree, member], span);
+    }
+    Ok(tree)
+}
+
+/// lexer_error turns text the lexer couldn't read
+/// into an error token.
+fn lexer_error(parser: &mut Parser) -> Result<Option<AbstractTree>> {
+    parser.next_token();
+    let error = parser.lexer_error.take().unwrap();
+    Ok(Some(parser.error(error.span, error.description)))
+}
 
-// consolidate these into one function.
-fn close_paren(parser: &mut Parser) -> Result<Option<AbstractTree>> {
-    parser.advance_char();
-    Ok(Some(AbstractTree::Token(TokenType::Flag, ")".to_string(), parser.position.clone())))
+/// The standard reader prefixes for code as data, and
+/// the forms they stand for: `'x` is read as `(quote x)`.
+/// `Parser::new` registers a reader for each, and `parse_cst`
+/// reads them as prefixes too.
+pub static QUOTING_PREFIXES: &[(&str, &str)] = &[("'", "quote"),
+                                                 ("`", "quasiquote"),
+                                                 (",", "unquote"),
+                                                 (",@", "unquote-splicing")];
+
+/// A reader for a quoting prefix, which wraps the
+/// expression after the prefix in a call to `name`.
+fn quoting(name: &'static str) -> impl Fn(&mut Parser) -> Result<Option<AbstractTree>> {
+    move |parser| {
+        let prefix = parser.next_token().unwrap();
+        let expression = parser.read_expression()?;
+        let span = prefix.span.to(&expression.span());
+        let symbol = AbstractTree::Token(TokenType::Symbol, name.to_string(), prefix.span);
+        Ok(Some(AbstractTree::Node(vec![symbol, expression], span)))
+    }
 }
 
-fn close_curly(parser: &mut Parser) -> Result<Option<AbstractTree>> {
-    parser.advance_char();
-    Ok(Some(AbstractTree::Token(TokenType::Flag, "}".to_string(), parser.position.clone())))
+/// comment records a comment on the parser and returns
+/// nothing - so to every other reader a comment looks
+/// just like whitespace.
+fn comment(parser: &mut Parser) -> Result<Option<AbstractTree>> {
+    let token = parser.next_token().unwrap();
+    let (kind, text) = match token.kind {
+        TokenKind::BlockComment => {
+            (CommentKind::Block, token.text[2..token.text.len() - 2].to_string())
+        }
+        _ => (CommentKind::Line, token.text[1..].to_string()),
+    };
+    parser.comments.push(Comment {
+        kind,
+        text,
+        span: token.span,
+    });
+    Ok(None)
 }
 
-fn newline(parser: &mut Parser) -> Result<Option<AbstractTree>> {
-    parser.advance_char();
-    Ok(Some(AbstractTree::Token(TokenType::Flag, "\n".to_string(), parser.position.clone())))
+/// The span from the first to the last of some expressions.
+fn span_of(expressions: &[AbstractTree]) -> Option<Span> {
+    match (expressions.first(), expressions.last()) {
+        (Some(first), Some(last)) => Some(first.span().to(&last.span())),
+        _ => None,
+    }
+}
+
+/// The error for a delimited expression that's missing its
+/// closing delimiter - either the file ended, or a closer
+/// belonging to an enclosing expression turned up first.
+fn unclosed(parser: &mut Parser, opener: &Span, name: &str) -> AbstractTree {
+    let description = match parser.peek() {
+        // the layout's closers have no text
+        Some(token) if token.text.is_empty() => {
+            format!("{} was not closed before the end of its block", name)
+        }
+        Some(token) => format!("{} was not closed before '{}'", name, token.text),
+        None => format!("hit end of file while reading {}", name),
+    };
+    let span = opener.to(&parser.point());
+    parser.error(span, description)
 }
 
 macro_rules! define_expression_parser {
@@ -155,56 +609,94 @@ macro_rules! define_expression_parser {
         advance: $should_advance: expr,
         top_level: $top_level: expr,
         ignore_newlines: $should_ignore_newlines: expr,
+        infix: $infix: expr,
     ) => {
 
         fn $a(parser: &mut Parser) -> Result<Option<AbstractTree>> {
-            let starting_position = parser.position.clone();
-            if $should_advance {
-                parser.advance_char();
-            }
+            // the opening delimiter, if there is one.
+            let opener = if $should_advance {
+                parser.next_token().map(|token| token.span)
+            } else {
+                None
+            };
             let mut accumulator = vec![];
             loop {
-
-                if $should_ignore_newlines { parser.remove_newlines() }
-
-                let expression = parser.parse_expression();
-
-                if $should_ignore_newlines { parser.add_newlines() }
-
-                match expression {
-                    Ok(Some(AbstractTree::Token(TokenType::Flag, s, p))) => {
-                        if $close.contains(&s) {
-                            break;
-                        } else if $allow.contains(&s) {
-                            parser.return_char = Some(s);
-                            break;
+                match parser.peek_kind() {
+                    Some(TokenKind::Newline) if $should_ignore_newlines => {
+                        parser.next_token();
+                    }
+                    Some(kind) if $close.contains(&kind) => {
+                        parser.last_closer = parser.next_token();
+                        break;
+                    }
+                    Some(kind) if $allow.contains(&kind) => {
+                        parser.last_closer = parser.next_token();
+                        parser.return_closer = Some(kind);
+                        break;
+                    }
+                    Some(kind) if parser.open_delimiters.contains(&kind) => {
+                        // an enclosing expression's closer - this
+                        // expression ends here, without its own.
+                        parser.last_closer = None;
+                        if $top_level {
+                            parser.return_closer = Some(kind);
                         } else {
-                            // this way I can ensure ( } doesnt happen
-                            return err_position(starting_position,
-                                                format!("encountered incorrect flag '{}',\
-                                                        at position {:?}, while reading \
-                                                        {}", s, p, $name))
+                            let start = opener.clone().unwrap_or_else(|| parser.point());
+                            let error = unclosed(parser, &start, $name);
+                            accumulator.push(error);
+                        }
+                        break;
+                    }
+                    Some(kind) if kind.is_closer() => {
+                        // this way I can ensure ( } doesnt happen
+                        let token = parser.next_token().unwrap();
+                        let error = parser.error(token.span,
+                                                 format!("encountered unexpected '{}' \
+                                                         while reading {}", token.text, $name));
+                        accumulator.push(error);
+                    }
+                    Some(_) => {
+                        match parser.parse_expression() {
+                            Ok(Some(a)) => accumulator.push(a),
+                            Ok(None) => {}
+                            Err(error) => {
+                                accumulator.push(parser.error(error.span, error.description));
+                                parser.synchronize();
+                            }
                         }
                     }
-                    Ok(Some(a)) => accumulator.push(a),
-                    Ok(None) => {
-                        if parser.at_eof() {
-                            if $top_level { break; }
-
-                            return err_position(starting_position,
-                                                format!("hit end of file \
-                                                        while reading {}", $name))
+                    None => {
+                        parser.last_closer = None;
+                        if $top_level {
+                            break;
                         }
+
+                        let start = opener.clone().unwrap_or_else(|| parser.point());
+                        let error = unclosed(parser, &start, $name);
+                        accumulator.push(error);
+                        break;
                     }
-                    error @ Err(_) => return error,
                 }
             }
 
-            if accumulator.is_empty() { // This might be a bug // && parser.at_eof() {
+            // `()` is an empty node, but an empty line is nothing.
+            if accumulator.is_empty() && opener.is_none() {
                 return Ok(None)
             }
 
-            Ok(Some(AbstractTree::Node(accumulator, starting_position)))
+            // delimited expressions cover their delimiters too
+            let span = match (opener, parser.last_closer.as_ref()) {
+                (Some(opener), Some(closer)) => opener.to(&closer.span),
+                (Some(opener), None) => match accumulator.last() {
+                    Some(last) => opener.to(&last.span()),
+                    None => opener.to(&parser.point()),
+                },
+                (None, _) => span_of(&accumulator).unwrap(),
+            };
+            if !$infix {
+                return Ok(Some(AbstractTree::Node(accumulator, span)));
+            }
+            Ok(Some(rewrite_line(parser, accumulator, span)))
         }
 
     }
@@ -213,348 +705,1115 @@ macro_rules! define_expression_parser {
 macro_rules! define_aggregate_parser {
 
     ( $name: ident
-      parser: $inner_parser: expr,
-      final_char: $final_char: expr, ) => {
+      parser: $inner_parser: expr, ) => {
 
         fn $name(parser: &mut Parser) -> Result<Option<AbstractTree>> {
-            let starting_position = parser.position.clone();
             let mut accumulator = vec![];
             loop {
-                let expression = try!($inner_parser(parser));
+                let expression = $inner_parser(parser)?;
                 match expression {
-                    Some(AbstractTree::Token(_, _, _)) => {
-                        panic!("tokens shouldn't be returned by
-                               the inner parser of an aggregate parser");
+                    Some(token @ AbstractTree::Token(_, _, _)) => {
+                        return token.err("tokens shouldn't be returned by \
+                                          the inner parser of an aggregate parser"
+                                             .to_string());
                     }
                     Some(a) => {
                         accumulator.push(a);
-                        if parser.return_char.take() == Some($final_char.to_string()) {
+                        if parser.return_closer.take().is_some() {
                             break;
                         }
                     }
                     None => {
-                        if parser.at_eof() {
+                        // an empty line can still be the last one.
+                        if parser.return_closer.take().is_some() || parser.at_eof() {
                             break;
                         }
                     }
                 }
             }
-            Ok(Some(AbstractTree::Node(accumulator, starting_position)))
+            let span = match span_of(&accumulator) {
+                Some(span) => span,
+                None => parser.point(),
+            };
+            Ok(Some(AbstractTree::Node(accumulator, span)))
         }
 
     }
 }
 
-define_expression_parser! { open_paren
+define_expression_parser! { parse_paren
     name: "an open paren",
-    close: ")",
-    allow: "",
+    close: [TokenKind::CloseParen],
+    allow: [],
     advance: true,
     top_level: false,
     ignore_newlines: true,
+    infix: true,
+}
+
+// each item of a list literal is an expression of its own,
+// so an infix operator needs parens there: `[(a + b) c]`.
+define_expression_parser! { parse_square
+    name: "a list",
+    close: [TokenKind::CloseSquare],
+    allow: [],
+    advance: true,
+    top_level: false,
+    ignore_newlines: true,
+    infix: false,
+}
+
+// the code in a string's `#{..}` - read_at starts
+// it after the '#{', so it ends at the '}'.
+define_expression_parser! { parse_interpolation
+    name: "an interpolation",
+    close: [TokenKind::CloseCurly],
+    allow: [],
+    advance: false,
+    top_level: false,
+    ignore_newlines: true,
+    infix: true,
 }
 
 // this is used for parsting top level expressions
 // i.e. expressions without any nesting or parens.
 define_expression_parser! { parse_whole_expression
     name: "top level expressions",
-    close: "\n",
-    allow: "",
+    close: [TokenKind::Newline],
+    allow: [],
     advance: false,
     top_level: true,
     ignore_newlines: false,
+    infix: true,
 }
 
 // this is used for the first expression within a block - it parses differently
-// depending on whether or not it terminates with a '}' or a '\n'
+// depending on whether or not it terminates with a '}' or a '\n'. Its infix
+// operators are rewritten by read_block, once it's found the parameters.
 define_expression_parser! { parse_whole_expression_block_start
     name: "expressions of a block",
-    close: "}\n", // either one of these will work
-    allow: "",
+    close: [TokenKind::CloseCurly, TokenKind::Newline], // either one of these will work
+    allow: [],
     advance: false,
     top_level: true,
     ignore_newlines: false,
+    infix: false,
 }
 
-// this is used for the first expression within a block - it parses differently
-// depending on whether or not it terminates with a '}' or a '\n'
+// this is used for the rest of the expressions within a block, the
+// last of which is followed by the '}'
 define_expression_parser! { parse_whole_expression_block
     name: "expressions of a block",
-    close: "\n", // either one of these will work
-    allow: "}",
+    close: [TokenKind::Newline],
+    allow: [TokenKind::CloseCurly],
     advance: false,
     top_level: true,
     ignore_newlines: false,
+    infix: true,
 }
 
 define_aggregate_parser! { complete_parse
     parser: parse_whole_expression,
-    final_char: "}",
 }
 
 define_aggregate_parser! { complete_parse_block
     parser: parse_whole_expression_block,
-    final_char: "}",
 }
 
+fn open_paren(parser: &mut Parser) -> Result<Option<AbstractTree>> {
+    parser.open_delimiters.push(TokenKind::CloseParen);
+    let expression = parse_paren(parser);
+    parser.open_delimiters.pop();
+    expression
+}
+
+/// A list literal `[a b c]` is read as a call to `list`,
+/// as a block is a call to `block`.
+fn open_square(parser: &mut Parser) -> Result<Option<AbstractTree>> {
+    let opener = parser.peek().unwrap().span.clone();
+    parser.open_delimiters.push(TokenKind::CloseSquare);
+    let expression = parse_square(parser);
+    parser.open_delimiters.pop();
+    match expression? {
+        Some(AbstractTree::Node(mut items, span)) => {
+            let list = AbstractTree::Token(TokenType::Symbol, LIST_IDENTIFIER.to_string(), opener);
+            items.insert(0, list);
+            Ok(Some(AbstractTree::Node(items, span)))
+        }
+        expression => Ok(expression),
+    }
+}
 
 fn open_curly(parser: &mut Parser) -> Result<Option<AbstractTree>> {
-    let starting_position = parser.position.clone();
-    parser.advance_char();
-    let mut expression = try!(parse_whole_expression_block_start(parser));
-    loop {
-        match expression {
-            Some(AbstractTree::Token(_, _, _)) => {
-                panic!("inner parser to an aggregater should not return tokens")
-            }
-            Some(_) => {
-                break;
-            } // this can be unwrapped safely in the future.
-            None => {
-                if parser.at_eof() {
-                    return err_position(starting_position,
-                                        format!("hit end of file \
-                                                while reading a block"));
-                }
+    let opener = parser.next_token().unwrap().span;
+    parser.open_delimiters.push(TokenKind::CloseCurly);
+    let block = read_block(parser, opener);
+    parser.open_delimiters.pop();
+    block
+}
 
-                // Make this logic better.
-                if parser.last_char == Some('\n') {
-                    // position will not be used.
-                    expression = Some(AbstractTree::Node(vec![], Position(0, 0)));
-                    break;
-                } else {
-                    expression = try!(parse_whole_expression_block_start(parser));
-                }
-            }
+/// Reads the rest of a block, after its '{'. Its first line is
+/// either its parameters, like `{ a b` and a newline, or its
+/// body, like `{ times 2 3 }`. A `|` marks the parameters
+/// instead, and the body can follow it: `{ a | times a 2 }`.
+fn read_block(parser: &mut Parser, opener: Span) -> Result<Option<AbstractTree>> {
+    let mut first_line = match parse_whole_expression_block_start(parser)? {
+        Some(AbstractTree::Node(items, _)) => items,
+        Some(token) => vec![token],
+        None => vec![],
+    };
+    parser.return_closer = None;
+    let parameters = match first_line.iter().position(is_parameter_separator) {
+        Some(bar) => {
+            let body = first_line.split_off(bar + 1);
+            first_line.pop();
+            Some(std::mem::replace(&mut first_line, body))
+        }
+        None => None,
+    };
+    // only the first `|` separates - another isn't an argument
+    for item in &mut first_line {
+        if is_parameter_separator(item) {
+            let span = item.span();
+            *item = parser.error(span, "a block can only have one '|'".to_string());
         }
     }
-    if parser.last_char.is_none() {
-        return err_position(starting_position,
-                            format!("hit end of file while reading a block"));
-    } else if parser.last_char == Some('\n') {
-        let expression = expression.unwrap();
-        let position = expression.position();
 
+    if parser.last_closer.is_none() {
+        // the block isn't closed: keep what there is as its body
+        let mut arguments = vec![node_token(opener.clone())];
+        arguments.extend(parameters.map(|parameters| check_parameters(parser, parameters))
+                                   .unwrap_or_default());
+        let mut body = first_line;
+        body.push(unclosed(parser, &opener, "a block"));
+        let span = span_of(&body).unwrap();
+        arguments.push(AbstractTree::Node(body, span));
+        return Ok(Some(AbstractTree::Node(arguments, opener.to(&parser.point()))));
+    }
 
-        let mut arguments = match expression {
-            AbstractTree::Node(vector, _) => vector,
-            _ => panic!("fetching arguments on not a node"),
+    if parser.last_closer_kind() == Some(TokenKind::Newline) {
+        // without a `|`, the whole first line is the parameters -
+        // so an empty one means the block has none.
+        let (parameters, first_line) = match parameters {
+            Some(parameters) => (parameters, first_line),
+            None => (first_line, vec![]),
         };
-
-        arguments.insert(0, node_token());
+        let mut arguments = vec![node_token(opener.clone())];
+        arguments.extend(check_parameters(parser, parameters));
 
         // you might not need this iteration
-        let mut block = try!(complete_parse_block(parser));
+        let mut block = complete_parse_block(parser)?;
         loop {
             match block {
-                Some(AbstractTree::Token(_, _, _)) => {
-                    panic!("inner parser to an aggregater should not return tokens")
+                Some(token @ AbstractTree::Token(_, _, _)) => {
+                    return token.err("inner parser to an aggregater should not return tokens"
+                                         .to_string());
                 }
                 Some(a) => {
                     arguments.push(a);
                     break;
                 }
                 None => {
-                    if parser.at_eof() {
-                        return err_position(starting_position,
-                                            format!("hit end of file \
-                                                    while reading a block"));
-                    }
-                    block = try!(complete_parse_block(parser));
+                    block = complete_parse_block(parser)?;
                 }
             }
         }
 
-        Ok(Some(AbstractTree::Node(arguments, position)))
+        // anything after the `|` is the body's first line
+        if let Some(span) = span_of(&first_line) {
+            let line = rewrite_line(parser, first_line, span);
+            let body = arguments.last_mut();
+            if let Some(&mut AbstractTree::Node(ref mut body, ref mut span)) = body {
+                *span = if body.is_empty() {
+                    line.span()
+                } else {
+                    line.span().to(span)
+                };
+                body.insert(0, line);
+            }
+        }
+
+        if parser.last_closer_kind() != Some(TokenKind::CloseCurly) {
+            let error = unclosed(parser, &opener, "a block");
+            if let Some(&mut AbstractTree::Node(ref mut body, ref mut span)) = arguments.last_mut() {
+                *span = span.to(&error.span());
+                body.push(error);
+            }
+            return Ok(Some(AbstractTree::Node(arguments, opener.to(&parser.point()))));
+        }
+        let span = opener.to(&parser.last_closer.as_ref().unwrap().span);
+        Ok(Some(AbstractTree::Node(arguments, span)))
     } else {
-        assert_eq!(parser.last_char, Some('}'));
+        let closer = match parser.last_closer {
+            Some(ref token) if token.kind == TokenKind::CloseCurly => token.span.clone(),
+            _ => return err_span(parser.point(), "expected '}' to close a block".to_string()),
+        };
+        let line = span_of(&first_line).map(|span| rewrite_line(parser, first_line, span));
+        let mut arguments = vec![node_token(opener.clone())];
+        match parameters {
+            // a body after a `|` is read like the lines of a longer block.
+            Some(parameters) => {
+                arguments.extend(check_parameters(parser, parameters));
+                let span = line.as_ref().map_or(closer.start_point(), |line| line.span());
+                arguments.push(AbstractTree::Node(line.into_iter().collect(), span));
+            }
+            // an empty `{ }` is a block without a body.
+            None => {
+                arguments.push(line.unwrap_or_else(|| {
+                                       AbstractTree::Node(vec![], closer.start_point())
+                                   }))
+            }
+        }
 
-        let expression = expression.unwrap();
-        let arguments = vec![node_token(), expression];
+        Ok(Some(AbstractTree::Node(arguments, opener.to(&closer))))
+    }
+}
+
+fn is_parameter_separator(expression: &AbstractTree) -> bool {
+    matches!(*expression, AbstractTree::Token(TokenType::Symbol, ref name, _) if name == "|")
+}
 
-        Ok(Some(AbstractTree::Node(arguments, starting_position)))
+/// A block's parameters have to be plain symbols, or keyword
+/// parameters like `width: 1` - any other expression is
+/// replaced with an error.
+fn check_parameters(parser: &mut Parser, parameters: Vec<AbstractTree>) -> Vec<AbstractTree> {
+    parameters.into_iter()
+              .map(|parameter| match parameter {
+                  AbstractTree::Token(TokenType::Symbol, _, _) |
+                  AbstractTree::Token(TokenType::Error, _, _) => parameter,
+                  AbstractTree::Node(ref items, _)
+                      if matches!(items.first(),
+                                  Some(&AbstractTree::Token(TokenType::Symbol, ref name, _))
+                                      if name == KEYWORD_IDENTIFIER) => parameter,
+                  _ => {
+                      parser.error(parameter.span(),
+                                   "a block's parameters have to be plain names".to_string())
+                  }
+              })
+              .collect()
+}
+
+/// Rewrites the infix operators of a line, like `a + b`,
+/// into calls - a line that can't be rewritten is an error.
+fn rewrite_line(parser: &mut Parser, expressions: Vec<AbstractTree>, span: Span) -> AbstractTree {
+    // the line is read by the reader at `depth`, so its
+    // expressions can be nested up to MAX_NESTING from there
+    let room = MAX_NESTING + 1 - parser.depth;
+    match operators::rewrite(expressions, span.clone(), room) {
+        Ok(node) => node,
+        Err(error) => {
+            let error = parser.error(error.span, error.description);
+            AbstractTree::Node(vec![error], span)
+        }
     }
 }
 
+/// The full result of parsing some source.
+pub struct Parsed {
+    /// Where something couldn't be parsed, the tree has an
+    /// error token (`TokenType::Error`) in its place.
+    pub tree: AbstractTree,
+    /// Every error found, in the order they were found.
+    pub errors: Vec<Error>,
+    /// Every comment in the source, in the order they appear.
+    pub comments: Vec<Comment>,
+}
 
+/// parse_recovering parses as much of the source as it can -
+/// rather than stopping at the first error, it carries on
+/// after each one from the next line or closing delimiter.
+pub fn parse_recovering(string: &str) -> Parsed {
+    Parser::new(string).parse()
+}
 
 pub fn parse(string: &str) -> Result<Option<AbstractTree>> {
-    let mut parser = Parser::new(string)
-                         .read_as('\n', newline)
-                         .read_as(' ', no_op)
-                         .read_as(')', close_paren)
-                         .read_as('(', open_paren)
-                         .read_as('}', close_curly)
-                         .read_as('{', open_curly);
-    complete_parse(&mut parser)
+    parse_with_comments(string).map(|(tree, _)| tree)
+}
+
+/// Like `parse`, but also returns every comment in
+/// the source, in the order they appear.
+pub fn parse_with_comments(string: &str) -> Result<(Option<AbstractTree>, Vec<Comment>)> {
+    let mut parsed = parse_recovering(string);
+    if parsed.errors.is_empty() {
+        Ok((Some(parsed.tree), parsed.comments))
+    } else {
+        Err(parsed.errors.remove(0))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use parser::parse;
+    use parser::{parse, parse_recovering, parse_with_comments, Comment, CommentKind, Parser,
+                 MAX_HEIGHT, MAX_NESTING};
+    use compiler::abstract_tree::AbstractTree;
     use compiler::abstract_tree::AbstractTree::*;
     use compiler::abstract_tree::TokenType::*;
-    use utils::{Position, Error};
+    use lexer::advance_position;
+    use utils::{Position, Span, Error};
+    use compiler::compile;
+    use parser::cst::parse_cst;
+
+    /// The span between two byte offsets of `source`.
+    fn span(source: &str, start: usize, end: usize) -> Span {
+        let position = |offset: usize| {
+            let mut position = Position(0, 0);
+            for c in source[..offset].chars() {
+                advance_position(&mut position, c);
+            }
+            position
+        };
+        Span {
+            start: position(start),
+            end: position(end),
+            start_offset: start,
+            end_offset: end,
+        }
+    }
+
+    /// The concrete syntax tree prints back as the source,
+    /// and converts to the same tree the parser reads.
+    fn assert_round_trips(source: &str) {
+        let cst = parse_cst(source);
+        assert_eq!(cst.tree.to_string(), source);
+        assert_eq!(cst.tree.to_abstract_tree().tree, parse_recovering(source).tree);
+    }
+
+    /// `source` parses to `expected`, written by `to_sexp` with
+    /// offsets - and every span's lines and columns are right.
+    fn assert_parses_to(source: &str, expected: &str) {
+        assert_round_trips(source);
+        let tree = parse(source).unwrap().unwrap();
+        assert_eq!(tree.to_sexp(true), expected);
+        assert_eq!(AbstractTree::from_sexp(expected, Some(source)).unwrap(), tree);
+    }
 
     macro_rules! assert_parses {
-        ( $str: expr, $( $node: expr ),* ) => {
+        ( $str: expr, $( $node: expr ),* ) => {{
+            assert_round_trips($str);
             assert_eq!(parse($str).unwrap().unwrap(),
                        Node(vec![
                             $( $node ),*
-                       ], Position(0,0)))
-        }
+                       ], span($str, 0, $str.len())))
+        }}
     }
 
     #[test]
     fn test_parse_symbol() {
-        assert_parses!("symbol",
-                       Node(vec![Token(Symbol, "symbol".to_string(), Position(0, 0))],
-                            Position(0, 0)));
+        let s = "symbol";
+        assert_parses!(s,
+                       Node(vec![Token(Symbol, "symbol".to_string(), span(s, 0, 6))],
+                            span(s, 0, 6)));
     }
 
     #[test]
     fn test_parse_parentheses() {
-        assert_parses!("(hi there)",
-                       Node(vec![Node(vec![Token(Symbol, "hi".to_string(), Position(0, 1)),
-                                           Token(Symbol, "there".to_string(), Position(0, 4))],
-                                      Position(0, 0))],
-                            Position(0, 0)));
+        let s = "(hi there)";
+        assert_parses!(s,
+                       Node(vec![Node(vec![Token(Symbol, "hi".to_string(), span(s, 1, 3)),
+                                           Token(Symbol, "there".to_string(), span(s, 4, 9))],
+                                      span(s, 0, 10))],
+                            span(s, 0, 10)));
 
         // Try with two levels
-        assert_parses!("(hi (one) there)",
-                       Node(vec![Node(vec![Token(Symbol, "hi".to_string(), Position(0, 1)),
+        let s = "(hi (one) there)";
+        assert_parses!(s,
+                       Node(vec![Node(vec![Token(Symbol, "hi".to_string(), span(s, 1, 3)),
                                            Node(vec![Token(Symbol,
                                                            "one".to_string(),
-                                                           Position(0, 5))],
-                                                Position(0, 4)),
-                                           Token(Symbol, "there".to_string(), Position(0, 10))],
-                                      Position(0, 0))],
-                            Position(0, 0)));
+                                                           span(s, 5, 8))],
+                                                span(s, 4, 9)),
+                                           Token(Symbol, "there".to_string(), span(s, 10, 15))],
+                                      span(s, 0, 16))],
+                            span(s, 0, 16)));
     }
 
     #[test]
     fn test_fail_parse_parentheses() {
-        match parse("(hi there") {
+        let s = "(hi there";
+        match parse(s) {
             Ok(_) => panic!("I'm assertng this should not parse correctly"),
-            Err(Error { description, position: _ }) => {
+            Err(Error { description, span: error_span, .. }) => {
                 assert_eq!("hit end of file while reading an open paren".to_string(),
                            description);
+                assert_eq!(span(s, 0, 9), error_span);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_string() {
+        let s = "print \"hello world\"";
+        assert_parses!(s,
+                       Node(vec![Token(Symbol, "print".to_string(), span(s, 0, 5)),
+                                 Token(Str, "hello world".to_string(), span(s, 6, 19))],
+                            span(s, 0, 19)));
+    }
+
+    #[test]
+    fn test_parse_string_escapes() {
+        let s = r#""a\n\t\"\\\u{e9}""#;
+        assert_parses!(s,
+                       Node(vec![Token(Str, "a\n\t\"\\\u{e9}".to_string(), span(s, 0, 17))],
+                            span(s, 0, 17)));
+    }
+
+    #[test]
+    fn test_fail_parse_string() {
+        let s = "print (\"hi)";
+        match parse(s) {
+            Ok(_) => panic!("I'm assertng this should not parse correctly"),
+            Err(Error { description, span: error_span, .. }) => {
+                assert_eq!("hit end of file while reading a string".to_string(),
+                           description);
+                assert_eq!(span(s, 7, 11), error_span);
+            }
+        }
+
+        let s = "\"one \\q\"";
+        match parse(s) {
+            Ok(_) => panic!("I'm assertng this should not parse correctly"),
+            Err(Error { description, span: error_span, .. }) => {
+                assert_eq!("unknown escape sequence '\\q' in a string".to_string(),
+                           description);
+                assert_eq!(span(s, 5, 7), error_span);
+            }
+        }
+
+        assert!(parse("\"\\u{d800}\"").is_err());
+        assert!(parse("\"\\u{1234567}\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_numbers() {
+        let s = "add 4 4.3 1e9 2.5E-3";
+        assert_parses!(s,
+                       Node(vec![Token(Symbol, "add".to_string(), span(s, 0, 3)),
+                                 Token(Int, "4".to_string(), span(s, 4, 5)),
+                                 Token(Float, "4.3".to_string(), span(s, 6, 9)),
+                                 Token(Float, "1e9".to_string(), span(s, 10, 13)),
+                                 Token(Float, "2.5E-3".to_string(), span(s, 14, 20))],
+                            span(s, 0, 20)));
+    }
+
+    #[test]
+    fn test_fail_parse_float() {
+        let s = "add 1 4.3.2";
+        match parse(s) {
+            Ok(_) => panic!("I'm assertng this should not parse correctly"),
+            Err(Error { description, span: error_span, .. }) => {
+                assert_eq!("malformed float literal '4.3.2'".to_string(), description);
+                assert_eq!(span(s, 6, 11), error_span);
+            }
+        }
+
+        assert!(parse("4.").is_err());
+        assert!(parse("1e").is_err());
+        assert!(parse("1e+").is_err());
+        assert!(parse("1.e5").is_err());
+        assert_eq!(parse("1e400").err().unwrap().description,
+                   "float literal '1e400' is out of range");
+    }
+
+    #[test]
+    fn test_parse_integers() {
+        let s = "add 0xFF 0b1010 0o17 1_000_000 -5 -0x10 -2.5";
+        assert_parses!(s,
+                       Node(vec![Token(Symbol, "add".to_string(), span(s, 0, 3)),
+                                 Token(Int, "255".to_string(), span(s, 4, 8)),
+                                 Token(Int, "10".to_string(), span(s, 9, 15)),
+                                 Token(Int, "15".to_string(), span(s, 16, 20)),
+                                 Token(Int, "1000000".to_string(), span(s, 21, 30)),
+                                 Token(Int, "-5".to_string(), span(s, 31, 33)),
+                                 Token(Int, "-16".to_string(), span(s, 34, 39)),
+                                 Token(Float, "-2.5".to_string(), span(s, 40, 44))],
+                            span(s, 0, 44)));
+
+        // a '-' on its own is still a symbol
+        let s = "- x";
+        assert_parses!(s,
+                       Node(vec![Token(Symbol, "-".to_string(), span(s, 0, 1)),
+                                 Token(Symbol, "x".to_string(), span(s, 2, 3))],
+                            span(s, 0, 3)));
+
+        assert_parses!("-9223372036854775808",
+                       Node(vec![Token(Int,
+                                       "-9223372036854775808".to_string(),
+                                       span("-9223372036854775808", 0, 20))],
+                            span("-9223372036854775808", 0, 20)));
+    }
+
+    #[test]
+    fn test_fail_parse_integers() {
+        let error = parse("add 12abc").err().unwrap();
+        assert_eq!(error.description, "malformed integer literal '12abc'");
+        assert_eq!(error.span, span("add 12abc", 4, 9));
+
+        let error = parse("x
+ 99999999999999999999999").err().unwrap();
+        assert_eq!(error.description,
+                   "integer literal '99999999999999999999999' is out of range");
+        assert_eq!(error.span.start, Position(1, 1));
+
+        assert_eq!(parse("0x").err().unwrap().description, "malformed integer literal '0x'");
+        assert_eq!(parse("0b12").err().unwrap().description,
+                   "malformed integer literal '0b12'");
+        assert_eq!(parse("0x8000000000000000").err().unwrap().description,
+                   "integer literal '0x8000000000000000' is out of range");
+        assert_eq!(parse("-5x").err().unwrap().description, "malformed integer literal '-5x'");
+
+        // a '_' has to be between two digits
+        for &literal in &["1_", "1__0", "0x_ff", "0b1_", "-1__2"] {
+            assert_eq!(parse(literal).err().unwrap().description,
+                       format!("malformed integer literal '{}'", literal));
+        }
+        assert!(parse("1_000 0xff_ff -1_2").is_ok());
+    }
+
+    #[test]
+    fn test_parse_comments() {
+        let s = "hi # there\n(one # two\n three) #| four |# five";
+        assert_parses!(s,
+                       Node(vec![Token(Symbol, "hi".to_string(), span(s, 0, 2))],
+                            span(s, 0, 2)),
+                       Node(vec![Node(vec![Token(Symbol, "one".to_string(), span(s, 12, 15)),
+                                           Token(Symbol, "three".to_string(), span(s, 23, 28))],
+                                      span(s, 11, 29)),
+                                 Token(Symbol, "five".to_string(), span(s, 41, 45))],
+                            span(s, 11, 45)));
+
+        let s = "# one\n#| two #| three |# |#";
+        let (_, comments) = parse_with_comments(s).unwrap();
+        assert_eq!(comments,
+                   vec![Comment {
+                            kind: CommentKind::Line,
+                            text: " one".to_string(),
+                            span: span(s, 0, 5),
+                        },
+                        Comment {
+                            kind: CommentKind::Block,
+                            text: " two #| three |# ".to_string(),
+                            span: span(s, 6, 27),
+                        }]);
+    }
+
+    #[test]
+    fn test_parses_block_with_comments() {
+        assert_parses_to("map { a # the parameter\n# a whole line\ntimes a 2 # and the body\n}",
+                         "((map@0-3 (block@4-5 a@6-7 \
+                          ((times@39-44 a@45-46 2@47-48)@39-48)@39-48)@4-65)@0-65)@0-65");
+    }
+
+    #[test]
+    fn test_fail_parse_block_comment() {
+        let s = "one #| two #| three |#";
+        match parse(s) {
+            Ok(_) => panic!("I'm assertng this should not parse correctly"),
+            Err(Error { description, span: error_span, .. }) => {
+                assert_eq!("hit end of file while reading a block comment".to_string(),
+                           description);
+                assert_eq!(span(s, 4, 22), error_span);
             }
         }
     }
 
     #[test]
     fn test_two_lines_of_code() {
-        assert_parses!("hi there\n(one two)",
-                       Node(vec![Token(Symbol, "hi".to_string(), Position(0, 0)),
-                                 Token(Symbol, "there".to_string(), Position(0, 3))],
-                            Position(0, 0)),
+        let s = "hi there\n(one two)";
+        assert_parses!(s,
+                       Node(vec![Token(Symbol, "hi".to_string(), span(s, 0, 2)),
+                                 Token(Symbol, "there".to_string(), span(s, 3, 8))],
+                            span(s, 0, 8)),
                        Node(vec![
                     Node(vec![
-                        Token(Symbol, "one".to_string(), Position(1, 1)),
-                        Token(Symbol, "two".to_string(), Position(1, 5))
-                    ], Position(1,0)),
+                        Token(Symbol, "one".to_string(), span(s, 10, 13)),
+                        Token(Symbol, "two".to_string(), span(s, 14, 17))
+                    ], span(s, 9, 18)),
                 ],
-                            Position(1, 0)))
+                            span(s, 9, 18)))
     }
 
     #[test]
     fn test_parse_parentheses_with_newline() {
-        assert_parses!("(hi \n\n\nthere)",
-                       Node(vec![Node(vec![Token(Symbol, "hi".to_string(), Position(0, 1)),
-                                           Token(Symbol, "there".to_string(), Position(3, 0))],
-                                      Position(0, 0))],
-                            Position(0, 0)));
+        let s = "(hi \n\n\nthere)";
+        assert_parses!(s,
+                       Node(vec![Node(vec![Token(Symbol, "hi".to_string(), span(s, 1, 3)),
+                                           Token(Symbol, "there".to_string(), span(s, 7, 12))],
+                                      span(s, 0, 13))],
+                            span(s, 0, 13)));
     }
 
     #[test]
     fn test_parses_block_simple() {
-        assert_parses!("{ one two }",
-                       Node(vec![Node(vec![Token(Symbol, "block".to_string(), Position(0, 0)),
+        let s = "{ one two }";
+        assert_parses!(s,
+                       Node(vec![Node(vec![Token(Symbol, "block".to_string(), span(s, 0, 1)),
                                            Node(vec![Token(Symbol,
                                                            "one".to_string(),
-                                                           Position(0, 2)),
+                                                           span(s, 2, 5)),
                                                      Token(Symbol,
                                                            "two".to_string(),
-                                                           Position(0, 6))],
-                                                Position(0, 1))],
-                                      Position(0, 0))],
-                            Position(0, 0)))
+                                                           span(s, 6, 9))],
+                                                span(s, 2, 9))],
+                                      span(s, 0, 11))],
+                            span(s, 0, 11)))
     }
 
     #[test]
     fn test_parses_block_newline() {
-        assert_parses!("{\none two\n}",
-                       Node(vec![Node(vec![Token(Symbol, "block".to_string(), Position(0, 0)),
+        let s = "{\none two\n}";
+        assert_parses!(s,
+                       Node(vec![Node(vec![Token(Symbol, "block".to_string(), span(s, 0, 1)),
                                            Node(vec![
-                                        Node(vec![Token(Symbol, "one".to_string(), Position(1, 0)),
-                                                Token(Symbol, "two".to_string(), Position(1, 4))
-                                        ], Position(1, 0)),
+                                        Node(vec![Token(Symbol, "one".to_string(), span(s, 2, 5)),
+                                                Token(Symbol, "two".to_string(), span(s, 6, 9))
+                                        ], span(s, 2, 9)),
                                       ],
-                                                Position(1, 0))],
-                                      Position(0, 0))],
-                            Position(0, 0)))
+                                                span(s, 2, 9))],
+                                      span(s, 0, 11))],
+                            span(s, 0, 11)))
     }
 
+    #[test]
+    fn test_parses_empty_block() {
+        let s = "{ }";
+        assert_parses!(s,
+                       Node(vec![Node(vec![Token(Symbol, "block".to_string(), span(s, 0, 1)),
+                                           Node(vec![], span(s, 2, 2))],
+                                      span(s, 0, 3))],
+                            span(s, 0, 3)))
+    }
 
     #[test]
     fn test_parses_block_complete() {
-        assert_parses!("map { a\ntimes a 2\nreturn 4\n\n}",
-                       Node(vec![Token(Symbol, "map".to_string(), Position(0, 0)),
-                                 Node(vec![Token(Symbol, "block".to_string(), Position(0, 0)),
-                                           Token(Symbol, "a".to_string(), Position(0, 6)),
-                                           Node(vec![
-                                    Node(vec![Token(Symbol, "times".to_string(), Position(1, 0)),
-                                              Token(Symbol, "a".to_string(), Position(1, 6)),
-                                              Token(Int, "2".to_string(), Position(1, 8)),
-                                    ], Position(1, 0)),
-                                    Node(vec![Token(Symbol, "return".to_string(), Position(2, 0)),
-                                              Token(Int, "4".to_string(), Position(2, 7)),
-                                    ], Position(2, 0)),
-                              ],
-                                                Position(1, 0))],
-                                      Position(0, 5))],
-                            Position(0, 0)))
+        assert_parses_to("map { a\ntimes a 2\nreturn 4\n\n}",
+                         "((map@0-3 (block@4-5 a@6-7 ((times@8-13 a@14-15 2@16-17)@8-17 \
+                          (return@18-24 4@25-26)@18-26)@8-26)@4-29)@0-29)@0-29");
+    }
+
+    #[test]
+    fn test_parses_block_ending_in_empty_line() {
+        let s = "{\none\n\n}\ntwo";
+        assert_parses!(s,
+                       Node(vec![
+                            Node(vec![Token(Symbol, "block".to_string(), span(s, 0, 1)),
+                               Node(vec![
+                                   Node(vec![Token(Symbol,
+                                                   "one".to_string(),
+                                               span(s, 2, 5))],
+                                    span(s, 2, 5))],
+                                span(s, 2, 5))],
+                            span(s, 0, 8))],
+                          span(s, 0, 8)),
+                       Node(vec![Token(Symbol, "two".to_string(), span(s, 9, 12))],
+                            span(s, 9, 12)))
+    }
+
+    #[test]
+    fn test_parses_block_parameters() {
+        let assert_same = |source: &str, expected: &str| {
+            assert_round_trips(source);
+            let tree = parse(source).unwrap().unwrap();
+            assert!(tree.eq_ignoring_spans(&parse(expected).unwrap().unwrap()),
+                    "{:?}\nisn't the same as\n{:?}", source, expected);
+        };
+        assert_same("map { a | times a 2 }", "map { a\ntimes a 2\n}");
+        assert_same("f { a b | x + a\n b }", "f { a b\n x + a\n b\n}");
+        assert_same("f { a |\n a }", "f { a\n a\n}");
+        assert_same("f { | g }", "f {\ng\n}");
+        assert_same("f { a | }", "f { a\n}");
+
+        let s = "map { a | times a 2 }";
+        let tree = parse(s).unwrap().unwrap();
+        let block = &tree.arguments().unwrap()[0].arguments().unwrap()[1];
+        assert_eq!(block.arguments().unwrap()[2].span(), span(s, 10, 19));
+
+        let errors = |source: &str| {
+            parse_recovering(source).errors
+                                    .into_iter()
+                                    .map(|error| (error.description, error.span))
+                                    .collect::<Vec<_>>()
+        };
+        let s = "f { a 1 (b) | a }";
+        let message = "a block's parameters have to be plain names".to_string();
+        assert_eq!(errors(s),
+                   vec![(message.clone(), span(s, 6, 7)), (message.clone(), span(s, 8, 11))]);
+        let s = "f { a.b\n a }";
+        assert_eq!(errors(s), vec![(message, span(s, 4, 7))]);
+        let s = "f { a | b | c }";
+        assert_eq!(errors(s), vec![("a block can only have one '|'".to_string(), span(s, 10, 11))]);
+        let s = "f { a | b |\n c }";
+        assert_eq!(errors(s), vec![("a block can only have one '|'".to_string(), span(s, 10, 11))]);
+    }
+
+    #[test]
+    fn test_fail_parse_block() {
+        let s = "{ one\ntwo";
+        match parse(s) {
+            Ok(_) => panic!("I'm assertng this should not parse correctly"),
+            Err(Error { description, span: error_span, .. }) => {
+                assert_eq!("hit end of file while reading a block".to_string(), description);
+                assert_eq!(span(s, 0, 9), error_span);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fail_parse_unexpected_closer() {
+        let s = "(one }";
+        match parse(s) {
+            Ok(_) => panic!("I'm assertng this should not parse correctly"),
+            Err(Error { description, span: error_span, .. }) => {
+                assert_eq!("encountered unexpected '}' while reading an open paren".to_string(),
+                           description);
+                assert_eq!(span(s, 5, 6), error_span);
+            }
+        }
     }
 
     #[test]
     fn test_parses_block_with_next_statement() {
-        assert_parses!("{ one two } three",
+        let s = "{ one two } three";
+        assert_parses!(s,
                        Node(vec![
-                            Node(vec![Token(Symbol, "block".to_string(), Position(0, 0)),
+                            Node(vec![Token(Symbol, "block".to_string(), span(s, 0, 1)),
                                            Node(vec![Token(Symbol,
                                                            "one".to_string(),
-                                                           Position(0, 2)),
+                                                           span(s, 2, 5)),
                                                      Token(Symbol,
                                                            "two".to_string(),
-                                                           Position(0, 6))],
-                                                Position(0, 1))],
-                                      Position(0, 0)),
-                             Token(Symbol, "three".to_string(), Position(0, 12)),
-                       ], Position(0, 0)))
+                                                           span(s, 6, 9))],
+                                                span(s, 2, 9))],
+                                      span(s, 0, 11)),
+                             Token(Symbol, "three".to_string(), span(s, 12, 17)),
+                       ], span(s, 0, 17)))
     }
 
     #[test]
     fn test_parses_two_blocks() {
-        assert_parses!("{\nprint }\n{ print }",
-                       Node(vec![
-                            Node(vec![Token(Symbol, "block".to_string(), Position(0, 0)),
-                               Node(vec![
-                                   Node(vec![Token(Symbol,
-                                                   "print".to_string(),
-                                               Position(1, 0))],
-                                    Position(1, 0))],
-                                Position(1, 0))],
-                            Position(0, 0))],
-                          Position(0, 0)),
-                       Node(vec![
-                            Node(vec![Token(Symbol, "block".to_string(), Position(0, 0)),
-                               Node(vec![Token(Symbol,
-                                               "print".to_string(),
-                                           Position(2, 2))],
-                                      Position(2, 1))],
-                            Position(2, 0)),
-                       ], Position(2, 0)))
+        assert_parses_to("{\nprint }\n{ print }",
+                         "(((block@0-1 ((print@2-7)@2-7)@2-7)@0-9)@0-9 \
+                          ((block@10-11 (print@12-17)@12-17)@10-19)@10-19)@0-19");
+    }
+
+    #[test]
+    fn test_recovers_from_errors() {
+        let s = "(a })\nfoo )\n{ b";
+        let parsed = parse_recovering(s);
+        let errors = parsed.errors.iter()
+                                  .map(|error| (error.description.as_str(), error.span.clone()))
+                                  .collect::<Vec<_>>();
+        assert_eq!(errors,
+                   vec![("encountered unexpected '}' while reading an open paren", span(s, 3, 4)),
+                        ("encountered unexpected ')' while reading top level expressions",
+                         span(s, 10, 11)),
+                        ("hit end of file while reading a block", span(s, 12, 15))]);
+        assert_eq!(parsed.tree.syntax_errors(), parsed.errors);
+
+        // the rest of the first line is still read
+        let first_line = &parsed.tree.arguments().unwrap()[0];
+        assert_eq!(first_line.to_sexp(true),
+                   "((a@1-2 #error\"encountered unexpected '}' while reading an open paren\"@3-4)\
+                    @0-5)@0-5");
+    }
+
+    #[test]
+    fn test_read_prefix() {
+        // a closure can capture what it needs
+        let wrapper = "quote".to_string();
+        let s = "list #'(a b) #\"no \\escape\"#";
+        let parsed = Parser::new(s)
+                         .read_prefix("#'", move |parser| {
+                             let prefix = parser.next_token().unwrap();
+                             let expression = parser.read_expression()?;
+                             let span = prefix.span.to(&expression.span());
+                             let symbol = Token(Symbol, wrapper.clone(), prefix.span);
+                             Ok(Some(Node(vec![symbol, expression], span)))
+                         })
+                         .read_prefix("#\"", |parser| {
+                             let prefix = parser.next_token().unwrap();
+                             let (text, span) = parser.read_raw_until("\"#")?;
+                             Ok(Some(Token(Str, text, prefix.span.to(&span))))
+                         })
+                         .parse();
+        assert!(parsed.errors.is_empty());
+        assert_eq!(parsed.tree,
+                   Node(vec![
+                        Node(vec![
+                             Token(Symbol, "list".to_string(), span(s, 0, 4)),
+                             Node(vec![Token(Symbol, "quote".to_string(), span(s, 5, 7)),
+                                       Node(vec![Token(Symbol, "a".to_string(), span(s, 8, 9)),
+                                                 Token(Symbol, "b".to_string(), span(s, 10, 11))],
+                                            span(s, 7, 12))],
+                                  span(s, 5, 12)),
+                             Token(Str, "no \\escape".to_string(), span(s, 13, s.len())),
+                        ], span(s, 0, s.len())),
+                   ], span(s, 0, s.len())));
+
+        // the reader's errors are recovered from like any other
+        let parsed = Parser::new("#'\nx")
+                         .read_prefix("#'", |parser| {
+                             parser.next_token();
+                             parser.read_expression().map(Some)
+                         })
+                         .parse();
+        assert_eq!(parsed.errors[0].description, "expected an expression");
+
+        // however tall a tree a reader makes, what's parsed is kept to MAX_HEIGHT
+        let tall = |height: usize| {
+            move |parser: &mut Parser| {
+                let span = parser.next_token().unwrap().span;
+                let mut tree = Token(Symbol, "x".to_string(), span.clone());
+                for _ in 1..height {
+                    tree = Node(vec![tree], span.clone());
+                }
+                Ok(Some(tree))
+            }
+        };
+        let parsed = Parser::new("#^ y").read_prefix("#^", tall(MAX_HEIGHT - 2)).parse();
+        assert!(parsed.errors.is_empty());
+        assert_eq!(parsed.tree.height(), MAX_HEIGHT);
+        let parsed = Parser::new("#^ y\nz").read_prefix("#^", tall(MAX_HEIGHT - 1)).parse();
+        assert_eq!(parsed.errors.len(), 1);
+        assert_eq!(parsed.errors[0].description,
+                   format!("expressions can only be nested {} deep", MAX_NESTING));
+        assert_eq!(parsed.errors[0].span, span("#^ y\nz", 0, 4));
+        assert_eq!(parsed.tree.height(), 3);
+    }
+
+    #[test]
+    fn test_parse_lists() {
+        assert_parses_to("[1 a + [\n]]",
+                         "(((list@0-1 1@1-2 a@3-4 +@5-6 (list@7-8)@7-10)@0-11)@0-11)@0-11");
+        assert_eq!(parse_recovering("[a").errors[0].description,
+                   "hit end of file while reading a list");
+        assert_eq!(parse_recovering("[a)").errors[0].description,
+                   "encountered unexpected ')' while reading a list");
+    }
+
+    #[test]
+    fn test_parse_members() {
+        assert_parses_to("a.b.c é.x 4",
+                         "(((.@3-4 (.@1-2 a@0-1 b@2-3)@0-3 c@4-5)@0-5 \
+                          (.@8-9 é@6-8 x@9-10)@6-10 4@11-12)@0-12)@0-12");
+        // only names are split
+        assert_parses_to(". a..b c.", "((.@0-1 a..b@2-6 c.@7-9)@0-9)@0-9");
+
+        // each access is nested in the next
+        let members = |parts: usize| vec!["a"; parts].join(".");
+        assert!(parse(&members(MAX_NESTING)).is_ok());
+        let error = parse(&members(MAX_NESTING + 1)).unwrap_err();
+        assert_eq!(error.description,
+                   format!("expressions can only be nested {} deep", MAX_NESTING));
+        assert_eq!((error.span.start_offset, error.span.end_offset),
+                   (2 * MAX_NESTING - 1, 2 * MAX_NESTING));
+        let source = format!("define f {{ a\nprint {}\n}}", members(3000));
+        check_never_panics(&source);
+        assert_eq!(parse_recovering(&source).errors.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_keywords() {
+        assert_parses_to("f a width: 3 (g b:\n c)",
+                         "((f@0-1 a@2-3 (:@9-10 width@4-9 3@11-12)@4-12 \
+                          (g@14-15 (:@17-18 b@16-17 c@20-21)@16-21)@13-22)@0-22)@0-22");
+        assert_eq!(parse_recovering("f x:\ng").errors[0].description,
+                   "expected a value after 'x:'");
+        // a lone colon is just a symbol
+        assert_parses_to(":", "((:@0-1)@0-1)@0-1");
+    }
+
+    #[test]
+    fn test_parse_interpolation() {
+        assert_parses_to("print \"a #{x + 1} b\" \"#{\"#{y}\"}\"",
+                         "((print@0-5 \
+                          (interpolate@6-7 \"a \"@7-9 (+@13-14 x@11-12 1@15-16)@11-16 \
+                          \" b\"@17-19)@6-20 \
+                          (interpolate@21-22 \
+                          ((interpolate@24-25 (y@27-28)@27-28)@24-30)@24-30)@21-32)@0-32)@0-32");
+        // an escaped '#' doesn't start any code
+        assert_parses_to("\"\\#{x}\"", "((\"#{x}\"@0-7)@0-7)@0-7");
+
+        // errors in the code are where the code is
+        let s = "f \"n: #{g (}\" x";
+        let errors = parse_recovering(s).errors;
+        assert_eq!(errors[0].description, "an open paren was not closed before '}'");
+        assert_eq!(errors[0].span, span(s, 10, 11));
+        assert_eq!(parse_recovering("\"#{}\"").errors[0].description,
+                   "expected an expression in '#{}'");
+        assert_eq!(parse_recovering("\"#{\"}").errors[0].description,
+                   "hit end of file while reading a string");
+    }
+
+    #[test]
+    fn test_parse_quotes() {
+        assert_parses_to("'(a ,b) `,@c",
+                         "(((quote@0-1 (a@2-3 (unquote@4-5 b@5-6)@4-6)@1-7)@0-7 \
+                          (quasiquote@8-9 (unquote-splicing@9-11 c@11-12)@9-12)@8-12)@0-12)@0-12");
+        assert_eq!(parse_recovering("f '").errors[0].description, "expected an expression");
+    }
+
+    /// A small xorshift generator, so the randomized tests
+    /// are the same on every run.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn test_parse_indented_blocks() {
+        let assert_same = |indented: &str, braced: &str| {
+            let source = format!("#pragma indent\n{}", indented);
+            assert_round_trips(&source);
+            let tree = parse(&source).unwrap().unwrap();
+            assert!(tree.eq_ignoring_spans(&parse(braced).unwrap().unwrap()),
+                    "{:?}\nisn't the same as\n{:?}", indented, braced);
+        };
+        assert_same("define start | x:\n    print x\n\n    # done\n    x\nstart 1\n",
+                    "define start { x\n    print x\n    x\n}\nstart 1\n");
+        assert_same("f:\n  g | a b:\n    a (b\n  c)\n  h\nk",
+                    "f {\n  g { a b\n    a (b c)\n  }\n  h\n}\nk");
+        assert_same("f [a b: c]\ng x:\n y", "f [a b: c]\ng x {\n y\n}");
+        // lines in parens aren't laid out, and `{ }` still works
+        assert_same("f (a:\n   b)\ng { x\n  x }", "f (a: b)\ng { x\n x }");
+
+        // the closing brace is at the start of the line that dedents
+        let source = "#pragma indent\nf:\n  x\ny";
+        let tree = parse(source).unwrap().unwrap();
+        let block = tree.arguments().unwrap()[0].arguments().unwrap()[1].span();
+        assert_eq!(block, span(source, 16, 22));
+
+        let error = |indented: &str| {
+            let errors = parse_recovering(&format!("#pragma indent\n{}", indented)).errors;
+            errors.into_iter().map(|error| error.description).collect::<Vec<_>>()
+        };
+        assert_eq!(error("f:\ng"), vec!["expected an indented block after ':'"]);
+        assert_eq!(error("f:"), vec!["expected an indented block after ':'"]);
+        assert_eq!(error("f\n  g"), vec!["unexpected indentation"]);
+        assert_eq!(error("f:\n    g\n  h"),
+                   vec!["this line's indentation doesn't match an enclosing block's"]);
+        assert_eq!(error("f:\n  (g\nh"),
+                   vec!["an open paren was not closed before the end of its block"]);
+        // without the pragma, a trailing colon doesn't open a block
+        assert_eq!(parse_recovering("f:\n  g").errors[0].description,
+                   "expected a value after 'f:'");
+    }
+
+    /// Pieces of acorn - and of things that are almost acorn -
+    /// for building half-typed programs.
+    const FRAGMENTS: &[&str] = &["(", ")", "{", "}", "\n", " ", "\t", "\r", "\"", "\\",
+                                 "\\u{", "\\u{110000}", "\\q", "#", "#|", "|#", "|",
+                                 "0", "42", "1.5", "1e", "2.5e-3", "1e999", ".", "-",
+                                 "define", "block", "print", "x", "[", "]", "'", "`", ",", ",@",
+                                 "a.b", "é", "\u{0}", "🌰", ":", "x:", "  ", "#{", "\\#",
+                                 " + ", " ^ ", "not ", " |> "];
+
+    fn check_never_panics(source: &str) {
+        assert_round_trips(source);
+        let _ = parse(source);
+        let _ = parse_with_comments(source);
+        // the partial tree should be just as safe to compile
+        let _ = compile(parse_recovering(source).tree);
+    }
+
+    #[test]
+    fn test_parse_malformed_programs() {
+        for source in &["define f { (1)\n x }",
+                        "define f { x\n ((print) 1) }",
+                        "define f { ()\n }",
+                        "define (f) { x }",
+                        "define f { \"a\" }",
+                        "{ (\n}", "(}", "{ a )", "\"\\u{d800}\"", "#| #| |#"] {
+            check_never_panics(source);
+        }
+    }
+
+    #[test]
+    fn test_parse_deep_nesting() {
+        let deep = |open: &str, close: &str, depth: usize| {
+            format!("{}x{}", open.repeat(depth), close.repeat(depth))
+        };
+        for source in &[deep("(", ")", 5000),
+                        deep("[", "]", 5000),
+                        deep("{ a\n", "\n}", 5000),
+                        deep("'", "", 5000),
+                        deep("(\"#{", "}\")", 1000),
+                        format!("#pragma indent\n{}", deep("(", ")", 5000)),
+                        format!("{}\nf x", deep("(", ")", 5000))] {
+            check_never_panics(source);
+            let errors = parse_recovering(source).errors;
+            assert_eq!(errors.len(), 1, "{:?}", errors);
+            assert_eq!(errors[0].description,
+                       format!("expressions can only be nested {} deep", MAX_NESTING));
+        }
+
+        // strings are scanned without recursion, nested or not
+        let source = "\"#{".repeat(200000);
+        check_never_panics(&source);
+        assert_eq!(parse_recovering(&source).errors[0].description,
+                   "hit end of file while reading a string");
+        let source = deep("\"#{", "}\"", 1000);
+        check_never_panics(&source);
+        assert_eq!(parse_recovering(&source).errors[0].description,
+                   format!("expressions can only be nested {} deep", MAX_NESTING));
+
+        // so are the calls operators make
+        let chain = |operator: &str, terms: usize| vec!["a"; terms].join(operator);
+        for source in &[format!("{}a", "not ".repeat(3000)),
+                        chain(" ^ ", 10000),
+                        format!("define f {{ a\n{}\n}}", chain(" + ", 1000))] {
+            check_never_panics(source);
+            let errors = parse_recovering(source).errors;
+            assert_eq!(errors.len(), 1, "{:?}", errors);
+            assert_eq!(errors[0].description,
+                       format!("expressions can only be nested {} deep", MAX_NESTING));
+        }
+
+        // the `x` inside is an expression too
+        assert!(parse(&deep("(", ")", MAX_NESTING - 1)).is_ok());
+        let source = deep("(", ")", MAX_NESTING);
+        let error = parse(&source).unwrap_err();
+        assert_eq!(error.span, span(&source, MAX_NESTING, MAX_NESTING + 1));
+
+        // half-typed programs, nested deep
+        let mut random = Random(0xdee9);
+        for _ in 0..200 {
+            let length = random.below(24);
+            let source = (0..length).map(|_| FRAGMENTS[random.below(FRAGMENTS.len())])
+                                    .collect::<String>();
+            check_never_panics(&deep("(", ")", MAX_NESTING - 1).replace("x", &source));
+        }
+    }
+
+    #[test]
+    fn test_parse_arbitrary_bytes() {
+        let mut random = Random(0x5eed);
+        for _ in 0..2000 {
+            let length = random.below(64);
+            let bytes = (0..length).map(|_| random.next() as u8).collect::<Vec<_>>();
+            check_never_panics(&String::from_utf8_lossy(&bytes));
+        }
+    }
+
+    #[test]
+    fn test_parse_arbitrary_fragments() {
+        let mut random = Random(0xac0e);
+        for _ in 0..5000 {
+            let length = random.below(24);
+            let source = (0..length).map(|_| FRAGMENTS[random.below(FRAGMENTS.len())])
+                                    .collect::<String>();
+            check_never_panics(&source);
+            check_never_panics(&format!("#pragma indent\n{}", source));
+        }
     }
 
 }
@@ -0,0 +1,374 @@
+// parser/operators.rs
+
+use std::mem;
+use std::vec::IntoIter;
+use std::iter::Peekable;
+use utils::{Result, err_span, Span};
+use compiler::abstract_tree::{AbstractTree, TokenType};
+use parser::MAX_NESTING;
+
+/// How a chain of one binary operator groups:
+/// `a - b - c` is `(a - b) - c`, `a ^ b ^ c` is `a ^ (b ^ c)`,
+/// and `a < b < c` is an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Associativity {
+    Left,
+    Right,
+    None,
+}
+
+/// The precedence and associativity of a binary operator -
+/// a higher precedence binds tighter.
+fn binary(name: &str) -> Option<(u8, Associativity)> {
+    match name {
+        "=" => Some((0, Associativity::Right)),
+        "or" => Some((1, Associativity::Left)),
+        "and" => Some((2, Associativity::Left)),
+        "==" | "!=" | "<" | "<=" | ">" | ">=" => Some((4, Associativity::None)),
+        "|>" => Some((5, Associativity::Left)),
+        "+" | "-" => Some((6, Associativity::Left)),
+        "*" | "/" | "%" => Some((7, Associativity::Left)),
+        "^" => Some((9, Associativity::Right)),
+        _ => None,
+    }
+}
+
+/// The precedence of a prefix operator: `not` takes in
+/// a whole comparison, `-` only the expression after it.
+fn prefix(name: &str) -> Option<u8> {
+    match name {
+        "not" => Some(3),
+        "-" => Some(8),
+        _ => None,
+    }
+}
+
+fn operator_name(tree: &AbstractTree) -> Option<&str> {
+    match *tree {
+        AbstractTree::Token(TokenType::Symbol, ref name, _)
+            if binary(name).is_some() || prefix(name).is_some() => Some(name),
+        _ => None,
+    }
+}
+
+/// An item of a line is either an operator, or an operand -
+/// where side by side expressions (a call like `print x`)
+/// make up a single operand.
+enum Item {
+    Operator(AbstractTree),
+    Operand(Vec<AbstractTree>),
+}
+
+/// rewrite turns the infix operators among the expressions of a
+/// line (or a paren) into ordinary calls, by their precedence:
+///
+/// `print a + b * c` becomes `(+ (print a) (* b c))`
+///
+/// and `xs |> map f |> print` becomes `(print (map xs f))`.
+///
+/// Without any operators - or with just an operator on its
+/// own, like `(+)` - the expressions are left as they are.
+///
+/// Each call an operator makes is nested in the next, so the
+/// calls count towards MAX_NESTING - `room` is how many levels
+/// the line's tree can have, after the expressions around it.
+pub fn rewrite(expressions: Vec<AbstractTree>, span: Span, room: usize) -> Result<AbstractTree> {
+    if expressions.len() < 2 || !expressions.iter().any(|e| operator_name(e).is_some()) {
+        return Ok(AbstractTree::Node(expressions, span));
+    }
+
+    let mut items = vec![];
+    let mut operand = vec![];
+    for expression in expressions {
+        if operator_name(&expression).is_some() {
+            if !operand.is_empty() {
+                items.push(Item::Operand(mem::take(&mut operand)));
+            }
+            items.push(Item::Operator(expression));
+        } else {
+            operand.push(expression);
+        }
+    }
+    if !operand.is_empty() {
+        items.push(Item::Operand(operand));
+    }
+
+    let mut items = items.into_iter().peekable();
+    let (tree, _) = expression(&mut items, 0, None, room)?;
+    match items.next() {
+        // only a prefix-only operator can be left over, like `a not b`
+        Some(Item::Operator(operator)) => {
+            operator.err(format!("'{}' can only start an expression - try parentheses",
+                                 operator.name()?))
+        }
+        _ => Ok(tree),
+    }
+}
+
+/// A single expression stays as it is, while side
+/// by side expressions become a call - either way,
+/// with the height of the tree it makes.
+fn group(mut expressions: Vec<AbstractTree>) -> (AbstractTree, usize) {
+    if expressions.len() == 1 {
+        let expression = expressions.remove(0);
+        let height = expression.height();
+        return (expression, height);
+    }
+    let span = expressions[0].span().to(&expressions[expressions.len() - 1].span());
+    let tree = AbstractTree::Node(expressions, span);
+    let height = tree.height();
+    (tree, height)
+}
+
+/// The call of `operator` on `operands`, with its height - an
+/// error at the operator if that's more than `room`.
+fn call(operator: AbstractTree,
+        operands: Vec<(AbstractTree, usize)>,
+        span: Span,
+        room: usize)
+        -> Result<(AbstractTree, usize)> {
+    let height = 1 + operands.iter().map(|&(_, height)| height).max().unwrap_or(0);
+    if height > room {
+        return too_deep(&operator);
+    }
+    let mut expressions = vec![operator];
+    expressions.extend(operands.into_iter().map(|(operand, _)| operand));
+    Ok((AbstractTree::Node(expressions, span), height))
+}
+
+/// The error for operators that nest their calls too deeply.
+fn too_deep<T>(operator: &AbstractTree) -> Result<T> {
+    operator.err(format!("expressions can only be nested {} deep", MAX_NESTING))
+}
+
+/// Reads an expression whose operators all bind
+/// tighter than `minimum` - this is precedence climbing,
+/// with each precedence split into a left and right
+/// binding power to get the associativity right. It gives
+/// the expression's height, which can't be more than `room`
+/// - so this recurses no deeper than MAX_NESTING.
+fn expression(items: &mut Peekable<IntoIter<Item>>,
+              minimum: u8,
+              after: Option<&AbstractTree>,
+              room: usize)
+              -> Result<(AbstractTree, usize)> {
+    let mut left = match items.next() {
+        Some(Item::Operand(operand)) => group(operand),
+        Some(Item::Operator(operator)) => {
+            match prefix(operator_name(&operator).unwrap()) {
+                Some(_) if room < 2 => return too_deep(&operator),
+                Some(precedence) => {
+                    let operand = expression(items, precedence * 2, Some(&operator), room - 1)?;
+                    let span = operator.span().to(&operand.0.span());
+                    call(operator, vec![operand], span, room)?
+                }
+                None => return missing_operand(after, Some(&operator)),
+            }
+        }
+        None => return missing_operand(after, None),
+    };
+
+    while let Some(Item::Operator(operator)) = items.peek() {
+        let (precedence, associativity) = match binary(operator_name(operator).unwrap()) {
+            Some(binding) => binding,
+            None => break,
+        };
+        let (left_power, right_power) = match associativity {
+            Associativity::Right => (precedence * 2 + 1, precedence * 2),
+            _ => (precedence * 2, precedence * 2 + 1),
+        };
+        if left_power < minimum {
+            break;
+        }
+
+        let Some(Item::Operator(operator)) = items.next() else {
+            break;
+        };
+        if room < 2 {
+            return too_deep(&operator);
+        }
+        if operator.name()? == "|>" {
+            left = pipe(items, left, &operator, room)?;
+            continue;
+        }
+        let right = expression(items, right_power, Some(&operator), room - 1)?;
+
+        if associativity == Associativity::None {
+            if let Some(Item::Operator(next)) = items.peek() {
+                if binary(operator_name(next).unwrap()).map(|binding| binding.0) ==
+                   Some(precedence) {
+                    return next.err(format!("'{}' after '{}' is ambiguous - \
+                                             add parentheses",
+                                            next.name()?,
+                                            operator.name()?));
+                }
+            }
+        }
+
+        let span = left.0.span().to(&right.0.span());
+        left = call(operator, vec![left, right], span, room)?;
+    }
+    Ok(left)
+}
+
+/// `xs |> map f` passes `xs` as the first argument of the call
+/// after the `|>`, making `(map xs f)` - the call is only the
+/// side by side expressions up to the next operator, so one that
+/// binds tighter than `|>` has to be put in parentheses. The call
+/// keeps the span of the stage it's written as, so its errors
+/// point there rather than at the whole pipeline.
+fn pipe(items: &mut Peekable<IntoIter<Item>>,
+        (left, left_height): (AbstractTree, usize),
+        operator: &AbstractTree,
+        room: usize)
+        -> Result<(AbstractTree, usize)> {
+    let mut stage = match items.next() {
+        Some(Item::Operand(stage)) => stage,
+        _ => return missing_operand(Some(operator), None),
+    };
+    let (pipe_precedence, _) = binary("|>").unwrap();
+    if let Some(Item::Operator(next)) = items.peek() {
+        if matches!(binary(operator_name(next).unwrap()),
+                    Some((precedence, _)) if precedence > pipe_precedence) {
+            return next.err(format!("'{}' after '|>' is ambiguous - add parentheses",
+                                    next.name()?));
+        }
+    }
+    let span = stage[0].span().to(&stage[stage.len() - 1].span());
+    let height = 1 + stage.iter().map(AbstractTree::height).max().unwrap_or(0).max(left_height);
+    if height > room {
+        return too_deep(operator);
+    }
+    stage.insert(1, left);
+    Ok((AbstractTree::Node(stage, span), height))
+}
+
+/// The error for an operator without an expression where
+/// one is needed - `after` is the operator that needed it.
+fn missing_operand<T>(after: Option<&AbstractTree>, found: Option<&AbstractTree>) -> Result<T> {
+    match (after, found) {
+        (Some(after), _) => {
+            after.err(format!("expected an expression after '{}'", after.name()?))
+        }
+        (None, Some(found)) => {
+            found.err(format!("expected an expression before '{}'", found.name()?))
+        }
+        (None, None) => err_span(Span::default(), "expected an expression".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parser::{parse, parse_recovering, MAX_NESTING};
+    use compiler::abstract_tree::AbstractTree::*;
+
+    fn line(source: &str) -> String {
+        match parse(source).unwrap().unwrap() {
+            Node(ref lines, _) => lines[0].to_sexp(false),
+            _ => panic!("the top level should be a node"),
+        }
+    }
+
+    fn error(source: &str) -> (String, usize, usize) {
+        let error = parse_recovering(source).errors.remove(0);
+        (error.description, error.span.start_offset, error.span.end_offset)
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(line("a + b * c"), "(+ a (* b c))");
+        assert_eq!(line("a * b + c"), "(+ (* a b) c)");
+        assert_eq!(line("print a + b"), "(+ (print a) b)");
+        assert_eq!(line("print (a + b) 2"), "(print (+ a b) 2)");
+        assert_eq!(line("a or b and c == d"), "(or a (and b (== c d)))");
+        assert_eq!(line("f x"), "(f x)");
+        assert_eq!(line("map (+) xs"), "(map (+) xs)");
+        assert_eq!(line("x = a or f b"), "(= x (or a (f b)))");
+    }
+
+    #[test]
+    fn test_associativity() {
+        assert_eq!(line("a - b - c"), "(- (- a b) c)");
+        assert_eq!(line("a ^ b ^ c"), "(^ a (^ b c))");
+        assert_eq!(line("a = b = c"), "(= a (= b c))");
+        assert_eq!(line("(a < b) == c"), "(== (< a b) c)");
+    }
+
+    #[test]
+    fn test_prefix_operators() {
+        assert_eq!(line("- a * b"), "(* (- a) b)");
+        assert_eq!(line("a - - b"), "(- a (- b))");
+        assert_eq!(line("not a == b and c"), "(and (not (== a b)) c)");
+    }
+
+    #[test]
+    fn test_pipeline() {
+        assert_eq!(line("xs |> map f"), "(map xs f)");
+        assert_eq!(line("xs |> map f |> print"), "(print (map xs f))");
+        assert_eq!(line("x = xs |> length"), "(= x (length xs))");
+        assert_eq!(line("a + b |> f"), "(f (+ a b))");
+        assert_eq!(line("xs |> length == 3"), "(== (length xs) 3)");
+        assert_eq!(line("xs |> draw width: 2"), "(draw xs (: width 2))");
+        assert_eq!(line("(xs\n|> map f\n|> print)"), "((print (map xs f)))");
+    }
+
+    #[test]
+    fn test_operator_spans() {
+        let tree = parse("x\nab + c").unwrap().unwrap();
+        match tree {
+            Node(ref lines, _) => {
+                assert_eq!(lines[1].span().start_offset, 2);
+                assert_eq!(lines[1].span().end_offset, 8);
+            }
+            _ => panic!("the top level should be a node"),
+        }
+        // a stage of a pipeline is only as wide as its call
+        match parse("xs |> map f |> print").unwrap().unwrap() {
+            Node(ref lines, _) => {
+                assert_eq!(lines[0].span().start_offset, 15);
+                let stage = lines[0].argument(1).unwrap();
+                assert_eq!((stage.span().start_offset, stage.span().end_offset), (6, 11));
+            }
+            _ => panic!("the top level should be a node"),
+        }
+    }
+
+    #[test]
+    fn test_operator_errors() {
+        assert_eq!(error("a +"), ("expected an expression after '+'".to_string(), 2, 3));
+        assert_eq!(error("* a"), ("expected an expression before '*'".to_string(), 0, 1));
+        assert_eq!(error("a + * b"), ("expected an expression after '+'".to_string(), 2, 3));
+        assert_eq!(error("a < b < c"),
+                   ("'<' after '<' is ambiguous - add parentheses".to_string(), 6, 7));
+        assert_eq!(error("xs |>"), ("expected an expression after '|>'".to_string(), 3, 5));
+        assert_eq!(error("xs |> length + 1"),
+                   ("'+' after '|>' is ambiguous - add parentheses".to_string(), 13, 14));
+        assert_eq!(error("a not b"),
+                   ("'not' can only start an expression - try parentheses".to_string(), 2, 5));
+        // the rest of the source is still read
+        assert_eq!(parse_recovering("a +\n(b <= c >= d)\nok").errors.len(), 2);
+    }
+
+    #[test]
+    fn test_operator_nesting() {
+        let nesting = format!("expressions can only be nested {} deep", MAX_NESTING);
+        // each operator's call is nested in the next one's
+        let chain = |operator: &str, terms: usize| vec!["a"; terms].join(operator);
+        assert!(parse(&chain(" + ", MAX_NESTING + 1)).is_ok());
+        let offset = 4 * (MAX_NESTING + 1) - 2;
+        assert_eq!(error(&chain(" + ", MAX_NESTING + 2)), (nesting.clone(), offset, offset + 1));
+        assert_eq!(error(&chain(" ^ ", 100000)).0, nesting);
+        assert_eq!(error(&format!("{}a", "not ".repeat(3000))).0, nesting);
+        assert_eq!(error(&format!("{}a", "- ".repeat(3000))).0, nesting);
+        assert_eq!(error(&chain(" |> ", 3000)).0, nesting);
+        // parentheses around the operators count too
+        let source = format!("{}a + a{}",
+                             "(".repeat(MAX_NESTING - 1),
+                             ")".repeat(MAX_NESTING - 1));
+        assert!(parse(&source).is_ok());
+        let source = format!("{}a + a + a{}",
+                             "(".repeat(MAX_NESTING - 1),
+                             ")".repeat(MAX_NESTING - 1));
+        assert_eq!(error(&source).0, nesting);
+    }
+}
@@ -9,18 +9,102 @@ use std::io;
 ///
 /// Position(line: i64, column: i64)
 ///
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
 pub struct Position(pub i64, pub i64);
 
-/// Represents an error - consisting of
-/// a description and a position.
-///
-/// Position(line: i64, column: i64)
+/// The stretch of source between two Positions - `start`
+/// is inclusive and `end` is exclusive. The offsets are the
+/// same two points, as byte offsets into the source.
 ///
-#[derive(Debug, Clone)]
+/// The default Span is empty and at the very start of the source.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+    pub start_offset: usize,
+    pub end_offset: usize,
+}
+
+impl Span {
+    /// An empty span - just a point in the source.
+    pub fn point(position: Position, offset: usize) -> Span {
+        Span {
+            start: position.clone(),
+            end: position,
+            start_offset: offset,
+            end_offset: offset,
+        }
+    }
+
+    /// The span from the start of this one to the end of `other`.
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            start: self.start.clone(),
+            end: other.end.clone(),
+            start_offset: self.start_offset,
+            end_offset: other.end_offset,
+        }
+    }
+
+    /// The empty span where this one starts.
+    pub fn start_point(&self) -> Span {
+        Span::point(self.start.clone(), self.start_offset)
+    }
+}
+
+/// Represents an error - consisting of
+/// a description and the span of source
+/// it's about.
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Error {
     pub description: String,
-    pub position: Position,
+    pub span: Span,
+    /// Other source the error is about, like where
+    /// a name it mentions was declared.
+    pub notes: Vec<Note>,
+}
+
+/// A description of some more source, attached to an Error.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Note {
+    pub description: String,
+    pub span: Span,
+}
+
+impl Error {
+    /// Adds a note pointing at `span`.
+    pub fn note(mut self, span: Span, description: String) -> Error {
+        self.notes.push(Note { description, span });
+        self
+    }
+
+    /// The lines of source the error covers, each
+    /// followed by a line underlining the error's span.
+    pub fn underline(&self, source: &str) -> Vec<String> {
+        underline(&self.span, source)
+    }
+}
+
+/// The lines of source a span covers, each
+/// followed by a line underlining the span.
+fn underline(span: &Span, source: &str) -> Vec<String> {
+    let start = &span.start;
+    let end = &span.end;
+    let mut output = vec![];
+    for (i, line) in source.split('\n').enumerate() {
+        let i = i as i64;
+        if i < start.0 || i > end.0 {
+            continue;
+        }
+        let length = line.chars().count() as i64;
+        let from = if i == start.0 { start.1 } else { 0 };
+        let to = if i == end.0 { end.1 } else { length };
+        output.push(format!("{:4} | {}", i, line));
+        output.push(format!("     | {}{}",
+                            " ".repeat(from as usize),
+                            "^".repeat(::std::cmp::max(to - from, 1) as usize)));
+    }
+    output
 }
 
 /// The compiled form of acorn:
@@ -34,23 +118,54 @@ pub type IR = Vec<String>;
 ///
 /// If there are errors, the process exits.
 ///
+/// `source` is what was compiled - it's quoted
+/// to show where the error is.
 pub trait ErrorHandling<T> {
-    fn unpack_error(self) -> T;
+    fn unpack_error(self, source: &str) -> T;
+}
+
+/// Prints an error to stderr, quoting the
+/// lines of `source` it's about.
+pub fn report_error(error: &Error, source: &str) {
+    let stderr = &mut io::stderr();
+    writeln!(stderr, "compilation error:").unwrap();
+    writeln!(stderr,
+             "\tline {} column {}",
+             error.span.start.0,
+             error.span.start.1)
+        .unwrap();
+    writeln!(stderr, "\t{}", error.description).unwrap();
+    for line in error.underline(source) {
+        writeln!(stderr, "\t{}", line).unwrap();
+    }
+    for note in &error.notes {
+        writeln!(stderr, "\tnote: {}", note.description).unwrap();
+        for line in underline(&note.span, source) {
+            writeln!(stderr, "\t{}", line).unwrap();
+        }
+    }
 }
 
-impl<'a, T> ErrorHandling<T> for Result<T> {
-    fn unpack_error(self) -> T {
-        let mut stderr = &mut io::stderr();
+impl<T> ErrorHandling<T> for Result<T> {
+    fn unpack_error(self, source: &str) -> T {
         match self {
             Ok(a) => a,
             Err(ref error) => {
-                writeln!(stderr, "compilation error:").unwrap();
-                writeln!(stderr,
-                         "\tline {} column {}",
-                         error.position.0,
-                         error.position.1)
-                    .unwrap();
-                writeln!(stderr, "\t{}", error.description).unwrap();
+                report_error(error, source);
+                process::exit(0)
+            }
+        }
+    }
+}
+
+impl<T> ErrorHandling<T> for MultiResult<T> {
+    fn unpack_error(self, source: &str) -> T {
+        match self {
+            Ok(a) => a,
+            Err(ref errors) => {
+                for error in errors {
+                    report_error(error, source);
+                }
                 process::exit(0)
             }
         }
@@ -62,47 +177,78 @@ impl<'a, T> ErrorHandling<T> for Result<T> {
 ///
 pub type Result<T> = result::Result<T, Error>;
 
+/// The result of a pass that carries on past
+/// errors - and so can find more than one.
+pub type MultiResult<T> = result::Result<T, Vec<Error>>;
+
+
+pub fn err_span<T>(span: Span, description: String) -> Result<T> {
+    Err(Error {
+        description,
+        span,
+        notes: vec![],
+    })
+}
+
 #[cfg(test)]
 pub mod tests {
     use compiler::abstract_tree::AbstractTree;
     use compiler::abstract_tree::AbstractTree::*;
     use compiler::abstract_tree::TokenType::*;
-    use utils::Position;
-    use utils::Result;
+    use compiler::compile;
+    use parser::parse_recovering;
+    use utils::{Error, IR, Position, Span};
+    use utils::MultiResult;
 
     pub fn generate_data() -> AbstractTree {
         Node(vec![
             Node(vec![
-                 Token(Symbol, "foo".to_string(), Position(0,0)),
-                 Token(Int, "2".to_string(), Position(0,0)),
-                 Token(Int, "2".to_string(), Position(0,0)),
+                 Token(Symbol, "foo".to_string(), Span::default()),
+                 Token(Int, "2".to_string(), Span::default()),
+                 Token(Int, "2".to_string(), Span::default()),
                  Node(vec![
-                        Token(Symbol, "foo".to_string(), Position(0,0)),
-                        Token(Symbol, "foo".to_string(), Position(0,0)),
-                ], Position(0, 2)),
-            ], Position(0, 2)),
+                        Token(Symbol, "foo".to_string(), Span::default()),
+                        Token(Symbol, "foo".to_string(), Span::default()),
+                ], Span::default()),
+            ], Span::default()),
             Node(vec![
-                 Token(Symbol, "define".to_string(), Position(0,0)),
-                 Token(Int, "2".to_string(), Position(0,0))
-            ], Position(0, 2)),
+                 Token(Symbol, "define".to_string(), Span::default()),
+                 Token(Int, "2".to_string(), Span::default())
+            ], Span::default()),
         ],
-             Position(0, 2))
+             Span::default())
     }
 
-    pub fn abstract_tree_item<'a>(at: Vec<AbstractTree>) -> AbstractTree {
-        Node(vec![Node(at, Position(0, 0))], Position(0, 0))
+    pub fn abstract_tree_item(at: Vec<AbstractTree>) -> AbstractTree {
+        Node(vec![Node(at, Span::default())], Span::default())
     }
 
-    pub fn assert_returns_error<T>(result: Result<T>, description: &str) {
-        assert_eq!(result.err().unwrap().description, description)
+    pub fn assert_returns_error<T>(result: MultiResult<T>, description: &str) {
+        assert_eq!(result.err().unwrap()[0].description, description)
     }
 
-}
+    /// Compiles `source`, giving the first error if there are any.
+    pub fn compile_source(source: &str) -> Result<IR, Error> {
+        compile(parse_recovering(source).tree).map_err(|mut errors| errors.remove(0))
+    }
 
+    #[test]
+    fn test_underline() {
+        let error = Error {
+            description: "an error".to_string(),
+            span: Span {
+                start: Position(1, 4),
+                end: Position(2, 3),
+                start_offset: 7,
+                end_offset: 16,
+            },
+            notes: vec![],
+        };
+        assert_eq!(error.underline("one\ntwo three\nfour"),
+                   vec!["   1 | two three".to_string(),
+                        "     |     ^^^^^".to_string(),
+                        "   2 | four".to_string(),
+                        "     | ^^^".to_string()]);
+    }
 
-pub fn err_position<T>(position: Position, description: String) -> Result<T> {
-    Err(Error {
-        description: description,
-        position: position,
-    })
 }